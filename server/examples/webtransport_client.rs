@@ -0,0 +1,273 @@
+//! Connect to the game server as a controller over WebTransport, the way a
+//! browser does, and steer a duck in circles.
+//!
+//! The server's certificate is pinned rather than verified, so this works
+//! against the self-signed one it generates:
+//!
+//! ```sh
+//! cargo run --example webtransport_client -- https://localhost:4433/wt \
+//!     --cert ~/.local/share/game-server/cert.der
+//! ```
+
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::Parser;
+use h3::ext::Protocol;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+#[derive(Parser, Debug)]
+struct Opt {
+    /// Session URL, as served in `/config.json`
+    #[clap(default_value = "https://localhost:4433/wt")]
+    url: http::Uri,
+    /// The server's certificate, PEM or DER
+    #[clap(long)]
+    cert: PathBuf,
+    /// Inputs to send before disconnecting
+    #[clap(long, default_value_t = 300)]
+    inputs: u64,
+    /// Milliseconds between inputs
+    #[clap(long, default_value_t = 16)]
+    interval: u64,
+    /// Move to a new local port after this many inputs, the way a NAT
+    /// rebinding or a phone changing networks would
+    #[clap(long)]
+    rebind: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    let host = opt.url.host().context("URL has no host")?;
+    let port = opt.url.port_u16().unwrap_or(443);
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .context("host not found")?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedCertificate {
+        cert: read_certificate(&opt.cert)?,
+        provider: provider.clone(),
+    };
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let client_config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    ));
+
+    let bind = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let mut endpoint = quinn::Endpoint::client(bind.parse()?)?;
+    endpoint.set_default_client_config(client_config);
+    let connection = endpoint.connect(addr, host)?.await?;
+    println!("connected to {addr}");
+
+    // Open the session with an extended CONNECT request. HTTP/3 is done with
+    // after that, and its connection is kept but no longer polled so the
+    // session's own streams aren't taken for HTTP/3 ones.
+    let (mut driver, mut send_request) = h3::client::builder()
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .build::<_, _, Bytes>(h3_quinn::Connection::new(connection.clone()))
+        .await?;
+    let request = http::Request::builder()
+        .method(http::Method::CONNECT)
+        .uri(opt.url.clone())
+        .extension(Protocol::WEB_TRANSPORT)
+        .body(())?;
+    let open = async {
+        let mut session = send_request.send_request(request).await?;
+        let response = session.recv_response().await?;
+        anyhow::Ok((session, response))
+    };
+    let (session, response) = tokio::select! {
+        opened = open => opened?,
+        closed = std::future::poll_fn(|cx| driver.poll_close(cx)) => {
+            bail!("connection closed: {closed}")
+        }
+    };
+    if !response.status().is_success() {
+        bail!("session refused: {}", response.status());
+    }
+    let session_id = session.id().into_inner();
+    println!("session {session_id} open");
+    tokio::spawn(print_pushed(connection.clone(), session_id));
+
+    let register = serde_json::json!({ "type": "register", "data": { "role": "player" } });
+    for reply in request_on(&connection, session_id, &register).await? {
+        println!("< {reply}");
+    }
+
+    for seq in 1..=opt.inputs {
+        let angle = seq as f32 / 30.0;
+        let input = serde_json::json!({
+            "seq": seq,
+            "joystick": { "x": angle.cos(), "y": angle.sin() },
+            "buttons": { "a": seq % 60 < 5, "b": false, "x": false, "y": false },
+        });
+        let mut datagram = BytesMut::new();
+        put_varint(&mut datagram, session_id / 4);
+        datagram.put_slice(&serde_json::to_vec(&input)?);
+        connection.send_datagram(datagram.freeze())?;
+        if opt.rebind == Some(seq) {
+            endpoint.rebind(std::net::UdpSocket::bind(bind)?)?;
+            println!("rebound to {}", endpoint.local_addr()?);
+        }
+        tokio::time::sleep(Duration::from_millis(opt.interval)).await;
+    }
+    println!("sent {} inputs", opt.inputs);
+
+    connection.close(0u32.into(), b"done");
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Send one request on a new stream of the session and collect the replies.
+async fn request_on(
+    connection: &quinn::Connection,
+    session_id: u64,
+    message: &serde_json::Value,
+) -> Result<Vec<String>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut request = BytesMut::new();
+    put_varint(&mut request, 0x41);
+    put_varint(&mut request, session_id);
+    request.put_slice(&serde_json::to_vec(message)?);
+    send.write_all(&request).await?;
+    send.finish()?;
+    let replies = recv.read_to_end(64 * 1024).await?;
+    Ok(String::from_utf8(replies)?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Print the messages the server sends outside of replies, except the
+/// frequent state broadcasts.
+async fn print_pushed(connection: quinn::Connection, session_id: u64) -> Result<()> {
+    // HTTP/3 opens unidirectional streams of its own; the session's starts
+    // with its stream type and session id
+    let mut recv = loop {
+        let mut recv = BufReader::new(connection.accept_uni().await?);
+        if read_varint(&mut recv).await? == 0x54 && read_varint(&mut recv).await? == session_id {
+            break recv;
+        }
+    };
+    let mut line = String::new();
+    while recv.read_line(&mut line).await? > 0 {
+        if !line.starts_with(r#"{"type":"state""#) {
+            print!("<< {line}");
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+async fn read_varint(recv: &mut BufReader<quinn::RecvStream>) -> Result<u64> {
+    let first = recv.read_u8().await?;
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..1 << (first >> 6) {
+        value = value << 8 | u64::from(recv.read_u8().await?);
+    }
+    Ok(value)
+}
+
+/// Append a QUIC variable-length integer.
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    match value {
+        0..=0x3f => buf.put_u8(value as u8),
+        0x40..=0x3fff => buf.put_u16(0x4000 | value as u16),
+        0x4000..=0x3fff_ffff => buf.put_u32(0x8000_0000 | value as u32),
+        _ => buf.put_u64(0xc000_0000_0000_0000 | value),
+    }
+}
+
+fn read_certificate(path: &Path) -> Result<CertificateDer<'static>> {
+    let bytes =
+        fs::read(path).with_context(|| format!("failed to read certificate {}", path.display()))?;
+    if path.extension().is_some_and(|x| x == "der") {
+        return Ok(CertificateDer::from(bytes));
+    }
+    let cert = rustls_pemfile::certs(&mut &*bytes)
+        .next()
+        .context("no certificate found")?
+        .context("invalid PEM-encoded certificate")?;
+    Ok(cert)
+}
+
+/// Trusts exactly one certificate, like a browser given
+/// `serverCertificateHashes`.
+#[derive(Debug)]
+struct PinnedCertificate {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.cert.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate doesn't match".into()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,593 @@
+//! Authenticated HTTP/JSON API for operating a live server: listing clients,
+//! kicking and banning them, switching maps, resetting scores, pausing the
+//! simulation and broadcasting announcements. Every request needs an
+//! `Authorization: Bearer <admin_token>` header; without a configured token
+//! the API doesn't exist.
+
+use crate::bans;
+use crate::config::Config;
+use crate::game_state::{Activity, Feedback, Message, Role, SharedPlayers};
+use crate::http::{Request, Response};
+use crate::maps;
+use crate::session;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+/// Whether the simulation is paused. Player input is ignored while it is.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// An error response: HTTP status and message.
+type ApiError = (u16, String);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KickRequest {
+    player: Option<String>,
+    addr: Option<SocketAddr>,
+    #[serde(default = "default_reason")]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BanRequest {
+    player: Option<String>,
+    ip: Option<IpAddr>,
+    #[serde(default = "default_reason")]
+    reason: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UnbanRequest {
+    ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MapRequest {
+    map: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnnounceRequest {
+    message: String,
+}
+
+fn default_reason() -> String {
+    "removed by an administrator".to_string()
+}
+
+/// Answer a request for an `/admin/` path.
+pub async fn handle(
+    request: &Request,
+    addr: SocketAddr,
+    players_state: &SharedPlayers,
+    config: &Config,
+) -> Response {
+    let Some(token) = &config.admin_token else {
+        return Response::not_found();
+    };
+    if !authorized(request, token) {
+        warn!(%addr, path = %request.path, "unauthorized admin request");
+        return Response::json(401, &json!({ "error": "unauthorized" }));
+    }
+    match dispatch(request, players_state).await {
+        Ok(body) => {
+            if request.method == "POST" {
+                info!(%addr, path = %request.path, result = %body, "admin action");
+            }
+            Response::json(200, &body)
+        }
+        Err((status, error)) => Response::json(status, &json!({ "error": error })),
+    }
+}
+
+async fn dispatch(request: &Request, players_state: &SharedPlayers) -> Result<Value, ApiError> {
+    match (request.method.as_str(), request.route()) {
+        ("GET", "/admin/clients") => Ok(list_clients(players_state).await),
+        ("GET", "/admin/bans") => Ok(json!({ "banned": bans::list() })),
+        ("GET", "/admin/maps") => Ok(json!({ "maps": maps::ids() })),
+        ("POST", "/admin/kick") => kick(body(request)?).await,
+        ("POST", "/admin/ban") => ban(body(request)?).await,
+        ("POST", "/admin/unban") => {
+            let UnbanRequest { ip } = body(request)?;
+            Ok(json!({ "unbanned": ip, "was_banned": bans::unban(ip) }))
+        }
+        ("POST", "/admin/map") => {
+            let MapRequest { map } = body(request)?;
+            let message = maps::select(&map).map_err(|e| (404, e))?;
+            session::broadcast_message(&message).await;
+            Ok(json!({ "map": map }))
+        }
+        ("POST", "/admin/reset-scores") => Ok(reset_scores(players_state).await),
+        ("POST", "/admin/pause") => Ok(set_paused(true, players_state).await),
+        ("POST", "/admin/resume") => Ok(set_paused(false, players_state).await),
+        ("POST", "/admin/announce") => {
+            let AnnounceRequest { message } = body(request)?;
+            let announcement = Message {
+                type_: "announcement".to_string(),
+                data: json!({ "message": message }),
+            };
+            session::broadcast_message(&announcement).await;
+            Ok(json!({ "announced": message }))
+        }
+        _ => Err((
+            404,
+            format!("no admin endpoint {} {}", request.method, request.route()),
+        )),
+    }
+}
+
+/// Whether `request` carries the admin bearer token. The comparison doesn't
+/// stop at the first differing byte, so timing doesn't leak the token.
+fn authorized(request: &Request, token: &str) -> bool {
+    let Some(given) = request
+        .header("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn body<T: DeserializeOwned>(request: &Request) -> Result<T, ApiError> {
+    serde_json::from_slice(&request.body).map_err(|e| (400, format!("invalid request body: {e}")))
+}
+
+async fn list_clients(players_state: &SharedPlayers) -> Value {
+    let players = players_state.lock().await;
+    let clients: Vec<Value> = session::client_info()
+        .await
+        .into_iter()
+        .map(|client| {
+            let states: Vec<Value> = client
+                .players
+                .iter()
+                .filter_map(|id| {
+                    players.get(id).map(|state| {
+                        json!({
+                            "id": id,
+                            "color": state.color,
+                            "score": state.score,
+                            "health": state.health,
+                            "idle": state.idle,
+                        })
+                    })
+                })
+                .collect();
+            json!({
+                "addr": client.addr,
+                "role": client.role,
+                "registered": client.registered,
+                "rtt_ms": client.rtt_ms,
+                "players": states,
+            })
+        })
+        .collect();
+    json!({ "paused": is_paused(), "map": maps::current(), "clients": clients })
+}
+
+/// Resolve the connection a kick or ban targets, given a player id or an
+/// address.
+async fn target_addr(
+    player: Option<String>,
+    addr: Option<SocketAddr>,
+) -> Result<SocketAddr, ApiError> {
+    match (player, addr) {
+        (Some(player), None) => session::player_addr(&player)
+            .await
+            .ok_or((404, format!("no player `{player}`"))),
+        (None, Some(addr)) => Ok(addr),
+        _ => Err((400, "give exactly one of `player` or `addr`".to_string())),
+    }
+}
+
+async fn kick(request: KickRequest) -> Result<Value, ApiError> {
+    let addr = target_addr(request.player, request.addr).await?;
+    if !session::kick(addr, &request.reason).await {
+        return Err((404, format!("no client at {addr}")));
+    }
+    Ok(json!({ "kicked": [addr] }))
+}
+
+async fn ban(request: BanRequest) -> Result<Value, ApiError> {
+    let ip = match (request.player, request.ip) {
+        (None, Some(ip)) => ip,
+        (Some(player), None) => target_addr(Some(player), None).await?.ip(),
+        _ => return Err((400, "give exactly one of `player` or `ip`".to_string())),
+    };
+    bans::ban(ip);
+    let kicked = session::kick_ip(ip, &request.reason).await;
+    Ok(json!({ "banned": ip, "kicked": kicked }))
+}
+
+async fn reset_scores(players_state: &SharedPlayers) -> Value {
+    let mut players = players_state.lock().await;
+    for state in players.values_mut() {
+        state.score = 0;
+    }
+    let statuses: Vec<_> = players
+        .iter()
+        .map(|(id, state)| (id.clone(), state.health))
+        .collect();
+    let state_msg = session::state_message(&players);
+    drop(players);
+
+    for (id, health) in &statuses {
+        session::notify_player(
+            id,
+            &Feedback::Status {
+                score: 0,
+                health: *health,
+            },
+        )
+        .await;
+    }
+    session::send_to_role(Role::Viewer, &state_msg).await;
+    json!({ "reset": statuses.len() })
+}
+
+async fn set_paused(paused: bool, players_state: &SharedPlayers) -> Value {
+    if PAUSED.swap(paused, Ordering::Relaxed) != paused && !paused {
+        // Time spent paused doesn't count towards going idle
+        for state in players_state.lock().await.values_mut() {
+            state.last_input = Activity::default();
+        }
+    }
+    let message = Message {
+        type_: "paused".to_string(),
+        data: json!({ "paused": paused }),
+    };
+    session::broadcast_message(&message).await;
+    json!({ "paused": paused })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parse_head;
+    use crate::session::test::{feedback, types, Server};
+    use crate::transport::test::TestClient;
+    use std::time::Duration;
+
+    const TOKEN: &str = "s3cret-token";
+
+    fn config() -> Config {
+        Config {
+            admin_token: Some(TOKEN.to_string()),
+            ..Config::default()
+        }
+    }
+
+    fn request(method: &str, path: &str, authorization: Option<&str>, body: Value) -> Request {
+        let mut head = format!("{method} {path} HTTP/1.1");
+        if let Some(authorization) = authorization {
+            head.push_str(&format!("\r\nAuthorization: {authorization}"));
+        }
+        let mut request = parse_head(&head).unwrap();
+        if !body.is_null() {
+            request.body = body.to_string().into_bytes();
+        }
+        request
+    }
+
+    /// Make an authorized request of the admin API.
+    async fn call(server: &Server, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let bearer = format!("Bearer {TOKEN}");
+        let request = request(method, path, Some(&bearer), body);
+        let config = server.config.borrow().clone();
+        let response = handle(&request, admin_addr(), &server.players, &config).await;
+        (
+            response.status,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    fn admin_addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 9999))
+    }
+
+    /// Resumes the simulation when a test ends, however it ends.
+    struct Unpause;
+
+    impl Drop for Unpause {
+        fn drop(&mut self) {
+            PAUSED.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until `client` has been disconnected by the server.
+    async fn disconnected(server: &mut Server, client: &TestClient) {
+        let session = server.sessions.remove(&client.addr).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), session)
+            .await
+            .expect("client was not disconnected")
+            .unwrap();
+    }
+
+    #[test]
+    fn authorized_needs_the_exact_bearer_token() {
+        let check = |authorization| {
+            authorized(
+                &request("GET", "/admin/clients", authorization, Value::Null),
+                TOKEN,
+            )
+        };
+        assert!(check(Some("Bearer s3cret-token")));
+        assert!(!check(None));
+        assert!(!check(Some("s3cret-token")));
+        assert!(!check(Some("Basic s3cret-token")));
+        assert!(!check(Some("Bearer s3cret-tokeN")));
+        assert!(!check(Some("Bearer s3cret-toke")));
+        assert!(!check(Some("Bearer s3cret-token2")));
+        assert!(!check(Some("Bearer ")));
+        assert!(!check(Some("Bearer  s3cret-token")));
+    }
+
+    #[tokio::test]
+    async fn api_is_hidden_without_a_token_and_refuses_bad_ones() {
+        let server = Server::start(Config::default()).await;
+        let players = &server.players;
+        let get = |authorization| request("GET", "/admin/clients", authorization, Value::Null);
+        let bearer = format!("Bearer {TOKEN}");
+
+        let response = handle(
+            &get(Some(&bearer)),
+            admin_addr(),
+            players,
+            &Config::default(),
+        )
+        .await;
+        assert_eq!(response.status, 404);
+
+        for authorization in [None, Some("Bearer wrong"), Some(TOKEN)] {
+            let response = handle(&get(authorization), admin_addr(), players, &config()).await;
+            assert_eq!(response.status, 401);
+            assert_eq!(response.body, br#"{"error":"unauthorized"}"#);
+        }
+        let response = handle(&get(Some(&bearer)), admin_addr(), players, &config()).await;
+        assert_eq!(response.status, 200);
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_routes_and_bad_bodies() {
+        let server = Server::start(config()).await;
+
+        let (status, body) = call(&server, "GET", "/admin/nothing", Value::Null).await;
+        assert_eq!(
+            (status, body),
+            (
+                404,
+                json!({ "error": "no admin endpoint GET /admin/nothing" })
+            )
+        );
+        let (status, _) = call(&server, "GET", "/admin/kick", Value::Null).await;
+        assert_eq!(status, 404);
+
+        let (status, body) = call(&server, "POST", "/admin/kick", Value::Null).await;
+        assert_eq!(status, 400);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid request body"));
+        let (status, _) = call(&server, "POST", "/admin/kick", json!({ "who": "x" })).await;
+        assert_eq!(status, 400);
+        let (status, _) = call(
+            &server,
+            "POST",
+            "/admin/unban",
+            json!({ "ip": "not an ip" }),
+        )
+        .await;
+        assert_eq!(status, 400);
+        let (status, _) = call(&server, "POST", "/admin/map", json!({})).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn lists_clients_with_their_players() {
+        let mut server = Server::start(config()).await;
+        let (_player, id) = server.player(1).await;
+        let _viewer = server.viewer().await;
+
+        let (status, body) = call(&server, "GET", "/admin/clients", Value::Null).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["paused"], false);
+        let clients = body["clients"].as_array().unwrap();
+        assert_eq!(clients.len(), 2);
+        let player = clients.iter().find(|c| c["role"] == "player").unwrap();
+        assert_eq!(player["players"][0]["id"], id);
+        assert_eq!(player["players"][0]["health"], 100);
+    }
+
+    #[tokio::test]
+    async fn kicks_by_player_or_address() {
+        let mut server = Server::start(config()).await;
+        let (mut first, id) = server.player(2).await;
+        let mut second = server.viewer().await;
+
+        // Any of a connection's players names it
+        let kick = json!({ "player": format!("{id}#1"), "reason": "testing" });
+        let (status, body) = call(&server, "POST", "/admin/kick", kick).await;
+        assert_eq!((status, body), (200, json!({ "kicked": [first.addr] })));
+        assert_eq!(
+            first.expect("kicked").await.data,
+            json!({ "reason": "testing" })
+        );
+        disconnected(&mut server, &first).await;
+        assert!(server.players.lock().await.is_empty());
+
+        let (status, _) = call(
+            &server,
+            "POST",
+            "/admin/kick",
+            json!({ "addr": second.addr }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            second.expect("kicked").await.data["reason"],
+            "removed by an administrator"
+        );
+        disconnected(&mut server, &second).await;
+
+        let (status, body) = call(&server, "POST", "/admin/kick", json!({ "player": id })).await;
+        assert_eq!(
+            (status, body),
+            (404, json!({ "error": format!("no player `{id}`") }))
+        );
+        let (status, _) = call(
+            &server,
+            "POST",
+            "/admin/kick",
+            json!({ "addr": second.addr }),
+        )
+        .await;
+        assert_eq!(status, 404);
+        let both = json!({ "player": id, "addr": second.addr });
+        let (status, _) = call(&server, "POST", "/admin/kick", both).await;
+        assert_eq!(status, 400);
+        let (status, _) = call(&server, "POST", "/admin/kick", json!({})).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn bans_a_players_address_and_everyone_on_it() {
+        let mut server = Server::start(config()).await;
+        let ip: IpAddr = [127, 0, 0, 2].into();
+        let mut player = server.connect_from(ip);
+        player.send("register", json!({ "role": "player" }));
+        let id = crate::session::test::assigned(&mut player).await;
+        let mut viewer = server.connect_from(ip);
+        let _elsewhere = server.viewer().await;
+
+        let (status, body) = call(&server, "POST", "/admin/ban", json!({ "player": id })).await;
+        bans::unban(ip);
+        assert_eq!(status, 200);
+        assert_eq!(body["banned"], ip.to_string());
+        let mut kicked: Vec<SocketAddr> = serde_json::from_value(body["kicked"].clone()).unwrap();
+        kicked.sort();
+        let mut expected = vec![player.addr, viewer.addr];
+        expected.sort();
+        assert_eq!(kicked, expected);
+        player.expect("kicked").await;
+        viewer.expect("kicked").await;
+        assert_eq!(crate::session::client_info().await.len(), 1);
+
+        let ip = json!({ "ip": "192.0.2.7" });
+        let (status, body) = call(&server, "POST", "/admin/ban", ip.clone()).await;
+        assert_eq!(
+            (status, body),
+            (200, json!({ "banned": "192.0.2.7", "kicked": [] }))
+        );
+        let (_, body) = call(&server, "GET", "/admin/bans", Value::Null).await;
+        assert!(body["banned"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("192.0.2.7")));
+        let (_, body) = call(&server, "POST", "/admin/unban", ip.clone()).await;
+        assert_eq!(body, json!({ "unbanned": "192.0.2.7", "was_banned": true }));
+        let (_, body) = call(&server, "POST", "/admin/unban", ip).await;
+        assert_eq!(body["was_banned"], false);
+
+        let (status, _) = call(&server, "POST", "/admin/ban", json!({ "player": "nobody" })).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn reset_scores_sends_state_to_viewers_and_status_to_controllers() {
+        let mut server = Server::start(config()).await;
+        let (mut player, id) = server.player(1).await;
+        let mut viewer = server.viewer().await;
+        server.players.lock().await.get_mut(&id).unwrap().score = 12;
+        player.drain();
+
+        let (status, body) = call(&server, "POST", "/admin/reset-scores", Value::Null).await;
+        assert_eq!((status, body), (200, json!({ "reset": 1 })));
+        assert_eq!(viewer.expect("state").await.data[&id]["score"], 0);
+        let status = feedback(&mut player, "status").await;
+        assert_eq!(
+            (status["score"].clone(), status["health"].clone()),
+            (json!(0), json!(100))
+        );
+        assert!(!types(&mut player).contains(&"state".to_string()));
+    }
+
+    #[tokio::test]
+    async fn pausing_ignores_input_and_resuming_restarts_idle_timers() {
+        let mut server = Server::start(config()).await;
+        let _unpause = Unpause;
+        let (mut player, id) = server.player(1).await;
+
+        let (_, body) = call(&server, "POST", "/admin/pause", Value::Null).await;
+        assert_eq!(body, json!({ "paused": true }));
+        assert!(is_paused());
+        assert_eq!(
+            player.expect("paused").await.data,
+            json!({ "paused": true })
+        );
+
+        let ignored = || {
+            crate::metrics::MESSAGES_REJECTED
+                .with_label_values(&["paused"])
+                .get()
+        };
+        let before = ignored();
+        let buttons = json!({ "a": true, "b": false, "x": false, "y": false });
+        player.send(
+            "action",
+            json!({ "joystick": { "x": 1.0, "y": 0.0 }, "buttons": buttons }),
+        );
+        // Settings are answered, so the action ahead of them was handled
+        player.send("settings", json!({ "deadzone": 0.1 }));
+        feedback(&mut player, "settings").await;
+        assert_eq!(ignored(), before + 1);
+
+        let long_ago = tokio::time::Instant::now() - Duration::from_secs(60);
+        server.players.lock().await.get_mut(&id).unwrap().last_input = Activity(long_ago);
+        let (_, body) = call(&server, "POST", "/admin/resume", Value::Null).await;
+        assert_eq!(body, json!({ "paused": false }));
+        assert!(!is_paused());
+        assert_eq!(
+            player.expect("paused").await.data,
+            json!({ "paused": false })
+        );
+        let players = server.players.lock().await;
+        assert_eq!(players[&id].joystick.x, 0.0);
+        assert!(players[&id].last_input.0.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn announcements_reach_everyone() {
+        let mut server = Server::start(config()).await;
+        let (mut player, _) = server.player(1).await;
+        let mut viewer = server.viewer().await;
+
+        let (status, _) = call(
+            &server,
+            "POST",
+            "/admin/announce",
+            json!({ "message": "Final round" }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        for client in [&mut player, &mut viewer] {
+            let announcement = client.expect("announcement").await;
+            assert_eq!(announcement.data, json!({ "message": "Final round" }));
+        }
+    }
+}
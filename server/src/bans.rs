@@ -0,0 +1,112 @@
+//! Client IP addresses banned from connecting, either through the admin API
+//! or listed in the ban file.
+
+use anyhow::{bail, Context, Result};
+use std::{collections::BTreeSet, fs, net::IpAddr, path::Path, sync::Mutex};
+
+lazy_static::lazy_static! {
+    /// Bans made through the admin API while running.
+    static ref BANNED: Mutex<BTreeSet<IpAddr>> = Mutex::new(BTreeSet::new());
+    /// Bans read from the ban file, replaced whenever it is reloaded.
+    static ref FILE_BANNED: Mutex<BTreeSet<IpAddr>> = Mutex::new(BTreeSet::new());
+}
+
+pub fn is_banned(ip: IpAddr) -> bool {
+    BANNED.lock().unwrap().contains(&ip) || FILE_BANNED.lock().unwrap().contains(&ip)
+}
+
+/// Ban `ip`. Returns false if it was already banned.
+pub fn ban(ip: IpAddr) -> bool {
+    BANNED.lock().unwrap().insert(ip)
+}
+
+/// Lift the admin ban on `ip`. Returns false if it wasn't banned. Addresses
+/// in the ban file stay banned until they are removed from it.
+pub fn unban(ip: IpAddr) -> bool {
+    BANNED.lock().unwrap().remove(&ip)
+}
+
+pub fn list() -> Vec<IpAddr> {
+    let mut banned = BANNED.lock().unwrap().clone();
+    banned.extend(FILE_BANNED.lock().unwrap().iter());
+    banned.into_iter().collect()
+}
+
+/// Read a ban file: one IP address per line, with blank lines and `#`
+/// comments ignored. Any line that isn't an address fails the whole file.
+pub fn read_file(path: &Path) -> Result<BTreeSet<IpAddr>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read ban file {}", path.display()))?;
+    let mut banned = BTreeSet::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(ip) => banned.insert(ip),
+            Err(_) => bail!(
+                "{}:{}: `{line}` is not an IP address",
+                path.display(),
+                number + 1
+            ),
+        };
+    }
+    Ok(banned)
+}
+
+/// Replace the file bans with `banned`. Returns the addresses that were
+/// added and removed.
+pub fn set_file_bans(banned: BTreeSet<IpAddr>) -> (Vec<IpAddr>, Vec<IpAddr>) {
+    let mut current = FILE_BANNED.lock().unwrap();
+    let added = banned.difference(&current).copied().collect();
+    let removed = current.difference(&banned).copied().collect();
+    *current = banned;
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<BTreeSet<IpAddr>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.txt");
+        fs::write(&path, text).unwrap();
+        read_file(&path)
+    }
+
+    #[test]
+    fn reads_addresses_skipping_comments_and_blank_lines() {
+        let banned = read(
+            "# Known griefers\n\
+             \n\
+             192.0.2.1\n\
+             \x20 198.51.100.7  # returned under a new name\n\
+             2001:db8::1\n\
+             \t\n\
+             192.0.2.1\n",
+        )
+        .unwrap();
+        let expected: BTreeSet<IpAddr> = ["192.0.2.1", "198.51.100.7", "2001:db8::1"]
+            .into_iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        assert_eq!(banned, expected);
+        assert!(read("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_the_whole_file_over_one_bad_line() {
+        let error = read("192.0.2.1\n\n192.0.2.300\n").unwrap_err().to_string();
+        assert!(
+            error.ends_with(":3: `192.0.2.300` is not an IP address"),
+            "{error}"
+        );
+        assert!(read("192.0.2.0/24\n").is_err());
+        assert!(read("192.0.2.1:3000\n").is_err());
+
+        let error = read_file(Path::new("/nonexistent/bans.txt")).unwrap_err();
+        assert!(error.to_string().starts_with("failed to read ban file"));
+    }
+}
@@ -0,0 +1,395 @@
+//! Headless load-testing client. Connects simulated controllers and viewers
+//! to a game server over websocket, drives the controllers with scripted or
+//! random input and reports how the server kept up:
+//!
+//! ```sh
+//! cargo run --release --bin bot -- ws://127.0.0.1:3001/ws --controllers 50 --viewers 2
+//! ```
+//!
+//! Input latency is measured from a controller sending an `action` to the
+//! first viewer receiving the `state` broadcast that includes it, since only
+//! viewers are sent state. It needs at least one viewer.
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::{connect_async_with_config, tungstenite::Message as WsMessage};
+
+/// Inputs sent but not yet seen in a broadcast, per player. Older ones are
+/// given up on, e.g. while the game is paused.
+const MAX_PENDING_INPUTS: usize = 256;
+
+/// How often progress is printed while the test runs.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug, Clone)]
+#[clap(name = "bot", about = "Load test a game server with simulated clients")]
+struct Opt {
+    /// Websocket URL of the game server
+    #[clap(default_value = "ws://127.0.0.1:3001/ws")]
+    url: String,
+    /// Simulated controllers, each registering one player
+    #[clap(long, default_value_t = 10)]
+    controllers: usize,
+    /// Simulated viewers, which receive state and time inputs from it
+    #[clap(long, default_value_t = 1)]
+    viewers: usize,
+    /// Seconds to run once every client has started
+    #[clap(long, default_value_t = 30)]
+    duration: u64,
+    /// Inputs per second sent by each controller
+    #[clap(long, default_value_t = 30)]
+    rate: u32,
+    /// How controllers move their joystick and press buttons
+    #[clap(long, value_enum, default_value_t = InputMode::Random)]
+    input: InputMode,
+    /// Milliseconds between starting one client and the next
+    #[clap(long, default_value_t = 10)]
+    connect_interval: u64,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum InputMode {
+    /// Circle the stick and tap `a` on a fixed schedule
+    Scripted,
+    /// Wander the stick and press buttons at random
+    Random,
+}
+
+/// Measurements shared by every simulated client.
+#[derive(Default)]
+struct Stats {
+    connect_times: Mutex<Vec<Duration>>,
+    latencies: Mutex<Vec<Duration>>,
+    /// Each player's inputs not yet seen by a viewer, oldest first.
+    pending: Mutex<HashMap<String, VecDeque<(Input, Instant)>>>,
+    failures: AtomicU64,
+    inputs_sent: AtomicU64,
+    states_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opt = Opt::parse();
+    if opt.rate == 0 {
+        bail!("--rate must be at least 1");
+    }
+    if opt.viewers == 0 && opt.controllers > 0 {
+        eprintln!("no viewers, so input latency won't be measured");
+    }
+    let stats = Arc::new(Stats::default());
+    let started = Instant::now();
+    let clients = opt.controllers + opt.viewers;
+    let ramp = Duration::from_millis(opt.connect_interval) * clients as u32;
+    let deadline = started + ramp + Duration::from_secs(opt.duration);
+
+    let mut tasks = Vec::new();
+    for i in 0..clients {
+        let (client_opt, stats) = (opt.clone(), stats.clone());
+        let is_player = i >= opt.viewers;
+        tasks.push(tokio::spawn(async move {
+            let result = if is_player {
+                run_controller(&client_opt, &stats, deadline).await
+            } else {
+                run_viewer(&client_opt, &stats, deadline).await
+            };
+            if let Err(e) = result {
+                eprintln!("client {i}: {e:#}");
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        time::sleep(Duration::from_millis(opt.connect_interval)).await;
+    }
+
+    let progress = tokio::spawn(print_progress(stats.clone()));
+    for task in tasks {
+        task.await?;
+    }
+    progress.abort();
+    report(&opt, &stats, started.elapsed());
+    Ok(())
+}
+
+/// Connect and register, returning the socket and the first reply that
+/// isn't a state broadcast.
+async fn connect(
+    opt: &Opt,
+    stats: &Stats,
+    role: &str,
+) -> Result<(
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    Value,
+)> {
+    let start = Instant::now();
+    let (mut ws, _) = connect_async_with_config(&opt.url, None, true)
+        .await
+        .with_context(|| format!("failed to connect to {}", opt.url))?;
+    let register = json!({ "type": "register", "data": { "role": role } });
+    ws.send(WsMessage::Text(register.to_string())).await?;
+
+    // Viewers get no reply to registering, only broadcasts
+    if role != "player" {
+        stats.connect_times.lock().unwrap().push(start.elapsed());
+        return Ok((ws, Value::Null));
+    }
+    while let Some(message) = ws.next().await {
+        let WsMessage::Text(text) = message? else {
+            continue;
+        };
+        let message: Value = serde_json::from_str(&text)?;
+        if message["type"] != "state" {
+            stats.connect_times.lock().unwrap().push(start.elapsed());
+            return Ok((ws, message));
+        }
+    }
+    bail!("connection closed while registering")
+}
+
+async fn run_controller(opt: &Opt, stats: &Stats, deadline: Instant) -> Result<()> {
+    let (ws, reply) = connect(opt, stats, "player").await?;
+    if reply["data"]["kind"] != "assigned" {
+        bail!("not registered as a player: {reply}");
+    }
+    let player_id = reply["data"]["player_id"]
+        .as_str()
+        .context("no player id assigned")?
+        .to_string();
+    let (mut sender, mut receiver) = ws.split();
+
+    let mut input = InputScript::new(opt.input);
+    let mut ticks = time::interval(Duration::from_secs(1) / opt.rate);
+    let end = time::sleep_until(deadline);
+    tokio::pin!(end);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let action = input.next();
+                let message = json!({ "type": "action", "data": action.to_json() });
+                sender.send(WsMessage::Text(message.to_string())).await?;
+                stats.inputs_sent.fetch_add(1, Ordering::Relaxed);
+                let mut pending = stats.pending.lock().unwrap();
+                let pending = pending.entry(player_id.clone()).or_default();
+                if pending.len() == MAX_PENDING_INPUTS {
+                    pending.pop_front();
+                }
+                pending.push_back((action, Instant::now()));
+            }
+            // Controllers get their own status rather than state
+            message = receiver.next() => {
+                let Some(message) = message else {
+                    bail!("server closed the connection");
+                };
+                if let WsMessage::Text(text) = message? {
+                    stats.bytes_received.fetch_add(text.len() as u64, Ordering::Relaxed);
+                }
+            }
+            _ = &mut end => break,
+        }
+    }
+    let _ = sender.close().await;
+    Ok(())
+}
+
+async fn run_viewer(opt: &Opt, stats: &Stats, deadline: Instant) -> Result<()> {
+    let (mut ws, _) = connect(opt, stats, "viewer").await?;
+    let end = time::sleep_until(deadline);
+    tokio::pin!(end);
+    loop {
+        tokio::select! {
+            message = ws.next() => {
+                let Some(message) = message else {
+                    bail!("server closed the connection");
+                };
+                if let WsMessage::Text(text) = message? {
+                    if let Some(state) = read_state(stats, &text) {
+                        time_inputs(stats, &state);
+                    }
+                }
+            }
+            _ = &mut end => break,
+        }
+    }
+    let _ = ws.close(None).await;
+    Ok(())
+}
+
+/// Count a received message, returning its players if it's a state broadcast.
+fn read_state(stats: &Stats, text: &str) -> Option<serde_json::Map<String, Value>> {
+    stats
+        .bytes_received
+        .fetch_add(text.len() as u64, Ordering::Relaxed);
+    let message: Value = serde_json::from_str(text).ok()?;
+    if message["type"] != "state" {
+        return None;
+    }
+    stats.states_received.fetch_add(1, Ordering::Relaxed);
+    match message {
+        Value::Object(mut message) => match message.remove("data")? {
+            Value::Object(players) => Some(players),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Record the latency of every pending input that `state` shows.
+fn time_inputs(stats: &Stats, state: &serde_json::Map<String, Value>) {
+    let mut pending = stats.pending.lock().unwrap();
+    for (player_id, player) in state {
+        let Some(pending) = pending.get_mut(player_id) else {
+            continue;
+        };
+        // Broadcasts follow inputs in order, so anything sent before the
+        // matching input has been overtaken
+        let seen = pending
+            .iter()
+            .position(|(action, _)| action.seen_in(player));
+        if let Some(position) = seen {
+            let (_, sent) = pending[position];
+            pending.drain(..=position);
+            stats.latencies.lock().unwrap().push(sent.elapsed());
+        }
+    }
+}
+
+/// One controller input.
+#[derive(Clone, Copy, PartialEq)]
+struct Input {
+    stick: (f32, f32),
+    buttons: [bool; 4],
+}
+
+impl Input {
+    /// The data of an `action` message carrying this input.
+    fn to_json(self) -> Value {
+        let [a, b, x, y] = self.buttons;
+        json!({
+            "joystick": { "x": self.stick.0, "y": self.stick.1 },
+            "buttons": { "a": a, "b": b, "x": x, "y": y },
+        })
+    }
+
+    /// Whether a player's broadcast state shows this input. The server keeps
+    /// the stick as `f32`, so it's compared at that precision.
+    fn seen_in(&self, player: &Value) -> bool {
+        let axis = |name: &str| player["joystick"][name].as_f64().map(|v| v as f32);
+        let button = |name: &str| player["buttons"][name].as_bool();
+        axis("x") == Some(self.stick.0)
+            && axis("y") == Some(self.stick.1)
+            && ["a", "b", "x", "y"].map(button) == self.buttons.map(Some)
+    }
+}
+
+/// Generates one controller's inputs.
+struct InputScript {
+    mode: InputMode,
+    step: u64,
+    stick: (f32, f32),
+}
+
+impl InputScript {
+    fn new(mode: InputMode) -> Self {
+        InputScript {
+            mode,
+            step: 0,
+            stick: (0.0, 0.0),
+        }
+    }
+
+    fn next(&mut self) -> Input {
+        self.step += 1;
+        let (stick, buttons) = match self.mode {
+            InputMode::Scripted => {
+                let angle = self.step as f32 / 30.0;
+                let a = self.step % 60 < 5;
+                ((angle.cos(), angle.sin()), [a, false, false, false])
+            }
+            InputMode::Random => {
+                let mut rng = rand::thread_rng();
+                let x = (self.stick.0 + rng.gen_range(-0.2..0.2)).clamp(-1.0, 1.0);
+                let y = (self.stick.1 + rng.gen_range(-0.2..0.2)).clamp(-1.0, 1.0);
+                ((x, y), [(); 4].map(|_| rng.gen_bool(0.1)))
+            }
+        };
+        self.stick = stick;
+        Input { stick, buttons }
+    }
+}
+
+async fn print_progress(stats: Arc<Stats>) {
+    let mut interval = time::interval(PROGRESS_INTERVAL);
+    interval.tick().await;
+    let (mut sent, mut received) = (0, 0);
+    loop {
+        interval.tick().await;
+        let now_sent = stats.inputs_sent.load(Ordering::Relaxed);
+        let now_received = stats.states_received.load(Ordering::Relaxed);
+        let secs = PROGRESS_INTERVAL.as_secs_f64();
+        println!(
+            "{} connected, {:.0} inputs/s sent, {:.0} states/s received, {} failed",
+            stats.connect_times.lock().unwrap().len(),
+            (now_sent - sent) as f64 / secs,
+            (now_received - received) as f64 / secs,
+            stats.failures.load(Ordering::Relaxed),
+        );
+        (sent, received) = (now_sent, now_received);
+    }
+}
+
+fn report(opt: &Opt, stats: &Stats, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let connected = stats.connect_times.lock().unwrap().len();
+    println!();
+    println!(
+        "clients:   {connected} of {} connected, {} failed",
+        opt.controllers + opt.viewers,
+        stats.failures.load(Ordering::Relaxed)
+    );
+    println!(
+        "connect:   {}",
+        percentiles(&mut stats.connect_times.lock().unwrap())
+    );
+    println!(
+        "messages:  {:.0} inputs/s sent, {:.0} states/s received, {:.1} KiB/s received",
+        stats.inputs_sent.load(Ordering::Relaxed) as f64 / secs,
+        stats.states_received.load(Ordering::Relaxed) as f64 / secs,
+        stats.bytes_received.load(Ordering::Relaxed) as f64 / 1024.0 / secs,
+    );
+    let mut latencies = stats.latencies.lock().unwrap();
+    println!(
+        "latency:   {} ({} of {} inputs seen)",
+        percentiles(&mut latencies),
+        latencies.len(),
+        stats.inputs_sent.load(Ordering::Relaxed)
+    );
+}
+
+fn percentiles(samples: &mut [Duration]) -> String {
+    if samples.is_empty() {
+        return "no samples".to_string();
+    }
+    samples.sort_unstable();
+    let at = |p: f64| {
+        let index = ((samples.len() - 1) as f64 * p).round() as usize;
+        format!("{:.2}ms", samples[index].as_secs_f64() * 1000.0)
+    };
+    format!(
+        "p50 {}, p90 {}, p99 {}, max {}",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(1.0)
+    )
+}
@@ -0,0 +1,964 @@
+//! Server configuration, read from the command line with environment variable
+//! and TOML config file fallbacks (in that order of precedence).
+
+use crate::game_state::{AfkAction, Role};
+use crate::static_files;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[clap(name = "game-server")]
+struct Cli {
+    /// TOML config file to read settings from
+    #[clap(long, env = "DUCKGAME_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on; repeat or comma-separate for several
+    #[clap(long, env = "DUCKGAME_LISTEN", value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+    /// Address to accept TLS (`wss://`) connections on; repeat or
+    /// comma-separate for several
+    #[clap(long, env = "DUCKGAME_TLS_LISTEN", value_delimiter = ',')]
+    tls_listen: Vec<SocketAddr>,
+    /// UDP address to accept QUIC controller connections on; repeat or
+    /// comma-separate for several
+    #[clap(long, env = "DUCKGAME_QUIC_LISTEN", value_delimiter = ',')]
+    quic_listen: Vec<SocketAddr>,
+    /// TLS certificate chain, PEM or DER (self-signed if unset)
+    #[clap(long, env = "DUCKGAME_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// TLS private key, PEM or DER
+    #[clap(long, env = "DUCKGAME_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Origins allowed to open websockets, e.g. `https://duck.example`; any
+    /// origin if unset
+    #[clap(long, env = "DUCKGAME_ALLOWED_ORIGINS", value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+    /// Seconds a client has to complete the TLS and websocket handshakes
+    #[clap(long, env = "DUCKGAME_HANDSHAKE_SECS")]
+    handshake_secs: Option<u64>,
+    /// Largest websocket message or frame accepted from a client, in bytes
+    #[clap(long, env = "DUCKGAME_MAX_MESSAGE_BYTES")]
+    max_message_bytes: Option<usize>,
+    /// Offer websocket clients permessage-deflate compression
+    #[clap(long, env = "DUCKGAME_WEBSOCKET_COMPRESSION")]
+    websocket_compression: bool,
+    /// Smallest message compressed for clients that accept compression, in
+    /// bytes
+    #[clap(long, env = "DUCKGAME_COMPRESSION_MIN_BYTES")]
+    compression_min_bytes: Option<usize>,
+    /// Roles whose messages are compressed: `viewer`, `player` or both
+    /// comma-separated
+    #[clap(long, env = "DUCKGAME_COMPRESSION_ROLES", value_delimiter = ',', value_parser = parse_role)]
+    compression_roles: Vec<Role>,
+    /// Concurrent connections allowed from one IP address (0 for no limit)
+    #[clap(long, env = "DUCKGAME_MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<usize>,
+    /// Directory of the built web frontend to serve, e.g. `../dist`
+    #[clap(long, env = "DUCKGAME_STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+    /// Paths under the static directory whose subdirectories are listed when
+    /// they have no index.html, e.g. `/maps`; none if unset
+    #[clap(long, env = "DUCKGAME_LISTED_DIRS", value_delimiter = ',')]
+    listed_dirs: Vec<String>,
+    /// Websocket address of the server handed to the frontend in
+    /// `/config.json` and join links, without a path, e.g.
+    /// `wss://duck.example`; controllers add `/ws` themselves. Defaults to
+    /// the address the page was loaded from
+    #[clap(long, env = "DUCKGAME_WEBSOCKET_URL")]
+    websocket_url: Option<String>,
+    /// Controller page join links and QR codes open, e.g.
+    /// `https://duck.example/controls`; defaults to `/controls` on the game
+    /// port
+    #[clap(long, env = "DUCKGAME_CONTROLLER_URL")]
+    controller_url: Option<String>,
+    /// Address for the Prometheus `/metrics` endpoint (disabled if unset)
+    #[clap(long, env = "DUCKGAME_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+    /// Milliseconds between simulation ticks
+    #[clap(long, env = "DUCKGAME_TICK_MS")]
+    tick_ms: Option<u64>,
+    /// Milliseconds between state broadcasts to viewers
+    #[clap(long, env = "DUCKGAME_SEND_MS")]
+    send_ms: Option<u64>,
+    /// Maximum number of registered players
+    #[clap(long, env = "DUCKGAME_MAX_PLAYERS")]
+    max_players: Option<usize>,
+    /// Maximum number of registered viewers
+    #[clap(long, env = "DUCKGAME_MAX_VIEWERS")]
+    max_viewers: Option<usize>,
+    /// Players that may wait for a free slot when the server is full (0
+    /// turns them away instead)
+    #[clap(long, env = "DUCKGAME_PLAYER_QUEUE")]
+    player_queue: Option<usize>,
+    /// Seconds without input before a player is marked idle
+    #[clap(long, env = "DUCKGAME_IDLE_SECS")]
+    idle_secs: Option<u64>,
+    /// Seconds without input before an idle player is retired
+    #[clap(long, env = "DUCKGAME_AFK_SECS")]
+    afk_secs: Option<u64>,
+    /// What to do with AFK players: `spectate` or `remove`
+    #[clap(long, env = "DUCKGAME_AFK_ACTION", value_parser = parse_afk_action)]
+    afk_action: Option<AfkAction>,
+    /// Log filter, e.g. `info` or `game_server=debug`
+    #[clap(long, env = "DUCKGAME_LOG")]
+    log_level: Option<String>,
+    /// Log output format
+    #[clap(long, env = "DUCKGAME_LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// Directory containing map JSON files
+    #[clap(long, env = "DUCKGAME_MAP_DIR")]
+    map_dir: Option<PathBuf>,
+    /// File of banned IP addresses, one per line
+    #[clap(long, env = "DUCKGAME_BAN_FILE")]
+    ban_file: Option<PathBuf>,
+    /// Message of the day shown to clients when they register
+    #[clap(long, env = "DUCKGAME_MOTD")]
+    motd: Option<String>,
+    /// Bearer token for the `/admin` API (disabled if unset)
+    #[clap(long, env = "DUCKGAME_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Announce the server on the local network so clients can find it
+    #[clap(long, env = "DUCKGAME_ANNOUNCE")]
+    announce: bool,
+    /// Name the server announces itself by
+    #[clap(long, env = "DUCKGAME_SERVER_NAME")]
+    server_name: Option<String>,
+    /// Multicast group and port servers are announced and discovered on
+    #[clap(long, env = "DUCKGAME_DISCOVERY_GROUP")]
+    discovery_group: Option<SocketAddrV4>,
+    /// Address of the network interface to announce and discover on, e.g.
+    /// `127.0.0.1` to stay on this host; chosen by the system if unset
+    #[clap(long, env = "DUCKGAME_DISCOVERY_INTERFACE")]
+    discovery_interface: Option<Ipv4Addr>,
+    /// List the game servers announced on the local network and exit
+    #[clap(long)]
+    discover: bool,
+}
+
+/// Settings as written in the TOML config file. Every key is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<Vec<SocketAddr>>,
+    tls_listen: Option<Vec<SocketAddr>>,
+    quic_listen: Option<Vec<SocketAddr>>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    allowed_origins: Option<Vec<String>>,
+    handshake_secs: Option<u64>,
+    max_message_bytes: Option<usize>,
+    websocket_compression: Option<bool>,
+    compression_min_bytes: Option<usize>,
+    compression_roles: Option<Vec<Role>>,
+    max_connections_per_ip: Option<usize>,
+    static_dir: Option<PathBuf>,
+    listed_dirs: Option<Vec<String>>,
+    websocket_url: Option<String>,
+    controller_url: Option<String>,
+    metrics_listen: Option<SocketAddr>,
+    tick_ms: Option<u64>,
+    send_ms: Option<u64>,
+    max_players: Option<usize>,
+    max_viewers: Option<usize>,
+    player_queue: Option<usize>,
+    idle_secs: Option<u64>,
+    afk_secs: Option<u64>,
+    afk_action: Option<AfkAction>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+    map_dir: Option<PathBuf>,
+    ban_file: Option<PathBuf>,
+    motd: Option<String>,
+    admin_token: Option<String>,
+    announce: Option<bool>,
+    server_name: Option<String>,
+    discovery_group: Option<SocketAddrV4>,
+    discovery_interface: Option<Ipv4Addr>,
+}
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log ingestion.
+    Json,
+}
+
+/// The live configuration, updated when the config file is reloaded. Borrow
+/// it briefly and clone the `Arc` rather than holding the borrow.
+pub type ConfigWatch = watch::Receiver<Arc<Config>>;
+
+/// Fully resolved server configuration.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The TOML file the configuration was read from, watched for changes.
+    pub config_file: Option<PathBuf>,
+    pub listen: Vec<SocketAddr>,
+    pub tls_listen: Vec<SocketAddr>,
+    /// UDP addresses for QUIC controllers, which share the TLS certificate.
+    pub quic_listen: Vec<SocketAddr>,
+    /// Certificate chain and key for the TLS and QUIC listeners. Both or neither are
+    /// set; without them a self-signed certificate is used.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Origins allowed to open websockets; empty allows any.
+    pub allowed_origins: Vec<String>,
+    pub handshake_timeout: Duration,
+    pub max_message_size: usize,
+    /// Whether to agree to permessage-deflate with clients that offer it.
+    pub websocket_compression: bool,
+    /// Messages shorter than this are sent uncompressed.
+    pub compression_threshold: usize,
+    /// Roles of the clients messages are compressed for.
+    pub compression_roles: Vec<Role>,
+    /// Concurrent connections allowed per IP address; 0 means no limit.
+    pub max_connections_per_ip: usize,
+    /// Built web frontend served for plain HTTP requests to the game port.
+    pub static_dir: Option<PathBuf>,
+    /// Routes under [`Config::static_dir`] whose directories get listings;
+    /// empty lists none.
+    pub listed_dirs: Vec<String>,
+    pub websocket_url: Option<String>,
+    /// Controller page that join links point at.
+    pub controller_url: Option<String>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub tick_rate: Duration,
+    pub send_rate: Duration,
+    pub max_players: usize,
+    pub max_viewers: usize,
+    /// How many players may queue for a slot; 0 disables the queue.
+    pub player_queue: usize,
+    pub idle_after: Duration,
+    pub afk_timeout: Duration,
+    pub afk_action: AfkAction,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub map_dir: Option<PathBuf>,
+    /// Banned IP addresses, one per line, watched for changes.
+    pub ban_file: Option<PathBuf>,
+    pub motd: Option<String>,
+    /// Token the admin API expects as `Authorization: Bearer <token>`.
+    pub admin_token: Option<String>,
+    /// Whether to announce the server on [`Config::discovery_group`].
+    pub announce: bool,
+    pub server_name: String,
+    pub discovery_group: SocketAddrV4,
+    /// Interface for discovery multicast; unspecified lets the system pick.
+    pub discovery_interface: Ipv4Addr,
+    /// Look for servers instead of running one.
+    pub discover: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_file: None,
+            // Listen on all interfaces so that clients anywhere can connect.
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 3001))],
+            tls_listen: Vec::new(),
+            quic_listen: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            allowed_origins: Vec::new(),
+            handshake_timeout: Duration::from_secs(10),
+            max_message_size: 64 * 1024,
+            // Off like the old server, which found it cost too much CPU
+            websocket_compression: false,
+            compression_threshold: 256,
+            // Viewers get the large state broadcasts
+            compression_roles: vec![Role::Viewer],
+            max_connections_per_ip: 16,
+            static_dir: None,
+            listed_dirs: Vec::new(),
+            websocket_url: None,
+            controller_url: None,
+            metrics_listen: None,
+            tick_rate: Duration::from_millis(16), // ~60 FPS
+            send_rate: Duration::from_millis(16),
+            max_players: 8,
+            max_viewers: 16,
+            player_queue: 0,
+            idle_after: Duration::from_secs(30),
+            afk_timeout: Duration::from_secs(120),
+            afk_action: AfkAction::Spectate,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            map_dir: None,
+            ban_file: None,
+            motd: None,
+            admin_token: None,
+            announce: false,
+            server_name: "DuckGame".to_string(),
+            // Organization-local scope, so announcements stay on the site
+            discovery_group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 42), 3002),
+            discovery_interface: Ipv4Addr::UNSPECIFIED,
+            discover: false,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the command line, environment and config
+    /// file, and validate it.
+    pub fn load() -> Result<Config> {
+        Config::read(Cli::parse())
+    }
+
+    /// Read the configuration again, for a reload while running. Command line
+    /// flags and environment variables still take precedence over the file.
+    pub fn reload() -> Result<Config> {
+        Config::read(Cli::try_parse()?)
+    }
+
+    fn read(cli: Cli) -> Result<Config> {
+        let file = match &cli.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => FileConfig::default(),
+        };
+        let config = Config::merge(cli, file);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn merge(cli: Cli, file: FileConfig) -> Config {
+        let defaults = Config::default();
+        let listen = if cli.listen.is_empty() {
+            file.listen.unwrap_or(defaults.listen)
+        } else {
+            cli.listen
+        };
+        let tls_listen = if cli.tls_listen.is_empty() {
+            file.tls_listen.unwrap_or(defaults.tls_listen)
+        } else {
+            cli.tls_listen
+        };
+        let quic_listen = if cli.quic_listen.is_empty() {
+            file.quic_listen.unwrap_or(defaults.quic_listen)
+        } else {
+            cli.quic_listen
+        };
+        Config {
+            config_file: cli.config,
+            listen,
+            tls_listen,
+            quic_listen,
+            tls_cert: cli.tls_cert.or(file.tls_cert),
+            tls_key: cli.tls_key.or(file.tls_key),
+            allowed_origins: if cli.allowed_origins.is_empty() {
+                file.allowed_origins.unwrap_or(defaults.allowed_origins)
+            } else {
+                cli.allowed_origins
+            },
+            handshake_timeout: cli
+                .handshake_secs
+                .or(file.handshake_secs)
+                .map_or(defaults.handshake_timeout, Duration::from_secs),
+            max_message_size: cli
+                .max_message_bytes
+                .or(file.max_message_bytes)
+                .unwrap_or(defaults.max_message_size),
+            websocket_compression: cli.websocket_compression
+                || file
+                    .websocket_compression
+                    .unwrap_or(defaults.websocket_compression),
+            compression_threshold: cli
+                .compression_min_bytes
+                .or(file.compression_min_bytes)
+                .unwrap_or(defaults.compression_threshold),
+            compression_roles: if cli.compression_roles.is_empty() {
+                file.compression_roles.unwrap_or(defaults.compression_roles)
+            } else {
+                cli.compression_roles
+            },
+            max_connections_per_ip: cli
+                .max_connections_per_ip
+                .or(file.max_connections_per_ip)
+                .unwrap_or(defaults.max_connections_per_ip),
+            static_dir: cli.static_dir.or(file.static_dir),
+            listed_dirs: if cli.listed_dirs.is_empty() {
+                file.listed_dirs.unwrap_or(defaults.listed_dirs)
+            } else {
+                cli.listed_dirs
+            },
+            websocket_url: cli.websocket_url.or(file.websocket_url),
+            controller_url: cli.controller_url.or(file.controller_url),
+            metrics_listen: cli.metrics_listen.or(file.metrics_listen),
+            tick_rate: cli
+                .tick_ms
+                .or(file.tick_ms)
+                .map_or(defaults.tick_rate, Duration::from_millis),
+            send_rate: cli
+                .send_ms
+                .or(file.send_ms)
+                .map_or(defaults.send_rate, Duration::from_millis),
+            max_players: cli
+                .max_players
+                .or(file.max_players)
+                .unwrap_or(defaults.max_players),
+            max_viewers: cli
+                .max_viewers
+                .or(file.max_viewers)
+                .unwrap_or(defaults.max_viewers),
+            player_queue: cli
+                .player_queue
+                .or(file.player_queue)
+                .unwrap_or(defaults.player_queue),
+            idle_after: cli
+                .idle_secs
+                .or(file.idle_secs)
+                .map_or(defaults.idle_after, Duration::from_secs),
+            afk_timeout: cli
+                .afk_secs
+                .or(file.afk_secs)
+                .map_or(defaults.afk_timeout, Duration::from_secs),
+            afk_action: cli
+                .afk_action
+                .or(file.afk_action)
+                .unwrap_or(defaults.afk_action),
+            log_level: cli
+                .log_level
+                .or(file.log_level)
+                .unwrap_or(defaults.log_level),
+            log_format: cli
+                .log_format
+                .or(file.log_format)
+                .unwrap_or(defaults.log_format),
+            map_dir: cli.map_dir.or(file.map_dir),
+            ban_file: cli.ban_file.or(file.ban_file),
+            motd: cli.motd.or(file.motd),
+            admin_token: cli.admin_token.or(file.admin_token),
+            announce: cli.announce || file.announce.unwrap_or(defaults.announce),
+            server_name: cli
+                .server_name
+                .or(file.server_name)
+                .unwrap_or(defaults.server_name),
+            discovery_group: cli
+                .discovery_group
+                .or(file.discovery_group)
+                .unwrap_or(defaults.discovery_group),
+            discovery_interface: cli
+                .discovery_interface
+                .or(file.discovery_interface)
+                .unwrap_or(defaults.discovery_interface),
+            discover: cli.discover,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() && self.tls_listen.is_empty() {
+            bail!("at least one listen or tls_listen address is required");
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            bail!("tls_cert and tls_key must be set together");
+        }
+        if self.handshake_timeout.is_zero() {
+            bail!("handshake_secs must be greater than zero");
+        }
+        if self.max_message_size < 1024 {
+            bail!("max_message_bytes must be at least 1024");
+        }
+        if let Some(origin) = self.allowed_origins.iter().find(|o| !o.contains("://")) {
+            bail!("allowed origin `{origin}` needs a scheme, e.g. `https://{origin}`");
+        }
+        if self.tick_rate.is_zero() {
+            bail!("tick_ms must be greater than zero");
+        }
+        if self.send_rate < self.tick_rate {
+            bail!(
+                "send_ms ({:?}) must not be shorter than tick_ms ({:?})",
+                self.send_rate,
+                self.tick_rate
+            );
+        }
+        if self.max_players == 0 {
+            bail!("max_players must be at least 1");
+        }
+        if self.idle_after.is_zero() {
+            bail!("idle_secs must be greater than zero");
+        }
+        if self.afk_timeout <= self.idle_after {
+            bail!(
+                "afk_secs ({:?}) must be longer than idle_secs ({:?})",
+                self.afk_timeout,
+                self.idle_after
+            );
+        }
+        if let Err(e) = self.log_level.parse::<EnvFilter>() {
+            bail!("invalid log_level `{}`: {}", self.log_level, e);
+        }
+        if let Some(dir) = &self.static_dir {
+            if !dir.join("index.html").is_file() {
+                bail!("static directory {} has no index.html", dir.display());
+            }
+        }
+        if let Some(dir) = self
+            .listed_dirs
+            .iter()
+            .find(|d| static_files::resolve(d).is_none())
+        {
+            bail!("listed dir `{dir}` must be a path like `/maps`");
+        }
+        if let Some(url) = &self.websocket_url {
+            if !url.starts_with("ws://") && !url.starts_with("wss://") {
+                bail!("websocket_url `{url}` must start with ws:// or wss://");
+            }
+            let path = url.parse::<::http::Uri>().map(|uri| uri.path().to_string());
+            if !matches!(path.as_deref(), Ok("/" | "")) {
+                bail!("websocket_url `{url}` must be the server's address without a path");
+            }
+        }
+        if let Some(url) = &self.controller_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("controller_url `{url}` must start with http:// or https://");
+            }
+        }
+        if let Some(path) = &self.ban_file {
+            if !path.is_file() {
+                bail!("ban file {} does not exist", path.display());
+            }
+        }
+        if let Some(dir) = &self.map_dir {
+            if !dir.is_dir() {
+                bail!("map directory {} does not exist", dir.display());
+            }
+        }
+        if self.admin_token.as_deref().is_some_and(str::is_empty) {
+            bail!("admin_token must not be empty");
+        }
+        if self.server_name.trim().is_empty() {
+            bail!("server_name must not be empty");
+        }
+        if !self.discovery_group.ip().is_multicast() {
+            bail!(
+                "discovery_group {} is not a multicast address",
+                self.discovery_group
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Compare fields of two configs, collecting each change as
+/// `field: old -> new`.
+macro_rules! compare {
+    ($changes:ident, $old:ident, $new:ident, $($field:ident),*) => {
+        $(
+            if $old.$field != $new.$field {
+                $changes.push(format!(
+                    concat!(stringify!($field), ": {:?} -> {:?}"),
+                    $old.$field, $new.$field
+                ));
+            }
+        )*
+    };
+}
+
+/// The outcome of reloading the configuration while running.
+pub struct Reload {
+    /// The configuration to switch to.
+    pub config: Config,
+    /// Settings that changed and take effect now.
+    pub applied: Vec<String>,
+    /// Settings that changed but only take effect after a restart. The
+    /// reloaded configuration keeps their running values.
+    pub needs_restart: Vec<String>,
+}
+
+impl Config {
+    /// Work out which settings of `new` can replace the running ones. Listen
+    /// addresses, certificates, map, log format and discovery group are only
+    /// read at startup.
+    pub fn reloaded(&self, mut new: Config) -> Reload {
+        let old = self;
+        let mut applied = Vec::new();
+        compare!(
+            applied,
+            old,
+            new,
+            allowed_origins,
+            handshake_timeout,
+            max_message_size,
+            websocket_compression,
+            compression_threshold,
+            compression_roles,
+            max_connections_per_ip,
+            static_dir,
+            listed_dirs,
+            websocket_url,
+            controller_url,
+            tick_rate,
+            send_rate,
+            max_players,
+            max_viewers,
+            player_queue,
+            idle_after,
+            afk_timeout,
+            afk_action,
+            log_level,
+            ban_file,
+            motd,
+            server_name
+        );
+        // Never log the token itself
+        if old.admin_token != new.admin_token {
+            applied.push("admin_token: changed".to_string());
+        }
+
+        let mut needs_restart = Vec::new();
+        compare!(
+            needs_restart,
+            old,
+            new,
+            listen,
+            tls_listen,
+            quic_listen,
+            tls_cert,
+            tls_key,
+            metrics_listen,
+            log_format,
+            map_dir,
+            announce,
+            discovery_group,
+            discovery_interface
+        );
+        new.listen = old.listen.clone();
+        new.tls_listen = old.tls_listen.clone();
+        new.quic_listen = old.quic_listen.clone();
+        new.tls_cert = old.tls_cert.clone();
+        new.tls_key = old.tls_key.clone();
+        new.metrics_listen = old.metrics_listen;
+        new.log_format = old.log_format;
+        new.map_dir = old.map_dir.clone();
+        new.announce = old.announce;
+        new.discovery_group = old.discovery_group;
+        new.discovery_interface = old.discovery_interface;
+
+        Reload {
+            config: new,
+            applied,
+            needs_restart,
+        }
+    }
+}
+
+fn parse_role(s: &str) -> Result<Role, String> {
+    match s {
+        "viewer" => Ok(Role::Viewer),
+        "player" => Ok(Role::Player),
+        _ => Err(format!("expected `viewer` or `player`, got `{s}`")),
+    }
+}
+
+fn parse_afk_action(s: &str) -> Result<AfkAction, String> {
+    match s {
+        "spectate" => Ok(AfkAction::Spectate),
+        "remove" => Ok(AfkAction::Remove),
+        _ => Err(format!("expected `spectate` or `remove`, got `{s}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Command lines read `DUCKGAME_*` variables, which tests set one at a
+    /// time.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn cli(args: &[&str]) -> Cli {
+        let _env = ENV.lock().unwrap();
+        Cli::try_parse_from(std::iter::once("game-server").chain(args.iter().copied())).unwrap()
+    }
+
+    fn file(toml: &str) -> FileConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// Why `config` fails validation.
+    fn invalid(config: Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn command_line_wins_over_file_over_defaults() {
+        let file = file(
+            r#"
+            listen = ["127.0.0.1:4000", "127.0.0.1:4001"]
+            max_players = 4
+            tick_ms = 20
+            send_ms = 40
+            allowed_origins = ["https://file.example"]
+            announce = true
+            "#,
+        );
+        let cli = cli(&["--max-players", "6", "--listen", "127.0.0.1:5000"]);
+        let config = Config::merge(cli, file);
+
+        assert_eq!(config.max_players, 6);
+        assert_eq!(config.listen, ["127.0.0.1:5000".parse().unwrap()]);
+        assert_eq!(config.tick_rate, Duration::from_millis(20));
+        assert_eq!(config.send_rate, Duration::from_millis(40));
+        assert_eq!(config.allowed_origins, ["https://file.example"]);
+        assert!(config.announce);
+        assert_eq!(config.max_viewers, Config::default().max_viewers);
+        assert_eq!(config.server_name, "DuckGame");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn environment_sits_between_command_line_and_file() {
+        let file = || file("server_name = \"From file\"");
+        let _env = ENV.lock().unwrap();
+        std::env::set_var("DUCKGAME_SERVER_NAME", "From env");
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once("game-server").chain(args.iter().copied()))
+        };
+        let from_env = parse(&[]).map(|cli| Config::merge(cli, file()));
+        let from_cli = parse(&["--server-name", "From cli"]).map(|cli| Config::merge(cli, file()));
+        std::env::remove_var("DUCKGAME_SERVER_NAME");
+
+        assert_eq!(from_env.unwrap().server_name, "From env");
+        assert_eq!(from_cli.unwrap().server_name, "From cli");
+        assert_eq!(
+            Config::merge(parse(&[]).unwrap(), file()).server_name,
+            "From file"
+        );
+    }
+
+    #[test]
+    fn file_rejects_unknown_and_mistyped_keys() {
+        let unknown = toml::from_str::<FileConfig>("max_player = 3").unwrap_err();
+        assert!(
+            unknown.to_string().contains("unknown field `max_player`"),
+            "{unknown}"
+        );
+        let mistyped = toml::from_str::<FileConfig>("max_players = \"lots\"").unwrap_err();
+        assert!(mistyped.to_string().contains("max_players"), "{mistyped}");
+        assert!(toml::from_str::<FileConfig>("afk_action = \"sleep\"").is_err());
+        assert!(toml::from_str::<FileConfig>("").is_ok());
+    }
+
+    #[test]
+    fn reads_the_config_file_named_on_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        let path_arg = path.to_str().unwrap();
+
+        fs::write(&path, "max_players = 3\nmotd = \"hello\"\n").unwrap();
+        let config = Config::read(cli(&["--config", path_arg])).unwrap();
+        assert_eq!(
+            (config.max_players, config.motd.as_deref()),
+            (3, Some("hello"))
+        );
+
+        fs::write(&path, "max_players = 0\n").unwrap();
+        let error = Config::read(cli(&["--config", path_arg])).unwrap_err();
+        assert_eq!(error.to_string(), "max_players must be at least 1");
+
+        fs::write(&path, "motd = \n").unwrap();
+        let error = Config::read(cli(&["--config", path_arg])).unwrap_err();
+        assert!(
+            error.to_string().starts_with("invalid config file"),
+            "{error}"
+        );
+
+        let missing = dir.path().join("missing.toml");
+        let error = Config::read(cli(&["--config", missing.to_str().unwrap()])).unwrap_err();
+        assert!(
+            error.to_string().starts_with("failed to read config file"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unusable_settings() {
+        let default = Config::default;
+        assert!(default().validate().is_ok());
+
+        assert!(invalid(Config {
+            listen: Vec::new(),
+            ..default()
+        })
+        .contains("at least one listen"));
+        assert!(Config {
+            listen: Vec::new(),
+            tls_listen: vec!["127.0.0.1:3443".parse().unwrap()],
+            ..default()
+        }
+        .validate()
+        .is_ok());
+        assert_eq!(
+            invalid(Config {
+                tls_cert: Some("cert.pem".into()),
+                ..default()
+            }),
+            "tls_cert and tls_key must be set together"
+        );
+        assert!(invalid(Config {
+            idle_after: Duration::from_secs(60),
+            afk_timeout: Duration::from_secs(60),
+            ..default()
+        })
+        .starts_with("afk_secs"));
+        assert!(invalid(Config {
+            idle_after: Duration::ZERO,
+            ..default()
+        })
+        .starts_with("idle_secs"));
+        assert!(invalid(Config {
+            tick_rate: Duration::from_millis(20),
+            send_rate: Duration::from_millis(10),
+            ..default()
+        })
+        .starts_with("send_ms"));
+        assert!(invalid(Config {
+            max_message_size: 512,
+            ..default()
+        })
+        .starts_with("max_message_bytes"));
+        assert!(invalid(Config {
+            allowed_origins: vec!["duck.example".into()],
+            ..default()
+        })
+        .contains("needs a scheme"));
+        assert!(invalid(Config {
+            log_level: "game_server=loud".into(),
+            ..default()
+        })
+        .starts_with("invalid log_level"));
+        assert!(invalid(Config {
+            listed_dirs: vec!["maps".into()],
+            ..default()
+        })
+        .starts_with("listed dir"));
+        assert!(invalid(Config {
+            websocket_url: Some("https://duck.example".into()),
+            ..default()
+        })
+        .contains("ws:// or wss://"));
+        assert!(invalid(Config {
+            websocket_url: Some("wss://duck.example/ws".into()),
+            ..default()
+        })
+        .contains("without a path"));
+        assert!(invalid(Config {
+            controller_url: Some("duck.example/controls".into()),
+            ..default()
+        })
+        .starts_with("controller_url"));
+        assert!(invalid(Config {
+            ban_file: Some("/nonexistent/bans.txt".into()),
+            ..default()
+        })
+        .starts_with("ban file"));
+        assert!(invalid(Config {
+            admin_token: Some(String::new()),
+            ..default()
+        })
+        .starts_with("admin_token"));
+        assert!(invalid(Config {
+            server_name: "  ".into(),
+            ..default()
+        })
+        .starts_with("server_name"));
+        assert!(invalid(Config {
+            discovery_group: "10.0.0.1:3002".parse().unwrap(),
+            ..default()
+        })
+        .contains("not a multicast address"));
+    }
+
+    #[test]
+    fn reload_applies_hot_settings() {
+        let old = Config::default();
+        let new = Config {
+            max_players: 2,
+            tick_rate: Duration::from_millis(20),
+            motd: Some("Welcome".into()),
+            admin_token: Some("new-token".into()),
+            ..Config::default()
+        };
+        let reload = old.reloaded(new);
+
+        assert_eq!(
+            reload.applied,
+            [
+                "tick_rate: 16ms -> 20ms",
+                "max_players: 8 -> 2",
+                "motd: None -> Some(\"Welcome\")",
+                "admin_token: changed",
+            ]
+        );
+        assert!(reload.needs_restart.is_empty());
+        assert_eq!(reload.config.max_players, 2);
+        assert_eq!(reload.config.tick_rate, Duration::from_millis(20));
+        assert_eq!(reload.config.admin_token.as_deref(), Some("new-token"));
+    }
+
+    #[test]
+    fn reload_reports_restart_only_settings_and_keeps_them() {
+        let old = Config::default();
+        let new = Config {
+            listen: vec!["127.0.0.1:4000".parse().unwrap()],
+            tls_cert: Some("cert.pem".into()),
+            metrics_listen: Some("127.0.0.1:9100".parse().unwrap()),
+            log_format: LogFormat::Json,
+            announce: true,
+            discovery_interface: Ipv4Addr::new(192, 168, 1, 2),
+            server_name: "Renamed".into(),
+            ..Config::default()
+        };
+        let reload = old.reloaded(new);
+
+        assert_eq!(reload.applied, ["server_name: \"DuckGame\" -> \"Renamed\""]);
+        let restart: Vec<_> = reload
+            .needs_restart
+            .iter()
+            .map(|change| change.split(':').next().unwrap())
+            .collect();
+        assert_eq!(
+            restart,
+            [
+                "listen",
+                "tls_cert",
+                "metrics_listen",
+                "log_format",
+                "announce",
+                "discovery_interface"
+            ]
+        );
+        let config = reload.config;
+        assert_eq!(config.listen, old.listen);
+        assert_eq!(config.tls_cert, None);
+        assert_eq!(config.metrics_listen, old.metrics_listen);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert!(!config.announce);
+        assert_eq!(config.discovery_interface, old.discovery_interface);
+        assert_eq!(config.server_name, "Renamed");
+    }
+
+    #[test]
+    fn reloading_the_same_settings_changes_nothing() {
+        let reload = Config::default().reloaded(Config::default());
+        assert!(reload.applied.is_empty());
+        assert!(reload.needs_restart.is_empty());
+    }
+}
@@ -0,0 +1,718 @@
+//! The permessage-deflate websocket extension (RFC 7692), which tungstenite
+//! lacks.
+//!
+//! The server agrees to it during the handshake when the client offers it and
+//! `websocket_compression` is on. Messages the server sends are compressed
+//! one at a time by [`Deflate`], keeping the compression window between
+//! messages unless the client asked otherwise, since consecutive states repeat
+//! most of each other. Compressed messages from the client are inflated by
+//! [`InflateStream`] before tungstenite, which refuses frames with reserved
+//! bits set, ever sees them.
+
+use crate::metrics;
+use anyhow::{Context as _, Result};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Name of the extension in `Sec-WebSocket-Extensions` headers.
+const EXTENSION: &str = "permessage-deflate";
+
+/// The end of every compressed message, left off on the wire.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The deflate window the server uses, the largest allowed.
+const WINDOW_BITS: u8 = 15;
+
+/// Parameters agreed with a client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Agreement {
+    /// Compress each message on its own, as the client asked.
+    pub server_no_context_takeover: bool,
+    /// The client named the server's window size, which the response has to
+    /// confirm.
+    pub server_max_window_bits: bool,
+}
+
+impl Agreement {
+    /// The `Sec-WebSocket-Extensions` response header accepting the offer.
+    pub fn header(&self) -> String {
+        let mut header = EXTENSION.to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            header.push_str(&format!("; server_max_window_bits={WINDOW_BITS}"));
+        }
+        header
+    }
+}
+
+/// The first permessage-deflate offer in a client's
+/// `Sec-WebSocket-Extensions` headers the server can accept. Offers that
+/// limit the server's window are declined, since the deflate backend only
+/// compresses with the full window, as are offers with unknown, malformed or
+/// repeated parameters.
+pub fn negotiate<'a>(offers: impl IntoIterator<Item = &'a str>) -> Option<Agreement> {
+    offers
+        .into_iter()
+        .flat_map(|header| header.split(','))
+        .find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next() != Some(EXTENSION) {
+                return None;
+            }
+            let mut agreement = Agreement::default();
+            let mut seen = Vec::new();
+            let window_bits = |bits: &str| bits.parse().is_ok_and(|b: u8| (8..=15).contains(&b));
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                if seen.contains(&name) {
+                    return None;
+                }
+                seen.push(name);
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        agreement.server_no_context_takeover = true;
+                    }
+                    ("server_max_window_bits", Some(bits)) if bits.parse() == Ok(WINDOW_BITS) => {
+                        agreement.server_max_window_bits = true;
+                    }
+                    // Inflating with the full window reads any smaller one
+                    ("client_no_context_takeover", None) | ("client_max_window_bits", None) => {}
+                    ("client_max_window_bits", Some(bits)) if window_bits(bits) => {}
+                    _ => return None,
+                }
+            }
+            Some(agreement)
+        })
+}
+
+/// Compresses the messages sent on one connection.
+pub struct Deflate {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflate {
+    pub fn new(agreement: Agreement) -> Deflate {
+        Deflate {
+            // Small JSON messages compress nearly as well at the fastest level
+            compress: Compress::new(Compression::fast(), false),
+            no_context_takeover: agreement.server_no_context_takeover,
+        }
+    }
+
+    /// The payload of a compressed frame carrying `data`.
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let started = Instant::now();
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .context("failed to compress message")?;
+            consumed += (self.compress.total_in() - before) as usize;
+            // The flush is complete once it stops filling the buffer
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity().max(64));
+        }
+        if output.ends_with(&TAIL) {
+            output.truncate(output.len() - TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        record("sent", data.len(), output.len(), started);
+        Ok(output)
+    }
+}
+
+/// A client stream that inflates compressed messages into plain frames as
+/// they are read.
+///
+/// Everything up to the end of the handshake request passes through as it
+/// is. Frames after it are held back until the next read, by which time the
+/// handshake has settled whether the extension is in use; until it is, frames
+/// pass through untouched too. Inflated messages are written back as single
+/// masked frames with a zero key, which leaves the payload as it is.
+pub struct InflateStream<S> {
+    inner: S,
+    agreement: Arc<OnceLock<Agreement>>,
+    max_message_size: usize,
+    /// How much of the blank line ending the handshake request has been
+    /// seen; all of it once the request is through.
+    head_end: usize,
+    /// Bytes read from `inner` and not yet processed.
+    input: Vec<u8>,
+    /// Processed bytes, from `position` on not yet read.
+    output: Vec<u8>,
+    position: usize,
+    decompress: Decompress,
+    /// Opcode and payload so far of a compressed message arriving in
+    /// fragments.
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+/// The blank line ending a request head.
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASK: u8 = 0x80;
+const CONTINUATION: u8 = 0x0;
+
+impl<S> InflateStream<S> {
+    /// Wrap `inner`, inflating frames once `agreement` is set.
+    pub fn new(
+        inner: S,
+        agreement: Arc<OnceLock<Agreement>>,
+        max_message_size: usize,
+    ) -> InflateStream<S> {
+        InflateStream {
+            inner,
+            agreement,
+            max_message_size,
+            head_end: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+            position: 0,
+            decompress: Decompress::new(false),
+            fragments: None,
+        }
+    }
+
+    /// Move as much of `input` to `output` as is complete.
+    fn process(&mut self) -> io::Result<()> {
+        if self.head_end < HEAD_END.len() {
+            let mut n = 0;
+            while n < self.input.len() && self.head_end < HEAD_END.len() {
+                self.head_end = match self.input[n] {
+                    byte if byte == HEAD_END[self.head_end] => self.head_end + 1,
+                    b'\r' => 1,
+                    _ => 0,
+                };
+                n += 1;
+            }
+            self.output.extend(self.input.drain(..n));
+            return Ok(());
+        }
+        if self.agreement.get().is_none() {
+            self.output.append(&mut self.input);
+            return Ok(());
+        }
+
+        let mut start = 0;
+        while let Some(frame) = FrameHeader::parse(&self.input[start..], self.max_message_size)? {
+            let end = start + frame.len + frame.payload_len;
+            let payload = &self.input[start + frame.len..end];
+            let is_data = frame.opcode & 0x8 == 0;
+            let starts = is_data && frame.opcode != CONTINUATION;
+            if starts && self.fragments.is_some() {
+                return Err(invalid("message interrupted"));
+            }
+            let continues = frame.opcode == CONTINUATION && self.fragments.is_some();
+            // Unmasked client frames are left for tungstenite to refuse
+            if let (true, Some(mask)) = ((starts && frame.rsv1) || continues, frame.mask) {
+                let mut payload = payload.to_vec();
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+                let (opcode, mut message) =
+                    self.fragments.take().unwrap_or((frame.opcode, Vec::new()));
+                message.extend_from_slice(&payload);
+                if message.len() > self.max_message_size {
+                    return Err(invalid("compressed message too large"));
+                }
+                if frame.fin {
+                    let inflated = self.inflate(&message)?;
+                    write_frame(&mut self.output, opcode, &inflated);
+                } else {
+                    self.fragments = Some((opcode, message));
+                }
+            } else {
+                self.output.extend_from_slice(&self.input[start..end]);
+            }
+            start = end;
+        }
+        self.input.drain(..start);
+        Ok(())
+    }
+
+    fn inflate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let started = Instant::now();
+        let input = [data, &TAIL].concat();
+        let mut output = Vec::with_capacity(data.len() * 4 + 64);
+        let mut consumed = 0;
+        loop {
+            let before = self.decompress.total_in();
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| invalid("corrupt compressed message"))?;
+            consumed += (self.decompress.total_in() - before) as usize;
+            if output.len() > self.max_message_size {
+                return Err(invalid("inflated message too large"));
+            }
+            let done = consumed == input.len() && output.len() < output.capacity();
+            if done || status == Status::StreamEnd {
+                break;
+            }
+            if status == Status::BufError && output.len() < output.capacity() {
+                return Err(invalid("truncated compressed message"));
+            }
+            output.reserve(output.capacity());
+        }
+        record("received", output.len(), data.len(), started);
+        Ok(output)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for InflateStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.output.len() {
+                let remaining = &this.output[this.position..];
+                let n = remaining.len().min(buf.remaining());
+                buf.put_slice(&remaining[..n]);
+                this.position += n;
+                if this.position == this.output.len() {
+                    this.output.clear();
+                    this.position = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            this.process()?;
+            if !this.output.is_empty() {
+                continue;
+            }
+            let mut chunk = [0; 4096];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.input.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for InflateStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// The parts of a frame header the stream looks at.
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header itself.
+    len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    /// The header of the frame at the start of `buf`, if all of the frame
+    /// has arrived.
+    fn parse(buf: &[u8], max_size: usize) -> io::Result<Option<FrameHeader>> {
+        let [first, second, ..] = *buf else {
+            return Ok(None);
+        };
+        let (payload_len, mut len) = match second & 0x7f {
+            126 => match buf.get(2..4) {
+                Some(bytes) => (u64::from(u16::from_be_bytes([bytes[0], bytes[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            short => (u64::from(short), 2),
+        };
+        let mask = if second & MASK != 0 {
+            let Some(key) = buf.get(len..len + 4) else {
+                return Ok(None);
+            };
+            len += 4;
+            Some([key[0], key[1], key[2], key[3]])
+        } else {
+            None
+        };
+        // Control frames are short, and nothing else may be over the limit
+        let payload_len = match usize::try_from(payload_len) {
+            Ok(payload_len) if payload_len <= max_size => payload_len,
+            _ => return Err(invalid("frame too large")),
+        };
+        if buf.len() < len + payload_len {
+            return Ok(None);
+        }
+        Ok(Some(FrameHeader {
+            fin: first & FIN != 0,
+            rsv1: first & RSV1 != 0,
+            opcode: first & 0x0f,
+            mask,
+            len,
+            payload_len,
+        }))
+    }
+}
+
+/// Append a final, masked frame carrying `payload` as it is.
+fn write_frame(output: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    output.push(FIN | opcode);
+    match payload.len() {
+        len @ 0..=125 => output.push(MASK | len as u8),
+        len @ 126..=0xffff => {
+            output.push(MASK | 126);
+            output.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            output.push(MASK | 127);
+            output.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    output.extend_from_slice(&[0; 4]);
+    output.extend_from_slice(payload);
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn record(direction: &str, raw: usize, compressed: usize, started: Instant) {
+    metrics::DEFLATE_SECONDS
+        .with_label_values(&[direction])
+        .observe(started.elapsed().as_secs_f64());
+    metrics::DEFLATE_BYTES
+        .with_label_values(&[direction, "raw"])
+        .inc_by(raw as u64);
+    metrics::DEFLATE_BYTES
+        .with_label_values(&[direction, "compressed"])
+        .inc_by(compressed as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const HEAD: &[u8] = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+    const TEXT: u8 = 0x1;
+    const PING: u8 = 0x9;
+
+    /// A frame as a client sends it, masked with a non-zero key.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first];
+        match payload.len() {
+            len @ 0..=125 => frame.push(MASK | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(MASK | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(MASK | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Compress `data` the way a client does, without context takeover.
+    fn compressed(data: &[u8]) -> Vec<u8> {
+        Deflate::new(Agreement::default()).compress(data).unwrap()
+    }
+
+    /// What tungstenite reads after the handshake request when a client that
+    /// agreed to compression sends `frames`.
+    async fn read_through(frames: &[u8], max_message_size: usize) -> io::Result<Vec<u8>> {
+        let agreement = Arc::new(OnceLock::from(Agreement::default()));
+        let input = [HEAD, frames].concat();
+        let mut stream = InflateStream::new(&input[..], agreement, max_message_size);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await?;
+        assert!(output.starts_with(HEAD), "handshake request was altered");
+        Ok(output.split_off(HEAD.len()))
+    }
+
+    /// The first byte and unmasked payload of each frame in `bytes`.
+    fn frames(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while let Some(header) = FrameHeader::parse(bytes, usize::MAX).unwrap() {
+            let end = header.len + header.payload_len;
+            let mask = header.mask.unwrap_or_default();
+            let payload = bytes[header.len..end]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask[i % 4])
+                .collect();
+            frames.push((bytes[0], payload));
+            bytes = &bytes[end..];
+        }
+        assert!(bytes.is_empty(), "trailing partial frame");
+        frames
+    }
+
+    #[tokio::test]
+    async fn compressed_messages_round_trip() {
+        let first = br#"{"type":"state","data":{"a":{"x":0.5,"y":-0.25}}}"#;
+        let second = br#"{"type":"state","data":{"a":{"x":0.5,"y":-0.5}}}"#;
+        // The second message refers back into the first
+        let mut deflate = Deflate::new(Agreement::default());
+        let mut input = client_frame(FIN | RSV1 | TEXT, &deflate.compress(first).unwrap());
+        input.extend(client_frame(
+            FIN | RSV1 | TEXT,
+            &deflate.compress(second).unwrap(),
+        ));
+
+        let output = read_through(&input, 4096).await.unwrap();
+        assert_eq!(
+            frames(&output),
+            [(FIN | TEXT, first.to_vec()), (FIN | TEXT, second.to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn uncompressed_frames_pass_through() {
+        let input = client_frame(FIN | TEXT, b"plain");
+        assert_eq!(read_through(&input, 4096).await.unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn fragmented_message_is_inflated_whole() {
+        let message = "fragmented ".repeat(40);
+        let payload = compressed(message.as_bytes());
+        let (a, rest) = payload.split_at(payload.len() / 3);
+        let (b, c) = rest.split_at(rest.len() / 2);
+        let input = [
+            client_frame(RSV1 | TEXT, a),
+            client_frame(CONTINUATION, b),
+            client_frame(FIN | CONTINUATION, c),
+        ]
+        .concat();
+
+        let output = read_through(&input, 4096).await.unwrap();
+        assert_eq!(frames(&output), [(FIN | TEXT, message.into_bytes())]);
+    }
+
+    #[tokio::test]
+    async fn control_frame_between_fragments_passes_first() {
+        let message = b"interrupted by a ping, which arrives first";
+        let payload = compressed(message);
+        let (a, b) = payload.split_at(payload.len() / 2);
+        let input = [
+            client_frame(RSV1 | TEXT, a),
+            client_frame(FIN | PING, b"hi"),
+            client_frame(FIN | CONTINUATION, b),
+        ]
+        .concat();
+
+        let output = read_through(&input, 4096).await.unwrap();
+        assert_eq!(
+            frames(&output),
+            [(FIN | PING, b"hi".to_vec()), (FIN | TEXT, message.to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn new_message_inside_a_fragmented_one_is_refused() {
+        let payload = compressed(b"first half");
+        let input = [
+            client_frame(RSV1 | TEXT, &payload),
+            client_frame(FIN | TEXT, b"second"),
+        ]
+        .concat();
+        let error = read_through(&input, 4096).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversize_inflated_message_is_refused() {
+        // Compresses to a few bytes, far under the limit
+        let payload = compressed(&[b'a'; 10_000]);
+        assert!(payload.len() < 100);
+        let input = client_frame(FIN | RSV1 | TEXT, &payload);
+        let error = read_through(&input, 4096).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "inflated message too large");
+    }
+
+    #[tokio::test]
+    async fn corrupt_message_is_refused() {
+        let input = client_frame(FIN | RSV1 | TEXT, &[0xff; 16]);
+        let error = read_through(&input, 4096).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn frames_wait_until_extension_is_agreed() {
+        let agreement = Arc::new(OnceLock::new());
+        let frame = client_frame(FIN | RSV1 | TEXT, &compressed(b"early"));
+        let input = [HEAD, &frame].concat();
+        let mut stream = InflateStream::new(&input[..], agreement.clone(), 4096);
+        let mut head = vec![0; input.len()];
+        let n = stream.read(&mut head).await.unwrap();
+        assert_eq!(&head[..n], HEAD, "frames read along with the request");
+
+        agreement.set(Agreement::default()).unwrap();
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(frames(&output), [(FIN | TEXT, b"early".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn frames_pass_untouched_without_agreement() {
+        let frame = client_frame(FIN | RSV1 | TEXT, &compressed(b"refused"));
+        let input = [HEAD, &frame].concat();
+        let mut stream = InflateStream::new(&input[..], Arc::new(OnceLock::new()), 4096);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn parses_headers_of_every_length() {
+        for len in [0, 125, 126, 300, 0xffff, 0x10000, 70_000] {
+            let payload = vec![7; len];
+            let masked = client_frame(FIN | TEXT, &payload);
+            let header = FrameHeader::parse(&masked, usize::MAX).unwrap().unwrap();
+            let expected_len = match len {
+                0..=125 => 2,
+                126..=0xffff => 4,
+                _ => 10,
+            };
+            assert_eq!(header.len, expected_len + 4, "masked, {len} bytes");
+            assert_eq!(header.payload_len, len);
+            assert_eq!(header.mask, Some([0x12, 0x34, 0x56, 0x78]));
+            assert!(header.fin && !header.rsv1);
+            assert_eq!(header.opcode, TEXT);
+
+            let mut unmasked = client_frame(FIN | RSV1 | TEXT, &payload);
+            unmasked[1] &= !MASK;
+            unmasked.drain(expected_len..expected_len + 4);
+            let header = FrameHeader::parse(&unmasked, usize::MAX).unwrap().unwrap();
+            assert_eq!(header.len, expected_len, "unmasked, {len} bytes");
+            assert_eq!(header.payload_len, len);
+            assert_eq!(header.mask, None);
+            assert!(header.rsv1);
+
+            // Nothing until the whole frame is there
+            let partial = &masked[..masked.len() - 1];
+            assert!(FrameHeader::parse(partial, usize::MAX).unwrap().is_none());
+            assert!(FrameHeader::parse(&masked[..1], usize::MAX)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit() {
+        let frame = client_frame(FIN | TEXT, &[0; 300]);
+        assert!(FrameHeader::parse(&frame, 299).is_err());
+        // Refused from the header alone, before the payload arrives
+        assert!(FrameHeader::parse(&frame[..8], 299).is_err());
+    }
+
+    #[test]
+    fn written_frames_parse_back() {
+        for len in [0, 125, 126, 0xffff, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut frame = Vec::new();
+            write_frame(&mut frame, TEXT, &payload);
+            assert_eq!(frames(&frame), [(FIN | TEXT, payload)]);
+            let header = FrameHeader::parse(&frame, usize::MAX).unwrap().unwrap();
+            assert_eq!(header.mask, Some([0; 4]));
+        }
+    }
+
+    #[test]
+    fn negotiates_offers() {
+        let agreed = |offer: &str| negotiate([offer]);
+        let plain = Agreement::default();
+        assert_eq!(agreed("permessage-deflate"), Some(plain));
+        // What browsers send
+        assert_eq!(
+            agreed("permessage-deflate; client_max_window_bits"),
+            Some(plain)
+        );
+        assert_eq!(
+            agreed("permessage-deflate; client_max_window_bits=10; client_no_context_takeover"),
+            Some(plain)
+        );
+        let own = agreed("permessage-deflate; server_no_context_takeover").unwrap();
+        assert!(own.server_no_context_takeover);
+        assert_eq!(
+            own.header(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        let full = agreed(r#"permessage-deflate; server_max_window_bits="15""#).unwrap();
+        assert_eq!(
+            full.header(),
+            "permessage-deflate; server_max_window_bits=15"
+        );
+        assert_eq!(plain.header(), "permessage-deflate");
+    }
+
+    #[test]
+    fn declines_offers_it_cannot_honor() {
+        for offer in [
+            "",
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_max_window_bits=10",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; client_max_window_bits=wide",
+            "permessage-deflate; server_no_context_takeover=yes",
+            "permessage-deflate; unknown_parameter",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; client_max_window_bits; client_max_window_bits=15",
+        ] {
+            assert_eq!(negotiate([offer]), None, "{offer}");
+        }
+        assert_eq!(negotiate([]), None);
+    }
+
+    #[test]
+    fn takes_the_first_acceptable_offer() {
+        let offers = [
+            "permessage-deflate; server_max_window_bits=9, permessage-deflate; server_no_context_takeover",
+            "permessage-deflate",
+        ];
+        let agreement = negotiate(offers).unwrap();
+        assert!(agreement.server_no_context_takeover);
+    }
+}
@@ -0,0 +1,274 @@
+//! Finding game servers on the local network without typing addresses.
+//!
+//! An announcing server joins a multicast group and sends an `announce`
+//! message to it every few seconds, with its name, ports, rooms and player
+//! count. Anyone can also send a `discover` message to the group; every
+//! server answers it with an `announce` straight back to the sender, which is
+//! how `--discover` finds servers without waiting for the next beacon.
+//! Clients reach a server at the address its announcement came from.
+
+use crate::config::{Config, ConfigWatch};
+use crate::game_state::{Message, SharedPlayers};
+use crate::DEFAULT_ROOM;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time};
+use tracing::{debug, info, warn};
+
+/// How often a server announces itself unasked.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long `--discover` collects answers.
+const DISCOVER_WAIT: Duration = Duration::from_secs(2);
+
+/// Queries sent by `--discover`, spread over the wait, in case one is lost.
+const DISCOVER_QUERIES: u32 = 3;
+
+/// Largest announcement or query read.
+const MAX_DATAGRAM: usize = 2048;
+
+/// Announce the server on its discovery group until the process exits, and
+/// answer queries. Failing to join the group is logged, not fatal, since the
+/// game works without discovery.
+pub async fn announce(players_state: SharedPlayers, mut config: ConfigWatch) {
+    let current = config.borrow().clone();
+    let socket = match group_socket(current.discovery_group, current.discovery_interface) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(error = %format!("{e:#}"), "failed to join discovery group");
+            return;
+        }
+    };
+    info!(group = %current.discovery_group, name = %current.server_name, "announcing on local network");
+
+    let group = SocketAddr::V4(current.discovery_group);
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = [0; MAX_DATAGRAM];
+    // Set when a receive fails, so a socket that keeps failing is retried
+    // once per announcement instead of in a busy loop
+    let mut receive_failed = false;
+    loop {
+        let to = tokio::select! {
+            _ = interval.tick() => {
+                receive_failed = false;
+                group
+            }
+            received = socket.recv_from(&mut buf), if !receive_failed => match received {
+                Ok((len, from)) if is_query(&buf[..len]) => {
+                    debug!(%from, "discovery query");
+                    from
+                }
+                // Including our own announcements, looped back by the group
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!(error = %e, "discovery receive failed");
+                    receive_failed = true;
+                    continue;
+                }
+            },
+        };
+        let current = config.borrow_and_update().clone();
+        let players = players_state.lock().await.len();
+        let announcement = serde_json::to_vec(&announcement(&current, players))
+            .expect("announcement is serializable");
+        if let Err(e) = socket.send_to(&announcement, to).await {
+            debug!(%to, error = %e, "failed to send announcement");
+        }
+    }
+}
+
+/// Ask the discovery group which servers are there and print each one that
+/// answers.
+pub async fn query(config: &Config) -> Result<()> {
+    let found = discover(config, |from, data| println!("{}", describe(data, from))).await?;
+    if found == 0 {
+        println!("no servers found on {}", config.discovery_group);
+    }
+    Ok(())
+}
+
+/// Ask the discovery group which servers are there, calling `found` with the
+/// address and announcement of each one as it answers. Returns how many did.
+async fn discover(config: &Config, mut found: impl FnMut(SocketAddr, &Value)) -> Result<usize> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&config.discovery_interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    let query = serde_json::to_vec(&Message {
+        type_: "discover".to_string(),
+        data: json!({}),
+    })?;
+    let group = SocketAddr::V4(config.discovery_group);
+    let mut resend = time::interval(DISCOVER_WAIT / DISCOVER_QUERIES);
+    let deadline = time::sleep(DISCOVER_WAIT);
+    tokio::pin!(deadline);
+    let mut seen = HashSet::new();
+    let mut buf = [0; MAX_DATAGRAM];
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = resend.tick() => {
+                socket
+                    .send_to(&query, group)
+                    .await
+                    .with_context(|| format!("failed to query discovery group {group}"))?;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                let Ok(message) = serde_json::from_slice::<Message>(&buf[..len]) else {
+                    continue;
+                };
+                // Servers on one host answer from the same shared port
+                let ports = ["port", "tlsPort", "quicPort"].map(|p| message.data[p].to_string());
+                if message.type_ == "announce" && seen.insert((from, ports)) {
+                    found(from, &message.data);
+                }
+            }
+        }
+    }
+    Ok(seen.len())
+}
+
+/// A UDP socket bound to the group's port and joined to the group. Other
+/// servers and queries on the same host can share the port.
+fn group_socket(group: SocketAddrV4, interface: Ipv4Addr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())
+        .with_context(|| format!("failed to bind port {}", group.port()))?;
+    socket
+        .join_multicast_v4(group.ip(), &interface)
+        .with_context(|| format!("failed to join {}", group.ip()))?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn is_query(datagram: &[u8]) -> bool {
+    serde_json::from_slice::<Message>(datagram).is_ok_and(|m| m.type_ == "discover")
+}
+
+/// What a server tells the network about itself. Ports are null for
+/// transports it doesn't listen on.
+fn announcement(config: &Config, players: usize) -> Message {
+    let port = |addrs: &[SocketAddr]| addrs.first().map(SocketAddr::port);
+    Message {
+        type_: "announce".to_string(),
+        data: json!({
+            "name": config.server_name,
+            "port": port(&config.listen),
+            "tlsPort": port(&config.tls_listen),
+            "quicPort": port(&config.quic_listen),
+            "websocketUrl": config.websocket_url,
+            "rooms": [{ "name": DEFAULT_ROOM, "players": players }],
+            "players": players,
+            "maxPlayers": config.max_players,
+        }),
+    }
+}
+
+/// One line about an announced server, with the URL to connect to.
+fn describe(data: &Value, from: SocketAddr) -> String {
+    let host = from.ip();
+    let url = match (&data["websocketUrl"], &data["port"], &data["tlsPort"]) {
+        (Value::String(url), _, _) => url.clone(),
+        (_, Value::Number(port), _) => format!("ws://{host}:{port}"),
+        (_, _, Value::Number(port)) => format!("wss://{host}:{port}"),
+        _ => "no websocket".to_string(),
+    };
+    let rooms: Vec<_> = data["rooms"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|room| {
+            format!(
+                "{}({})",
+                room["name"].as_str().unwrap_or("?"),
+                room["players"]
+            )
+        })
+        .collect();
+    format!(
+        "{}  {url}  players {}/{}  rooms {}",
+        data["name"].as_str().unwrap_or("unnamed"),
+        data["players"],
+        data["maxPlayers"],
+        rooms.join(" "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::sync::{watch, Mutex};
+
+    /// Whether multicast sent to `group` on loopback comes back, which
+    /// needs a multicast route.
+    async fn loopback_multicast(group: SocketAddrV4) -> bool {
+        let Ok(socket) = group_socket(group, Ipv4Addr::LOCALHOST) else {
+            return false;
+        };
+        if socket.send_to(b"probe", group).await.is_err() {
+            return false;
+        }
+        let mut buf = [0; 16];
+        let received = time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf));
+        matches!(received.await, Ok(Ok((5, _))))
+    }
+
+    #[tokio::test]
+    async fn finds_an_announced_server_over_loopback() {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), port);
+        if !loopback_multicast(group).await {
+            eprintln!("skipping: no loopback multicast route for {group}");
+            return;
+        }
+        let config = Config {
+            server_name: "Test pond".to_string(),
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3456))],
+            quic_listen: vec![SocketAddr::from(([127, 0, 0, 1], 4433))],
+            discovery_group: group,
+            discovery_interface: Ipv4Addr::LOCALHOST,
+            ..Config::default()
+        };
+        let (_reload, watch) = watch::channel(Arc::new(config.clone()));
+        let players = Arc::new(Mutex::new(HashMap::new()));
+        let announcer = tokio::spawn(announce(players, watch));
+
+        let mut servers = Vec::new();
+        let found = discover(&config, |from, data| servers.push((from, data.clone())))
+            .await
+            .unwrap();
+        announcer.abort();
+
+        assert_eq!(found, 1, "{servers:?}");
+        let (from, data) = &servers[0];
+        assert_eq!(data["name"], "Test pond");
+        assert_eq!(data["port"], 3456);
+        assert_eq!(data["quicPort"], 4433);
+        assert_eq!(data["tlsPort"], Value::Null);
+        assert_eq!(
+            describe(data, *from),
+            "Test pond  ws://127.0.0.1:3456  players 0/8  rooms default(0)"
+        );
+    }
+}
@@ -1,12 +1,13 @@
+use crate::input::{Capability, ExtendedInput, InputSettings};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::{fmt, sync::Arc};
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, time::Instant};
 
 /// A shared map of player id to that player's game state.
 pub type SharedPlayers = Arc<Mutex<HashMap<String, GameState>>>;
 
-#[derive(Clone, Serialize, Deserialize, Default)]
+#[derive(Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Vector2 {
     pub x: f32,
     pub y: f32,
@@ -24,9 +25,42 @@ pub struct GameState {
     pub joystick: Vector2,
     #[serde(default)]
     pub buttons: Buttons,
+    /// Optional inputs declared by the controller at registration.
+    #[serde(flatten)]
+    pub extended: ExtendedInput,
+    /// Duck color assigned by the server at registration.
+    #[serde(default)]
+    pub color: String,
+    /// Score and health as last reported by a viewer.
+    #[serde(default)]
+    pub score: i32,
+    #[serde(default)]
+    pub health: i32,
+    /// How this player's raw joystick input is processed. Server-side only.
+    #[serde(skip)]
+    pub settings: InputSettings,
+    /// Optional inputs this player's controller declared. Server-side only.
+    #[serde(skip)]
+    pub capabilities: Vec<Capability>,
+    /// Set once the player has sent no meaningful input for a while.
+    #[serde(default)]
+    pub idle: bool,
+    /// When the player last sent meaningful input. Server-side only.
+    #[serde(skip)]
+    pub last_input: Activity,
 }
 
-#[derive(Clone, Serialize, Deserialize, Default)]
+/// A point in time that defaults to now, so new players start out active.
+#[derive(Clone, Copy)]
+pub struct Activity(pub Instant);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity(Instant::now())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct Buttons {
     pub a: bool,
     pub b: bool,
@@ -48,16 +82,59 @@ impl fmt::Debug for GameState {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "GameState {{ joystick: {:?}, buttons: {:?} }}",
-            self.joystick, self.buttons
+            "GameState {{ joystick: {:?}, buttons: {:?}, color: {}, score: {}, health: {} }}",
+            self.joystick, self.buttons, self.color, self.score, self.health
         )
     }
 }
 
+/// Smallest joystick movement that counts as the player being active, so
+/// thumbstick drift doesn't keep an abandoned controller alive.
+const MEANINGFUL_STICK_MOVE: f32 = 0.05;
+
+/// Duck colors handed out to players in registration order.
+pub const DUCK_COLORS: [&str; 8] = [
+    "#f5d142", "#ffffff", "#e8743b", "#5dade2", "#58d68d", "#af7ac5", "#ec7063", "#7f8c8d",
+];
+
 impl GameState {
     /// Create a default game state for a new player.
     pub fn new_default() -> GameState {
-        GameState::default()
+        GameState {
+            health: 100,
+            ..GameState::default()
+        }
+    }
+
+    /// Apply a controller's action through this player's input settings. Any
+    /// meaningful change in input marks the player active again.
+    pub fn apply_action(&mut self, action: GameState) {
+        let joystick = self.settings.apply(action.joystick);
+        let moved = (joystick.x - self.joystick.x).hypot(joystick.y - self.joystick.y);
+        let before = (self.buttons.clone(), self.extended.clone());
+
+        self.joystick = joystick;
+        self.buttons = action.buttons;
+        self.extended.update(action.extended, &self.settings);
+
+        if moved > MEANINGFUL_STICK_MOVE || before != (self.buttons.clone(), self.extended.clone())
+        {
+            self.last_input = Activity::default();
+            self.idle = false;
+        }
+    }
+
+    /// Create a game state for a new player with the first duck color not
+    /// already taken by someone in `players`.
+    pub fn new_with_color(players: &HashMap<String, GameState>) -> GameState {
+        let color = DUCK_COLORS
+            .iter()
+            .find(|c| !players.values().any(|p| p.color == **c))
+            .unwrap_or(&DUCK_COLORS[players.len() % DUCK_COLORS.len()]);
+        GameState {
+            color: color.to_string(),
+            ..GameState::new_default()
+        }
     }
 }
 
@@ -66,6 +143,103 @@ pub fn new_players() -> SharedPlayers {
     Arc::new(Mutex::new(HashMap::new()))
 }
 
+/// What a connection registered as. Connections are treated as viewers until
+/// they register as something else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    #[default]
+    Viewer,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Player => write!(f, "player"),
+            Role::Viewer => write!(f, "viewer"),
+        }
+    }
+}
+
+/// What happens to a player that stays idle past the AFK timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AfkAction {
+    /// Drop the player's duck but keep the connection open as a viewer.
+    Spectate,
+    /// Drop the player's duck and close the connection.
+    Remove,
+}
+
+/// Feedback pushed to a single controller.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Feedback {
+    /// Vibration pattern in milliseconds, alternating on/off as in
+    /// `navigator.vibrate`.
+    Vibrate { pattern: Vec<u32> },
+    /// The player's duck was hit, optionally by another player.
+    Hit {
+        #[serde(default)]
+        by: Option<String>,
+    },
+    /// Current score and health.
+    Status { score: i32, health: i32 },
+    /// The duck color this controller drives.
+    Assigned { player_id: String, color: String },
+    /// The input settings now in effect for this controller.
+    Settings(InputSettings),
+    /// The player has sent no meaningful input for a while.
+    Idle,
+    /// The player was idle too long and was moved to spectators or removed.
+    Afk { action: AfkAction },
+    /// The optional inputs the server accepted from the controller's
+    /// registration.
+    Capabilities { accepted: Vec<Capability> },
+}
+
+/// Why a registration was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)] // Named for their wire form, e.g. `players_full`
+pub enum RejectReason {
+    PlayersFull,
+    ViewersFull,
+    /// The server is full and so is the queue of players waiting for a slot.
+    QueueFull,
+}
+
+impl RejectReason {
+    /// Label used for the rejected-messages metric.
+    pub fn label(self) -> &'static str {
+        match self {
+            RejectReason::PlayersFull => "players_full",
+            RejectReason::ViewersFull => "viewers_full",
+            RejectReason::QueueFull => "queue_full",
+        }
+    }
+}
+
+/// Body of a `rejected` message: why a registration was turned away and the
+/// counts behind the decision.
+#[derive(Clone, Debug, Serialize)]
+pub struct Rejection {
+    pub reason: RejectReason,
+    pub players: usize,
+    pub max_players: usize,
+    pub viewers: usize,
+    pub max_viewers: usize,
+}
+
+/// A viewer's request to deliver `feedback` to the controller of `player`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeedbackRequest {
+    pub player: String,
+    #[serde(flatten)]
+    pub feedback: Feedback,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Message {
     #[serde(rename = "type")]
@@ -0,0 +1,87 @@
+//! Liveness and readiness state reported by `/healthz` and `/readyz`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Milliseconds since `STARTED` at which the ticker last completed a tick.
+static LAST_TICK_MS: AtomicU64 = AtomicU64::new(0);
+static TICKED: AtomicBool = AtomicBool::new(false);
+static MAPS_LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Record that the ticker completed a tick.
+pub fn record_tick() {
+    LAST_TICK_MS.store(STARTED.elapsed().as_millis() as u64, Ordering::Relaxed);
+    TICKED.store(true, Ordering::Relaxed);
+}
+
+pub fn set_maps_loaded(loaded: bool) {
+    MAPS_LOADED.store(loaded, Ordering::Relaxed);
+}
+
+/// Whether the server can take players: the ticker has ticked recently and
+/// the maps loaded. The error names what is wrong.
+pub fn readiness(tick_rate: Duration) -> Result<(), String> {
+    let since_tick = TICKED.load(Ordering::Relaxed).then(|| {
+        STARTED
+            .elapsed()
+            .saturating_sub(Duration::from_millis(LAST_TICK_MS.load(Ordering::Relaxed)))
+    });
+    check(MAPS_LOADED.load(Ordering::Relaxed), since_tick, tick_rate)
+}
+
+/// Readiness given whether the maps loaded and how long ago the ticker last
+/// ticked, if it has yet.
+fn check(
+    maps_loaded: bool,
+    since_tick: Option<Duration>,
+    tick_rate: Duration,
+) -> Result<(), String> {
+    if !maps_loaded {
+        return Err("maps not loaded".to_string());
+    }
+    let Some(since_tick) = since_tick else {
+        return Err("ticker has not started".to_string());
+    };
+    // Allow a generous margin so a single slow tick doesn't flap readiness
+    let stale_after = (tick_rate * 10).max(Duration::from_secs(1));
+    if since_tick > stale_after {
+        return Err(format!("ticker stalled for {since_tick:?}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(16);
+
+    #[test]
+    fn ready_once_maps_load_and_the_ticker_runs() {
+        assert_eq!(
+            check(false, Some(Duration::ZERO), TICK),
+            Err("maps not loaded".to_string())
+        );
+        assert_eq!(
+            check(true, None, TICK),
+            Err("ticker has not started".to_string())
+        );
+        assert_eq!(check(true, Some(Duration::from_millis(5)), TICK), Ok(()));
+    }
+
+    #[test]
+    fn stale_ticker_is_not_ready() {
+        // At least a second of grace, however fast the tick
+        assert_eq!(check(true, Some(Duration::from_millis(900)), TICK), Ok(()));
+        let stalled = check(true, Some(Duration::from_millis(1500)), TICK).unwrap_err();
+        assert!(stalled.starts_with("ticker stalled"), "{stalled}");
+        // Slow ticks get ten of them
+        let slow = Duration::from_millis(500);
+        assert_eq!(check(true, Some(Duration::from_secs(4)), slow), Ok(()));
+        assert!(check(true, Some(Duration::from_secs(6)), slow).is_err());
+    }
+}
@@ -0,0 +1,610 @@
+//! A minimal HTTP/1.1 responder for the server's plain HTTP endpoints. Each
+//! connection carries a single request and is closed after the response.
+
+use crate::config::{Config, ConfigWatch};
+use crate::health;
+use crate::join;
+use crate::metrics;
+use crate::static_files;
+use crate::webtransport;
+use anyhow::{bail, Context, Result};
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time,
+};
+use tracing::{debug, warn};
+
+/// Largest request head we are willing to buffer.
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Largest request body we are willing to buffer.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A client connection, plain TCP or TLS.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The request path without its query string.
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// The percent-decoded value of the query parameter `name`.
+    pub fn query(&self, name: &str) -> Option<String> {
+        let (_, query) = self.path.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| {
+                percent_decode_str(&value.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+    }
+
+    /// Whether this is a websocket upgrade handshake.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: Cow<'static, str>,
+    /// Headers besides the content type and length.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+    /// Send the headers for `body` but not the body itself, as for `HEAD`.
+    pub head_only: bool,
+}
+
+impl Response {
+    pub fn new(
+        status: u16,
+        content_type: impl Into<Cow<'static, str>>,
+        body: impl Into<Vec<u8>>,
+    ) -> Response {
+        Response {
+            status,
+            content_type: content_type.into(),
+            headers: Vec::new(),
+            body: body.into(),
+            head_only: false,
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Response {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn json(status: u16, body: &impl serde::Serialize) -> Response {
+        Response::new(
+            status,
+            "application/json",
+            serde_json::to_vec(body).expect("response is not serializable"),
+        )
+    }
+
+    pub fn not_found() -> Response {
+        Response::text(404, "not found\n")
+    }
+
+    /// The status line and headers, up to and including the blank line
+    /// before the body.
+    pub fn head(&self) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Read and parse a request head from `stream`. Also returns every byte read,
+/// so the request can be replayed through a [`Rewind`] if it turns out to be
+/// a websocket handshake.
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> Result<(Request, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before request was complete");
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            bail!("request head too large");
+        }
+    };
+    let head = std::str::from_utf8(&buffer[..head_end]).context("request is not UTF-8")?;
+    let mut request = parse_head(head)?;
+
+    let body_start = head_end + 4;
+    let content_length = match request.header("content-length") {
+        Some(length) => length.parse::<usize>().context("invalid content-length")?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        bail!("request body too large");
+    }
+    while buffer.len() < body_start + content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before request body was complete");
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+    request.body = buffer[body_start..body_start + content_length].to_vec();
+    Ok((request, buffer))
+}
+
+/// Parse a request line and the header lines following it. The body is left
+/// empty.
+pub fn parse_head(head: &str) -> Result<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        bail!("malformed request line");
+    };
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Write `response` to `stream` and close the connection.
+pub async fn write_response(
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: Response,
+) -> Result<()> {
+    let response = response.with_header("Connection", "close");
+    stream.write_all(response.head().as_bytes()).await?;
+    if !response.head_only {
+        stream.write_all(&response.body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// A stream that replays bytes already read from it before reading further.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Rewind<S> {
+        Rewind {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            self.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Answer a plain HTTP request made to the game port. `secure` says whether
+/// it arrived over TLS.
+pub async fn game_port_response(request: &Request, config: &Config, secure: bool) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::text(405, "method not allowed\n");
+    }
+    let mut response = match request.route() {
+        "/healthz" => Response::text(200, "ok\n"),
+        "/readyz" => match health::readiness(config.tick_rate) {
+            Ok(()) => Response::text(200, "ready\n"),
+            Err(reason) => Response::text(503, format!("not ready: {reason}\n")),
+        },
+        "/config.json" => client_config(request, config, secure),
+        "/join" | "/join.svg" | "/join.png" | "/join.txt" => join::respond(request, config, secure),
+        _ => match &config.static_dir {
+            Some(dir) => static_files::serve(dir, &config.listed_dirs, request).await,
+            None => Response::not_found(),
+        },
+    };
+    response.head_only = request.method == "HEAD";
+    response
+}
+
+/// Settings the web frontend needs at startup. Unless configured, the
+/// websocket URL points back at the host and port the page was loaded from.
+/// With a QUIC endpoint, controllers are also told where to open a
+/// WebTransport session on the same host.
+fn client_config(request: &Request, config: &Config, secure: bool) -> Response {
+    let websocket_url = match (&config.websocket_url, request.header("host")) {
+        (Some(url), _) => url.clone(),
+        (None, Some(host)) => format!("{}://{host}", if secure { "wss" } else { "ws" }),
+        (None, None) => return Response::text(400, "missing host header\n"),
+    };
+    let mut body = serde_json::json!({ "websocketUrl": websocket_url });
+    if let (Some(quic), Some(host)) = (config.quic_listen.first(), request.header("host")) {
+        body["webtransportUrl"] = format!(
+            "https://{}:{}{}",
+            host_name(host),
+            quic.port(),
+            webtransport::PATH
+        )
+        .into();
+    }
+    Response::json(200, &body).with_header("Cache-Control", "no-cache")
+}
+
+/// The host part of a `Host` header, without the port.
+pub fn host_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // A bare IPv6 address has colons but no port
+        Some((name, port))
+            if !port.contains(']') && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name
+        }
+        _ => host,
+    }
+}
+
+/// Read one request from `stream`, answer it with `handler` and close.
+/// Clients get `timeout` to send the request.
+pub async fn respond<F, Fut>(mut stream: TcpStream, addr: SocketAddr, timeout: Duration, handler: F)
+where
+    F: FnOnce(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let response = match time::timeout(timeout, read_request(&mut stream)).await {
+        Ok(Ok((request, _))) => {
+            debug!(%addr, method = %request.method, path = %request.path, "http request");
+            handler(request).await
+        }
+        Ok(Err(e)) => {
+            debug!(%addr, error = %e, "bad http request");
+            Response::text(400, "bad request\n")
+        }
+        Err(_) => {
+            debug!(%addr, "timed out reading request");
+            metrics::reject_connection("handshake_timeout");
+            Response::text(408, "request timeout\n")
+        }
+    };
+    if let Err(e) = write_response(&mut stream, response).await {
+        warn!(%addr, error = %e, "failed to write http response");
+    }
+}
+
+/// Serve the Prometheus `/metrics` endpoint on its own listener.
+pub async fn serve_metrics(listener: TcpListener, config: ConfigWatch) {
+    while let Ok((stream, addr)) = listener.accept().await {
+        let timeout = config.borrow().handshake_timeout;
+        tokio::spawn(respond(stream, addr, timeout, metrics_response));
+    }
+}
+
+async fn metrics_response(request: Request) -> Response {
+    match request.route() {
+        "/metrics" => {
+            crate::session::update_client_gauges().await;
+            Response::new(
+                200,
+                "text/plain; version=0.0.4; charset=utf-8",
+                metrics::render(),
+            )
+        }
+        _ => Response::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+
+    /// Start a metrics endpoint that gives clients `timeout` to send their
+    /// request, returning its address.
+    async fn metrics_endpoint(timeout: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            handshake_timeout: timeout,
+            ..Config::default()
+        };
+        let (reload, config) = watch::channel(std::sync::Arc::new(config));
+        tokio::spawn(async move {
+            let _reload = reload;
+            serve_metrics(listener, config).await;
+        });
+        addr
+    }
+
+    /// Send `request` to `addr` and read the whole response.
+    async fn fetch(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_serves_metrics_with_or_without_a_query() {
+        metrics::TICK_SECONDS.observe(0.003);
+        metrics::reject("test_reason");
+        let addr = metrics_endpoint(Duration::from_secs(5)).await;
+
+        for path in ["/metrics", "/metrics?x=1"] {
+            let response = fetch(addr, &format!("GET {path} HTTP/1.1\r\n\r\n")).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(response.contains("text/plain; version=0.0.4"));
+            assert!(response.contains("duckgame_tick_duration_seconds_bucket{le=\"0.004\"}"));
+            assert!(response.contains("duckgame_messages_rejected_total{reason=\"test_reason\"}"));
+        }
+        let response = fetch(addr, "GET /other HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_times_out_stalled_requests() {
+        let addr = metrics_endpoint(Duration::from_millis(100)).await;
+        let response = time::timeout(Duration::from_secs(2), fetch(addr, "GET /metr"))
+            .await
+            .expect("stalled request was never answered");
+        assert!(response.starts_with("HTTP/1.1 408"), "{response}");
+    }
+
+    /// Parse `raw` as a request arriving on a connection.
+    async fn read(raw: &[u8]) -> Result<(Request, Vec<u8>)> {
+        let mut stream = raw;
+        read_request(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn reads_a_request_head() {
+        let raw =
+            b"GET /join?room=pond&x=a+b%21 HTTP/1.1\r\nHost: duck.example:3001\r\nX-Empty:\r\n\r\n";
+        let (request, buffered) = read(raw).await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.route(), "/join");
+        assert_eq!(request.query("room").as_deref(), Some("pond"));
+        assert_eq!(request.query("x").as_deref(), Some("a b!"));
+        assert_eq!(request.query("missing"), None);
+        assert_eq!(request.header("HOST"), Some("duck.example:3001"));
+        assert_eq!(request.header("x-empty"), Some(""));
+        assert!(request.body.is_empty());
+        assert_eq!(buffered, raw);
+    }
+
+    #[tokio::test]
+    async fn reads_heads_longer_than_one_read() {
+        let padding = "x".repeat(4000);
+        let raw = format!("GET / HTTP/1.1\r\nX-Padding: {padding}\r\nUpgrade: WebSocket\r\n\r\n");
+        let (request, _) = read(raw.as_bytes()).await.unwrap();
+        assert_eq!(request.header("x-padding"), Some(padding.as_str()));
+        assert!(request.is_websocket_upgrade());
+    }
+
+    #[tokio::test]
+    async fn refuses_incomplete_and_oversized_heads() {
+        let error = read(b"GET / HTTP/1.1\r\nHost: x\r\n").await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "connection closed before request was complete"
+        );
+
+        let huge = format!("GET / HTTP/1.1\r\nX-Padding: {}", "x".repeat(MAX_HEAD_SIZE));
+        let error = read(huge.as_bytes()).await.unwrap_err();
+        assert_eq!(error.to_string(), "request head too large");
+
+        assert!(read(b"GET\r\n\r\n").await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\xff: x\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_bodies() {
+        let raw = b"POST /admin/kick HTTP/1.1\r\ncontent-LENGTH: 5\r\n\r\nhello";
+        let (request, _) = read(raw).await.unwrap();
+        assert_eq!(request.body, b"hello");
+
+        // Bytes past the body are buffered for whoever reads the stream next
+        let (request, buffered) = read(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi there")
+            .await
+            .unwrap();
+        assert_eq!(request.body, b"hi");
+        assert!(buffered.ends_with(b"hi there"));
+
+        let short = read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello").await;
+        assert_eq!(
+            short.unwrap_err().to_string(),
+            "connection closed before request body was complete"
+        );
+        let invalid = read(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").await;
+        assert_eq!(invalid.unwrap_err().to_string(), "invalid content-length");
+        let large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let large = read(large.as_bytes()).await;
+        assert_eq!(large.unwrap_err().to_string(), "request body too large");
+    }
+
+    #[test]
+    fn host_name_strips_the_port() {
+        assert_eq!(host_name("duck.example:3001"), "duck.example");
+        assert_eq!(host_name("duck.example"), "duck.example");
+        assert_eq!(host_name("10.0.0.2:80"), "10.0.0.2");
+        assert_eq!(host_name("[::1]:3001"), "[::1]");
+        assert_eq!(host_name("[fe80::1%25eth0]:3001"), "[fe80::1%25eth0]");
+        assert_eq!(host_name("[::1]"), "[::1]");
+        assert_eq!(host_name("::1"), "::1");
+    }
+
+    #[tokio::test]
+    async fn rewind_replays_the_upgrade_request_before_the_stream() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let upgrade = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+        client.write_all(upgrade).await.unwrap();
+        client.write_all(b"first frame").await.unwrap();
+        let (request, buffered) = read_request(&mut server).await.unwrap();
+        assert!(request.is_websocket_upgrade());
+
+        let mut rewound = Rewind::new(buffered, server);
+        client.write_all(b", second frame").await.unwrap();
+        drop(client);
+        let mut replayed = Vec::new();
+        rewound.read_to_end(&mut replayed).await.unwrap();
+        let mut expected = upgrade.to_vec();
+        expected.extend_from_slice(b"first frame, second frame");
+        assert_eq!(replayed, expected);
+    }
+
+    #[tokio::test]
+    async fn rewind_writes_through_to_the_stream() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut rewound = Rewind::new(b"prefix".to_vec(), server);
+        rewound.write_all(b"reply").await.unwrap();
+        rewound.shutdown().await.unwrap();
+        let mut written = Vec::new();
+        client.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"reply");
+    }
+
+    #[tokio::test]
+    async fn writes_responses_with_length_and_no_body_for_head() {
+        let mut written = Vec::new();
+        let response = Response::text(200, "hello\n").with_header("ETag", "\"1\"");
+        write_response(&mut written, response).await.unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 6\r\n\
+             ETag: \"1\"\r\nConnection: close\r\n\r\nhello\n"
+        );
+
+        let mut written = Vec::new();
+        let mut response = Response::text(404, "not found\n");
+        response.head_only = true;
+        write_response(&mut written, response).await.unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("Content-Length: 10\r\n"));
+        assert!(written.ends_with("\r\n\r\n"), "{written}");
+    }
+
+    #[tokio::test]
+    async fn readyz_reports_why_the_server_is_not_ready() {
+        let config = Config::default();
+        let readyz = |method: &str| parse_head(&format!("{method} /readyz HTTP/1.1")).unwrap();
+
+        health::set_maps_loaded(false);
+        let response = game_port_response(&readyz("GET"), &config, false).await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.body, b"not ready: maps not loaded\n");
+
+        health::set_maps_loaded(true);
+        health::record_tick();
+        let response = game_port_response(&readyz("HEAD"), &config, false).await;
+        assert_eq!((response.status, response.head_only), (200, true));
+
+        let response = game_port_response(&readyz("POST"), &config, false).await;
+        assert_eq!(response.status, 405);
+    }
+}
@@ -0,0 +1,382 @@
+//! Controller input beyond the raw joystick: per-player stick settings
+//! (deadzones, response curve, inversion) applied before positions are
+//! stored, and the optional capabilities a controller can declare at
+//! registration with the extra inputs they carry.
+
+use crate::game_state::Vector2;
+use serde::{Deserialize, Serialize};
+
+/// Per-player joystick processing, set by the controller with a `settings`
+/// message and kept for as long as the player stays registered.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    /// Stick magnitudes at or below this are treated as centered.
+    pub deadzone: f32,
+    /// Stick magnitudes within this distance of the edge are treated as full.
+    pub outer_deadzone: f32,
+    /// Response curve applied after the deadzones; 1.0 is linear.
+    pub exponent: f32,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        InputSettings {
+            deadzone: 0.0,
+            outer_deadzone: 0.0,
+            exponent: 1.0,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+impl InputSettings {
+    /// Check that the settings describe a usable mapping.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..1.0).contains(&self.deadzone) {
+            return Err(format!("deadzone {} must be in [0, 1)", self.deadzone));
+        }
+        if !(0.0..1.0).contains(&self.outer_deadzone) {
+            return Err(format!(
+                "outer_deadzone {} must be in [0, 1)",
+                self.outer_deadzone
+            ));
+        }
+        if self.deadzone + self.outer_deadzone >= 1.0 {
+            return Err("deadzone and outer_deadzone leave no usable range".to_string());
+        }
+        if !(0.1..=10.0).contains(&self.exponent) {
+            return Err(format!("exponent {} must be in [0.1, 10]", self.exponent));
+        }
+        Ok(())
+    }
+
+    /// Map a raw stick position through the deadzones, response curve and
+    /// axis inversion. Default settings leave the input untouched.
+    pub fn apply(&self, raw: Vector2) -> Vector2 {
+        if *self == InputSettings::default() {
+            return raw;
+        }
+
+        let x = if self.invert_x { -raw.x } else { raw.x };
+        let y = if self.invert_y { -raw.y } else { raw.y };
+        let magnitude = (x * x + y * y).sqrt();
+        if !magnitude.is_finite() || magnitude <= self.deadzone {
+            return Vector2::default();
+        }
+
+        // Rescale the live band between the deadzones to [0, 1], then curve it.
+        let live = 1.0 - self.outer_deadzone - self.deadzone;
+        let scaled = ((magnitude.min(1.0) - self.deadzone) / live).clamp(0.0, 1.0);
+        let curved = scaled.powf(self.exponent);
+        Vector2 {
+            x: x / magnitude * curved,
+            y: y / magnitude * curved,
+        }
+    }
+}
+
+/// Optional inputs a controller can declare at registration, beyond the
+/// joystick and `a/b/x/y` buttons every controller sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    RightStick,
+    Triggers,
+    Dpad,
+    Shoulders,
+    Tilt,
+}
+
+impl Capability {
+    /// Keep the capabilities this server understands, dropping duplicates and
+    /// unknown names so newer controllers can still register.
+    pub fn negotiate(requested: &[String]) -> Vec<Capability> {
+        let mut accepted = Vec::new();
+        for name in requested {
+            if let Ok(capability) =
+                serde_json::from_value::<Capability>(serde_json::Value::String(name.clone()))
+            {
+                if !accepted.contains(&capability) {
+                    accepted.push(capability);
+                }
+            }
+        }
+        accepted
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Triggers {
+    #[serde(default)]
+    pub left: f32,
+    #[serde(default)]
+    pub right: f32,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Dpad {
+    #[serde(default)]
+    pub up: bool,
+    #[serde(default)]
+    pub down: bool,
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Shoulders {
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+}
+
+/// Device tilt from the accelerometer, normalized to [-1, 1] per axis.
+#[derive(Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Vector3 {
+    #[serde(default)]
+    pub x: f32,
+    #[serde(default)]
+    pub y: f32,
+    #[serde(default)]
+    pub z: f32,
+}
+
+/// Inputs beyond the base joystick and buttons. Each is only present for
+/// controllers that declared the matching capability, and is left out of
+/// broadcasts otherwise so older viewers see the same shape as before.
+#[derive(Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct ExtendedInput {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right_stick: Option<Vector2>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub triggers: Option<Triggers>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dpad: Option<Dpad>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shoulders: Option<Shoulders>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tilt: Option<Vector3>,
+}
+
+impl ExtendedInput {
+    /// Neutral values for every declared capability.
+    pub fn for_capabilities(capabilities: &[Capability]) -> ExtendedInput {
+        let has = |c| capabilities.contains(&c);
+        ExtendedInput {
+            right_stick: has(Capability::RightStick).then(Vector2::default),
+            triggers: has(Capability::Triggers).then(Triggers::default),
+            dpad: has(Capability::Dpad).then(Dpad::default),
+            shoulders: has(Capability::Shoulders).then(Shoulders::default),
+            tilt: has(Capability::Tilt).then(Vector3::default),
+        }
+    }
+
+    /// Take the inputs from `action` that this controller declared; anything
+    /// it didn't declare is ignored.
+    pub fn update(&mut self, action: ExtendedInput, settings: &InputSettings) {
+        if let (Some(stick), Some(new)) = (&mut self.right_stick, action.right_stick) {
+            *stick = settings.apply(new);
+        }
+        if let (Some(triggers), Some(new)) = (&mut self.triggers, action.triggers) {
+            *triggers = new;
+        }
+        if let (Some(dpad), Some(new)) = (&mut self.dpad, action.dpad) {
+            *dpad = new;
+        }
+        if let (Some(shoulders), Some(new)) = (&mut self.shoulders, action.shoulders) {
+            *shoulders = new;
+        }
+        if let (Some(tilt), Some(new)) = (&mut self.tilt, action.tilt) {
+            *tilt = new;
+        }
+    }
+
+    /// Clamp analog inputs to their valid ranges.
+    pub fn clamp(&mut self) {
+        if let Some(stick) = &mut self.right_stick {
+            stick.x = stick.x.clamp(-1.0, 1.0);
+            stick.y = stick.y.clamp(-1.0, 1.0);
+        }
+        if let Some(triggers) = &mut self.triggers {
+            triggers.left = triggers.left.clamp(0.0, 1.0);
+            triggers.right = triggers.right.clamp(0.0, 1.0);
+        }
+        if let Some(tilt) = &mut self.tilt {
+            tilt.x = tilt.x.clamp(-1.0, 1.0);
+            tilt.y = tilt.y.clamp(-1.0, 1.0);
+            tilt.z = tilt.z.clamp(-1.0, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stick(x: f32, y: f32) -> Vector2 {
+        Vector2 { x, y }
+    }
+
+    fn assert_near(actual: Vector2, x: f32, y: f32) {
+        assert!(
+            (actual.x - x).abs() < 1e-5 && (actual.y - y).abs() < 1e-5,
+            "expected ({x}, {y}), got {actual:?}"
+        );
+    }
+
+    #[test]
+    fn default_settings_leave_input_untouched() {
+        let settings = InputSettings::default();
+        assert_eq!(settings.apply(stick(0.03, -0.7)), stick(0.03, -0.7));
+    }
+
+    #[test]
+    fn inputs_inside_the_deadzone_are_centered() {
+        let settings = InputSettings {
+            deadzone: 0.2,
+            ..InputSettings::default()
+        };
+        assert_eq!(settings.apply(stick(0.1, 0.1)), Vector2::default());
+        assert_eq!(settings.apply(stick(0.0, -0.2)), Vector2::default());
+        assert_eq!(settings.apply(stick(f32::NAN, 0.5)), Vector2::default());
+    }
+
+    #[test]
+    fn live_band_is_rescaled_between_the_deadzones() {
+        let settings = InputSettings {
+            deadzone: 0.2,
+            outer_deadzone: 0.1,
+            ..InputSettings::default()
+        };
+        // Just past the deadzone starts near zero rather than jumping to 0.2.
+        let edge = settings.apply(stick(0.2001, 0.0));
+        assert!(edge.x > 0.0 && edge.x < 0.001, "{edge:?}");
+        assert_near(settings.apply(stick(0.55, 0.0)), 0.5, 0.0);
+        // The outer deadzone and beyond count as full deflection.
+        assert_near(settings.apply(stick(0.0, 0.9)), 0.0, 1.0);
+        assert_near(settings.apply(stick(0.0, 1.0)), 0.0, 1.0);
+        assert_near(settings.apply(stick(3.0, 4.0)), 0.6, 0.8);
+    }
+
+    #[test]
+    fn exponent_curves_the_response() {
+        let squared = InputSettings {
+            exponent: 2.0,
+            ..InputSettings::default()
+        };
+        assert_near(squared.apply(stick(0.5, 0.0)), 0.25, 0.0);
+        assert_near(squared.apply(stick(1.0, 0.0)), 1.0, 0.0);
+
+        let root = InputSettings {
+            exponent: 0.5,
+            ..InputSettings::default()
+        };
+        assert_near(root.apply(stick(0.0, 0.25)), 0.0, 0.5);
+    }
+
+    #[test]
+    fn direction_and_sign_are_preserved() {
+        let settings = InputSettings {
+            deadzone: 0.1,
+            exponent: 2.0,
+            ..InputSettings::default()
+        };
+        let out = settings.apply(stick(-0.3, 0.4));
+        assert!(out.x < 0.0 && out.y > 0.0, "{out:?}");
+        assert!((out.x / out.y + 0.75).abs() < 1e-5, "{out:?}");
+
+        let inverted = InputSettings {
+            invert_x: true,
+            invert_y: true,
+            ..InputSettings::default()
+        };
+        assert_near(inverted.apply(stick(0.3, -0.4)), -0.3, 0.4);
+    }
+
+    #[test]
+    fn rejects_out_of_range_settings() {
+        let with = |deadzone, outer_deadzone, exponent| InputSettings {
+            deadzone,
+            outer_deadzone,
+            exponent,
+            ..InputSettings::default()
+        };
+        assert!(InputSettings::default().validate().is_ok());
+        assert!(with(0.2, 0.1, 2.0).validate().is_ok());
+
+        assert!(with(-0.1, 0.0, 1.0).validate().is_err());
+        assert!(with(1.0, 0.0, 1.0).validate().is_err());
+        assert!(with(0.0, 1.0, 1.0).validate().is_err());
+        assert!(with(0.6, 0.4, 1.0).validate().is_err());
+        assert!(with(0.0, 0.0, 0.05).validate().is_err());
+        assert!(with(0.0, 0.0, 11.0).validate().is_err());
+        assert!(with(f32::NAN, 0.0, 1.0).validate().is_err());
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate_keeps_known_capabilities_in_order() {
+        assert_eq!(
+            Capability::negotiate(&names(&["tilt", "right_stick", "dpad"])),
+            vec![Capability::Tilt, Capability::RightStick, Capability::Dpad]
+        );
+    }
+
+    #[test]
+    fn negotiate_drops_unknown_names_and_duplicates() {
+        assert_eq!(
+            Capability::negotiate(&names(&[
+                "triggers",
+                "haptics",
+                "Triggers",
+                "triggers",
+                "",
+                "shoulders",
+            ])),
+            vec![Capability::Triggers, Capability::Shoulders]
+        );
+        assert!(Capability::negotiate(&names(&["haptics", "gyro"])).is_empty());
+    }
+
+    #[test]
+    fn negotiate_accepts_an_empty_offer() {
+        assert!(Capability::negotiate(&[]).is_empty());
+    }
+
+    #[test]
+    fn extended_input_only_carries_negotiated_capabilities() {
+        let capabilities = Capability::negotiate(&names(&["triggers", "haptics"]));
+        let mut input = ExtendedInput::for_capabilities(&capabilities);
+        input.update(
+            ExtendedInput {
+                right_stick: Some(stick(0.5, 0.5)),
+                triggers: Some(Triggers {
+                    left: 0.4,
+                    right: 1.0,
+                }),
+                ..ExtendedInput::default()
+            },
+            &InputSettings::default(),
+        );
+        assert_eq!(
+            input,
+            ExtendedInput {
+                triggers: Some(Triggers {
+                    left: 0.4,
+                    right: 1.0,
+                }),
+                ..ExtendedInput::default()
+            }
+        );
+    }
+}
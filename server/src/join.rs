@@ -0,0 +1,270 @@
+//! Join links that put a phone straight into a room: the controller page's
+//! URL with the websocket address and room code in its query, and QR codes
+//! of it for a TV to show.
+
+use crate::config::Config;
+use crate::game_state::Message;
+use crate::http::{self, Request, Response};
+use crate::DEFAULT_ROOM;
+use anyhow::Result;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use qrcode::{render::unicode::Dense1x2, Color, QrCode};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::OnceLock,
+};
+use tracing::warn;
+
+/// Light modules around the code, as scanners expect.
+const QUIET_ZONE: usize = 4;
+
+/// Pixels per module in PNG codes.
+const PNG_SCALE: usize = 8;
+
+/// The server's address on the network, found by [`detect_lan_ip`].
+static LAN_IP: OnceLock<Option<IpAddr>> = OnceLock::new();
+
+/// Everything but unreserved characters is escaped in query values.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JoinLink {
+    pub room: String,
+    /// The controller page, opening straight into the room.
+    pub url: String,
+    pub websocket_url: String,
+}
+
+impl JoinLink {
+    /// The link to `room`. `host` is the `Host` header of the request asking
+    /// for it, if any; a loopback host is swapped for the server's address
+    /// on the network, since phones can't reach it.
+    pub fn new(config: &Config, room: &str, host: Option<&str>, secure: bool) -> JoinLink {
+        let websocket_url = match &config.websocket_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => server_address(config, host, secure),
+        };
+        let page = match &config.controller_url {
+            Some(url) => url.clone(),
+            None => controls_page(&websocket_url),
+        };
+        let url = format!(
+            "{page}?server={}&room={}",
+            utf8_percent_encode(&websocket_url, QUERY_VALUE),
+            utf8_percent_encode(room, QUERY_VALUE),
+        );
+        JoinLink {
+            room: room.to_string(),
+            url,
+            websocket_url,
+        }
+    }
+
+    /// The message telling a viewer how to join, with the QR code as SVG.
+    pub fn message(&self) -> Result<Message> {
+        let mut data = serde_json::to_value(self)?;
+        data["svg"] = svg(&self.url)?.into();
+        Ok(Message {
+            type_: "join".to_string(),
+            data,
+        })
+    }
+}
+
+/// Answer `/join` with the join link of the `room` in the query, or of the
+/// default room, and `/join.svg`, `/join.png` and `/join.txt` with its QR
+/// code.
+pub fn respond(request: &Request, config: &Config, secure: bool) -> Response {
+    let room = request
+        .query("room")
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    if room != DEFAULT_ROOM {
+        return Response::text(404, "no such room\n");
+    }
+    let link = JoinLink::new(config, &room, request.header("host"), secure);
+    let response = match request.route() {
+        "/join.svg" => svg(&link.url).map(|svg| Response::new(200, "image/svg+xml", svg)),
+        "/join.png" => png(&link.url).map(|png| Response::new(200, "image/png", png)),
+        "/join.txt" => terminal(&link.url).map(|code| Response::text(200, code)),
+        _ => Ok(Response::json(200, &link)),
+    };
+    match response {
+        Ok(response) => response.with_header("Cache-Control", "no-cache"),
+        Err(e) => {
+            warn!(url = %link.url, error = %e, "failed to encode join code");
+            Response::text(500, "internal server error\n")
+        }
+    }
+}
+
+/// A QR code of `text` as an SVG image, one path for all dark modules.
+pub fn svg(text: &str) -> Result<String> {
+    let (width, dark) = modules(text)?;
+    let mut path = String::new();
+    for y in 0..width {
+        let mut x = 0;
+        while x < width {
+            let run = dark[y * width + x..(y + 1) * width]
+                .iter()
+                .take_while(|&&d| d)
+                .count();
+            if run > 0 {
+                path.push_str(&format!("M{x} {y}h{run}v1h-{run}z"));
+            }
+            x += run.max(1);
+        }
+    }
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {width}\" \
+         shape-rendering=\"crispEdges\"><rect width=\"{width}\" height=\"{width}\" \
+         fill=\"#fff\"/><path fill=\"#000\" d=\"{path}\"/></svg>"
+    ))
+}
+
+/// A QR code of `text` as a greyscale PNG image.
+pub fn png(text: &str) -> Result<Vec<u8>> {
+    let (width, dark) = modules(text)?;
+    let size = width * PNG_SCALE;
+    let mut pixels = Vec::with_capacity(size * size);
+    for row in dark.chunks(width) {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&d| [if d { 0 } else { 255 }; PNG_SCALE])
+            .collect();
+        for _ in 0..PNG_SCALE {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(image)
+}
+
+/// A QR code of `text` drawn with block characters, two modules per line,
+/// followed by the text itself. Drawn for light text on a dark terminal.
+pub fn terminal(text: &str) -> Result<String> {
+    let code = QrCode::new(text)?
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build();
+    Ok(format!("{code}\n{text}\n"))
+}
+
+/// The side of the code including its quiet zone, and whether each module is
+/// dark, row by row.
+fn modules(text: &str) -> Result<(usize, Vec<bool>)> {
+    let code = QrCode::new(text)?;
+    let inner = code.width();
+    let width = inner + 2 * QUIET_ZONE;
+    let mut dark = vec![false; width * width];
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        let (x, y) = (i % inner + QUIET_ZONE, i / inner + QUIET_ZONE);
+        dark[y * width + x] = color == Color::Dark;
+    }
+    Ok((width, dark))
+}
+
+/// The controller page on the game port, which serves the frontend too:
+/// `/controls` on the host of `websocket_url`, whatever its path.
+fn controls_page(websocket_url: &str) -> String {
+    let uri = websocket_url.parse::<::http::Uri>().ok();
+    let Some(authority) = uri.as_ref().and_then(|uri| uri.authority()) else {
+        return format!("http{}/controls", websocket_url.trim_start_matches("ws"));
+    };
+    let secure = uri.as_ref().and_then(|uri| uri.scheme_str()) == Some("wss");
+    format!(
+        "{}://{authority}/controls",
+        if secure { "https" } else { "http" }
+    )
+}
+
+/// The websocket URL phones should use, e.g. `ws://192.168.0.82:3001`.
+fn server_address(config: &Config, host: Option<&str>, secure: bool) -> String {
+    if let Some(host) = host.filter(|h| !is_loopback(http::host_name(h))) {
+        return format!("{}://{host}", if secure { "wss" } else { "ws" });
+    }
+    let (secure, addr) = match (config.listen.first(), config.tls_listen.first()) {
+        (_, Some(addr)) if secure => (true, addr),
+        (Some(addr), _) => (false, addr),
+        (None, Some(addr)) => (true, addr),
+        (None, None) => unreachable!("config has no listen address"),
+    };
+    let ip = if addr.ip().is_unspecified() {
+        LAN_IP.get().copied().flatten().unwrap_or(addr.ip())
+    } else {
+        addr.ip()
+    };
+    let scheme = if secure { "wss" } else { "ws" };
+    format!("{scheme}://{}", SocketAddr::new(ip, addr.port()))
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Find the address of the interface that routes off this host, for links
+/// from servers listening on every interface. Until then they name the
+/// unspecified address.
+pub fn detect_lan_ip() {
+    LAN_IP.get_or_init(|| {
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+        // Connecting only picks the route, here to a documentation address
+        // reached through the default route; nothing is sent
+        socket.connect("192.0.2.1:9").ok()?;
+        Some(socket.local_addr().ok()?.ip())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controls_page_replaces_the_websocket_path() {
+        assert_eq!(
+            controls_page("ws://10.0.0.2:3001"),
+            "http://10.0.0.2:3001/controls"
+        );
+        assert_eq!(
+            controls_page("ws://10.0.0.2:3001/ws"),
+            "http://10.0.0.2:3001/controls"
+        );
+        assert_eq!(
+            controls_page("wss://duck.example/ws/"),
+            "https://duck.example/controls"
+        );
+        assert_eq!(
+            controls_page("wss://[::1]:3443"),
+            "https://[::1]:3443/controls"
+        );
+    }
+
+    #[test]
+    fn link_opens_the_room_on_the_controls_page() {
+        let config = Config {
+            websocket_url: Some("wss://duck.example/".to_string()),
+            ..Config::default()
+        };
+        let link = JoinLink::new(&config, DEFAULT_ROOM, None, false);
+        assert_eq!(link.websocket_url, "wss://duck.example");
+        assert_eq!(
+            link.url,
+            "https://duck.example/controls?server=wss%3A%2F%2Fduck.example&room=default"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Limits on how many connections a single IP address may hold open.
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+lazy_static::lazy_static! {
+    static ref OPEN: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+}
+
+/// One open connection counted against its IP address, released on drop.
+pub struct ConnectionPermit(IpAddr);
+
+/// Count a new connection from `ip`, unless it already has `limit` open. A
+/// limit of 0 means no limit.
+pub fn acquire(ip: IpAddr, limit: usize) -> Option<ConnectionPermit> {
+    let mut open = OPEN.lock().unwrap();
+    let count = open.entry(ip).or_default();
+    if limit != 0 && *count >= limit {
+        return None;
+    }
+    *count += 1;
+    Some(ConnectionPermit(ip))
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut open = OPEN.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(ip: IpAddr) -> usize {
+        OPEN.lock().unwrap().get(&ip).copied().unwrap_or_default()
+    }
+
+    #[test]
+    fn refuses_connections_over_the_limit_until_one_closes() {
+        let ip = IpAddr::from([192, 0, 2, 10]);
+        let first = acquire(ip, 2).unwrap();
+        let second = acquire(ip, 2).unwrap();
+        assert!(acquire(ip, 2).is_none());
+        assert_eq!(open(ip), 2);
+        // Other addresses have limits of their own
+        assert!(acquire(IpAddr::from([192, 0, 2, 11]), 2).is_some());
+
+        drop(first);
+        assert_eq!(open(ip), 1);
+        let third = acquire(ip, 2).unwrap();
+        assert!(acquire(ip, 2).is_none());
+
+        drop((second, third));
+        assert!(!OPEN.lock().unwrap().contains_key(&ip));
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let ip = IpAddr::from([192, 0, 2, 20]);
+        let permits: Vec<_> = (0..100).map(|_| acquire(ip, 0).unwrap()).collect();
+        assert_eq!(open(ip), 100);
+        drop(permits);
+        assert_eq!(open(ip), 0);
+    }
+}
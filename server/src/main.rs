@@ -1,86 +1,400 @@
+mod admin;
+mod bans;
+mod config;
+mod deflate;
+mod discovery;
 mod game_state;
+mod health;
+mod http;
+mod input;
+mod join;
+mod limits;
+mod maps;
+mod metrics;
+mod quic;
+mod reload;
+mod session;
+mod static_files;
+mod tls;
+mod transport;
 mod websocket;
+mod webtransport;
 
-use game_state::{GameState, Message, SharedPlayers};
-use std::{net::SocketAddr, time::Duration};
-use tokio::{net::TcpListener, time};
+use config::{Config, ConfigWatch, LogFormat};
+use game_state::{GameState, Role, SharedPlayers};
+use http::{BoxedStream, Rewind};
+use std::{io::IsTerminal, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+use tracing_subscriber::EnvFilter;
 
-const PHYSICS_UPDATE_RATE: Duration = Duration::from_millis(16); // ~60 FPS
+/// Every player currently shares a single room.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Paths on which websocket upgrades are accepted. Viewers connect to `/` and
+/// controllers to `/ws`.
+const WEBSOCKET_PATHS: [&str; 2] = ["/", "/ws"];
+
+/// How often the ticker logs a summary of every player's input.
+const STATE_SUMMARY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often clients are pinged to measure their round-trip time.
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+fn main() {
+    // Report configuration problems before anything starts listening.
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("ERROR: {e:#}");
+            ::std::process::exit(1);
+        }
+    };
+    // Keep a handle on the filter so log_level can be changed by a reload
+    let set_log_level: reload::LogReloader = match config.log_format {
+        LogFormat::Text => {
+            let subscriber = tracing_subscriber::fmt()
+                .with_env_filter(EnvFilter::new(&config.log_level))
+                .with_filter_reloading();
+            let handle = subscriber.reload_handle();
+            subscriber.init();
+            Box::new(move |level| {
+                handle
+                    .reload(EnvFilter::new(level))
+                    .map_err(|e| e.to_string())
+            })
+        }
+        LogFormat::Json => {
+            let subscriber = tracing_subscriber::fmt()
+                .json()
+                .with_env_filter(EnvFilter::new(&config.log_level))
+                .with_filter_reloading();
+            let handle = subscriber.reload_handle();
+            subscriber.init();
+            Box::new(move |level| {
+                handle
+                    .reload(EnvFilter::new(level))
+                    .map_err(|e| e.to_string())
+            })
+        }
+    };
+    // Looked up before serving, since finding it blocks
+    join::detect_lan_ip();
+    if let Err(e) = run(Arc::new(config), set_log_level) {
+        eprintln!("ERROR: {e:#}");
+        ::std::process::exit(1);
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    env_logger::init();
+async fn run(config: Arc<Config>, set_log_level: reload::LogReloader) -> anyhow::Result<()> {
+    if config.discover {
+        return discovery::query(&config).await;
+    }
+    if let Some(path) = &config.ban_file {
+        let banned = bans::read_file(path)?;
+        info!(banned = banned.len(), "ban file loaded");
+        bans::set_file_bans(banned);
+    }
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    tokio::spawn(reload::watch(config_tx, set_log_level));
+
+    let mut listeners = Vec::new();
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "game server started");
+        listeners.push((listener, None));
+    }
+    if !config.tls_listen.is_empty() {
+        let acceptor = tls::acceptor(&config)?;
+        for addr in &config.tls_listen {
+            let listener = TcpListener::bind(addr).await?;
+            info!(%addr, "game server started with TLS");
+            listeners.push((listener, Some(acceptor.clone())));
+        }
+    }
+
+    let mut endpoints = Vec::new();
+    for &addr in &config.quic_listen {
+        endpoints.push(quic::endpoint(&config, addr)?);
+        info!(%addr, "QUIC endpoint started");
+    }
+
+    if let Some(addr) = config.metrics_listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "metrics endpoint started");
+        tokio::spawn(http::serve_metrics(listener, config_rx.clone()));
+    }
 
-    // Listen on all interfaces so that clients anywhere can connect.
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
-    let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
-    println!("Game Server Started on: {}", addr);
-    println!("----------------------------------------");
+    // Readiness waits on the maps; without a map directory there is nothing to load
+    match &config.map_dir {
+        Some(dir) => match maps::load_dir(dir) {
+            Ok(loaded) => {
+                info!(maps = ?loaded.keys().collect::<Vec<_>>(), "maps loaded");
+                maps::set_loaded(loaded);
+                health::set_maps_loaded(true);
+            }
+            Err(e) => error!(error = %format!("{e:#}"), "failed to load maps"),
+        },
+        None => health::set_maps_loaded(true),
+    }
 
     // Create a shared players map (PDR)
     let players_state: SharedPlayers = game_state::new_players();
 
     // Spawn physics update task for multiple players (Ticker)
     let players_for_physics = players_state.clone();
+    let mut ticker_config = config_rx.clone();
     tokio::spawn(async move {
-        let mut interval = time::interval(PHYSICS_UPDATE_RATE);
+        let mut config = ticker_config.borrow_and_update().clone();
+        let mut interval = time::interval(config.tick_rate);
+        let mut last_send = time::Instant::now();
+        let mut last_summary = time::Instant::now();
         loop {
             interval.tick().await;
+            // Pick up reloaded rates and limits
+            if ticker_config.has_changed().unwrap_or(false) {
+                let latest = ticker_config.borrow_and_update().clone();
+                if latest.tick_rate != config.tick_rate {
+                    interval = time::interval(latest.tick_rate);
+                }
+                config = latest;
+            }
+            let tick_started = time::Instant::now();
             let mut players = players_for_physics.lock().await;
 
             // Update each player's game state (e.g. ensure joystick values are clamped)
-            for (_id, player_state) in players.iter_mut() {
+            for player_state in players.values_mut() {
                 update_joysticks(player_state).await;
             }
+            // Track players whose phones went to sleep mid-game
+            let inactive = if admin::is_paused() {
+                session::Inactive::default()
+            } else {
+                session::mark_inactive(&mut players, &config)
+            };
+            let free = config.max_players.checked_sub(players.len());
+
+            metrics::ROOM_PLAYERS
+                .with_label_values(&[DEFAULT_ROOM])
+                .set(players.len() as i64);
 
-            // Print compact player states (only if there are players)
-            if !players.is_empty() {
-                let states: Vec<_> = players
-                    .iter()
-                    .map(|(id, state)| {
-                        format!(
-                            "{}:[j({:.1},{:.1}),b({}{}{}{})]",
-                            id.split(':').next().unwrap_or(id), // Take first part of IP:PORT
-                            state.joystick.x,
-                            state.joystick.y,
-                            if state.buttons.a { "A" } else { "-" },
-                            if state.buttons.b { "B" } else { "-" },
-                            if state.buttons.x { "X" } else { "-" },
-                            if state.buttons.y { "Y" } else { "-" },
-                        )
-                    })
-                    .collect();
-                println!("Players: {}", states.join(" "));
+            // State goes out at the send rate, which may be slower than the tick
+            let mut state_msg = None;
+            if last_send.elapsed() >= config.send_rate {
+                last_send = time::Instant::now();
+
+                // Log compact player states now and then (only if there are players)
+                if !players.is_empty() && last_summary.elapsed() >= STATE_SUMMARY_INTERVAL {
+                    last_summary = time::Instant::now();
+                    let states: Vec<_> = players
+                        .iter()
+                        .map(|(id, state)| {
+                            format!(
+                                "{}:[j({:.1},{:.1}),b({}{}{}{})]",
+                                id.split(':').next().unwrap_or(id), // Take first part of IP:PORT
+                                state.joystick.x,
+                                state.joystick.y,
+                                if state.buttons.a { "A" } else { "-" },
+                                if state.buttons.b { "B" } else { "-" },
+                                if state.buttons.x { "X" } else { "-" },
+                                if state.buttons.y { "Y" } else { "-" },
+                            )
+                        })
+                        .collect();
+                    debug!(players = players.len(), states = %states.join(" "), "state summary");
+                }
+
+                // Send ALL players' states in one message (global state update)
+                state_msg = Some(session::state_message(&players));
             }
+            // Nothing below may hold up the game while it talks to clients
+            drop(players);
 
-            // Broadcast ALL players' states in one message (global state update)
-            let state_msg = serde_json::to_string(&Message {
-                type_: "state".to_string(),
-                data: serde_json::to_value(&*players).unwrap(),
-            })
-            .unwrap();
+            session::notify_inactive(&inactive, config.afk_action).await;
+            // Let the next queued player in once there's room
+            if let Some(free) = free {
+                session::promote_queued(free).await;
+            }
+            // Controllers don't render the game, so only viewers need it.
+            if let Some(state_msg) = state_msg {
+                session::send_to_role(Role::Viewer, &state_msg).await;
+            }
 
-            websocket::broadcast_message(&state_msg).await;
+            health::record_tick();
+            let tick_duration = tick_started.elapsed();
+            metrics::TICK_SECONDS.observe(tick_duration.as_secs_f64());
+            if tick_duration > config.tick_rate {
+                metrics::TICK_OVERRUNS.inc();
+            }
+        }
+    });
+
+    tokio::spawn(async {
+        let mut interval = time::interval(PING_INTERVAL);
+        loop {
+            interval.tick().await;
+            session::ping_clients().await;
         }
     });
 
-    // Accept connections for multiple players.
+    // Print a code to scan for whoever runs the server in a terminal
+    if config.log_format == LogFormat::Text && std::io::stdout().is_terminal() {
+        let link = join::JoinLink::new(&config, DEFAULT_ROOM, None, false);
+        if let Ok(code) = join::terminal(&link.url) {
+            println!("Scan to join:\n{code}");
+        }
+    }
+
+    if config.announce {
+        tokio::spawn(discovery::announce(
+            players_state.clone(),
+            config_rx.clone(),
+        ));
+    }
+
+    for endpoint in endpoints {
+        tokio::spawn(quic::accept_connections(
+            endpoint,
+            players_state.clone(),
+            config_rx.clone(),
+        ));
+    }
+
+    // Accept connections on every listen address.
+    let accepts: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, tls)| {
+            tokio::spawn(accept_connections(
+                listener,
+                tls,
+                players_state.clone(),
+                config_rx.clone(),
+            ))
+        })
+        .collect();
+    for accept in accepts {
+        accept.await?;
+    }
+    Ok(())
+}
+
+/// Accept connections for multiple players, terminating TLS first on
+/// listeners that have an acceptor.
+async fn accept_connections(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    players_state: SharedPlayers,
+    config_rx: ConfigWatch,
+) {
     while let Ok((stream, addr)) = listener.accept().await {
-        println!("\nNew Client Connection:");
-        println!("----------------------------------------");
-        println!("Client Address: {}", addr);
-        println!("Connection Time: {:?}", time::Instant::now());
-        println!("----------------------------------------");
+        let tls = tls.clone();
+        let players_state = players_state.clone();
+        let config_rx = config_rx.clone();
+        let config = config_rx.borrow().clone();
+        tokio::spawn(async move {
+            let secure = tls.is_some();
+            // Checked before the TLS handshake, so refused clients cost no
+            // more than the accept.
+            if bans::is_banned(addr.ip()) {
+                debug!(%addr, "refusing banned address");
+                metrics::reject_connection("banned");
+                refuse(stream, secure, http::Response::text(403, "banned\n")).await;
+                return;
+            }
+            // Held until the connection closes
+            let Some(_permit) = limits::acquire(addr.ip(), config.max_connections_per_ip) else {
+                debug!(%addr, "too many connections from this address");
+                metrics::reject_connection("per_ip_limit");
+                let response = http::Response::text(429, "too many connections\n");
+                refuse(stream, secure, response).await;
+                return;
+            };
+            let stream: BoxedStream = match tls {
+                Some(acceptor) => {
+                    match time::timeout(config.handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => Box::new(stream),
+                        Ok(Err(e)) => {
+                            debug!(%addr, error = %e, "TLS handshake failed");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!(%addr, "TLS handshake timed out");
+                            metrics::reject_connection("handshake_timeout");
+                            return;
+                        }
+                    }
+                }
+                None => Box::new(stream),
+            };
+            route_connection(stream, addr, secure, players_state, config_rx).await;
+        });
+    }
+}
+
+/// Turn a connection away before its TLS handshake. Only plain connections
+/// can be told why; TLS clients just see it close.
+async fn refuse(mut stream: TcpStream, secure: bool, response: http::Response) {
+    if !secure {
+        let _ = http::write_response(&mut stream, response).await;
+    }
+}
+
+/// Hand websocket upgrades on a websocket path to the game, and answer
+/// anything else as plain HTTP. `secure` says whether the connection is TLS.
+async fn route_connection(
+    mut stream: BoxedStream,
+    addr: SocketAddr,
+    secure: bool,
+    players_state: SharedPlayers,
+    config_rx: ConfigWatch,
+) {
+    let config = config_rx.borrow().clone();
+    let read = time::timeout(config.handshake_timeout, http::read_request(&mut stream)).await;
+    let (request, buffered) = match read {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            debug!(%addr, error = %e, "bad request");
+            metrics::reject_connection("bad_request");
+            let _ =
+                http::write_response(&mut stream, http::Response::text(400, "bad request\n")).await;
+            return;
+        }
+        Err(_) => {
+            debug!(%addr, "timed out reading request");
+            metrics::reject_connection("handshake_timeout");
+            let response = http::Response::text(408, "request timeout\n");
+            let _ = http::write_response(&mut stream, response).await;
+            return;
+        }
+    };
+
+    if request.is_websocket_upgrade() && WEBSOCKET_PATHS.contains(&request.route()) {
+        info!(%addr, "new client connection");
 
         // Use the connection's address as a unique player id
-        let player_id = format!("{}", addr);
-        let players_state_clone = players_state.clone();
-        tokio::spawn(websocket::handle_connection(
-            stream,
-            addr,
-            players_state_clone,
-            player_id,
-        ));
+        let mut peer = transport::Peer::new(addr, format!("{}", addr));
+        peer.host = request.header("host").map(str::to_string);
+        peer.secure = secure;
+        let stream = Rewind::new(buffered, stream);
+        if let Err(e) = websocket::handle_connection(stream, peer, players_state, config_rx).await {
+            error!(%addr, error = %e, "connection failed");
+        }
+    } else {
+        debug!(%addr, method = %request.method, path = %request.path, "http request");
+        let response = if request.route().starts_with("/admin/") {
+            admin::handle(&request, addr, &players_state, &config).await
+        } else {
+            http::game_port_response(&request, &config, secure).await
+        };
+        if let Err(e) = http::write_response(&mut stream, response).await {
+            debug!(%addr, error = %e, "failed to write http response");
+        }
     }
 }
 
@@ -88,12 +402,119 @@ async fn update_joysticks(state: &mut GameState) {
     // Clamp joystick values between -1 and 1
     state.joystick.x = state.joystick.x.clamp(-1.0, 1.0);
     state.joystick.y = state.joystick.y.clamp(-1.0, 1.0);
+    state.extended.clamp();
 
     // (Additional physics or button handling can be added here.)
 }
 
-#[allow(unused_variables)]
-pub fn print_received_message(_addr: SocketAddr, _msg_type: &str, data: &serde_json::Value) {
-    // For debugging: print deserialized GameState if needed.
-    let _game_state: GameState = serde_json::from_value(data.clone()).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpSocket,
+        sync::Mutex,
+    };
+
+    /// A listener on a free local port served by [`accept_connections`].
+    async fn listen(tls: Option<TlsAcceptor>, config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let players = Arc::new(Mutex::new(Default::default()));
+        let (reload, config_rx) = watch::channel(Arc::new(config));
+        tokio::spawn(async move {
+            let _reload = reload;
+            accept_connections(listener, tls, players, config_rx).await;
+        });
+        addr
+    }
+
+    /// Connect to `server` from the loopback address `ip`.
+    async fn connect_from(ip: IpAddr, server: SocketAddr) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(ip, 0)).unwrap();
+        socket.connect(server).await.unwrap()
+    }
+
+    /// Everything the server sends before closing, or `None` if it is still
+    /// waiting on the client after a while.
+    async fn read_until_closed(stream: &mut TcpStream) -> Option<String> {
+        let mut received = Vec::new();
+        let read = stream.read_to_end(&mut received);
+        time::timeout(Duration::from_millis(300), read)
+            .await
+            .ok()?
+            .ok()?;
+        Some(String::from_utf8(received).unwrap())
+    }
+
+    /// A TLS acceptor for a freshly generated certificate.
+    fn acceptor() -> TlsAcceptor {
+        let dir = tempfile::tempdir().unwrap();
+        tls::acceptor(&tls::test::config(dir.path())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn banned_addresses_are_refused_before_the_tls_handshake() {
+        let banned = IpAddr::from([127, 0, 0, 3]);
+        bans::ban(banned);
+        let secure = listen(Some(acceptor()), Config::default()).await;
+        let plain = listen(None, Config::default()).await;
+
+        // Closed without waiting for the client's hello
+        let mut refused = connect_from(banned, secure).await;
+        let tls_refusal = read_until_closed(&mut refused).await;
+        let mut welcome = connect_from(IpAddr::from([127, 0, 0, 4]), secure).await;
+        let tls_welcome = read_until_closed(&mut welcome).await;
+        // Plain connections are told why
+        let mut refused = connect_from(banned, plain).await;
+        let plain_refusal = read_until_closed(&mut refused).await;
+        bans::unban(banned);
+
+        assert_eq!(tls_refusal.as_deref(), Some(""));
+        assert_eq!(tls_welcome, None);
+        let plain_refusal = plain_refusal.unwrap();
+        assert!(
+            plain_refusal.starts_with("HTTP/1.1 403 Forbidden"),
+            "{plain_refusal}"
+        );
+        assert!(
+            plain_refusal.ends_with("\r\n\r\nbanned\n"),
+            "{plain_refusal}"
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_per_ip_limit_are_refused() {
+        let ip = IpAddr::from([127, 0, 0, 5]);
+        let config = Config {
+            max_connections_per_ip: 1,
+            ..Config::default()
+        };
+        let server = listen(None, config).await;
+
+        let mut first = connect_from(ip, server).await;
+        // Still waiting for its request, so the permit is held
+        first.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(read_until_closed(&mut first).await, None);
+        let mut second = connect_from(ip, server).await;
+        let refusal = read_until_closed(&mut second).await.unwrap();
+        assert!(refusal.starts_with("HTTP/1.1 429"), "{refusal}");
+        let mut elsewhere = connect_from(IpAddr::from([127, 0, 0, 6]), server).await;
+        assert_eq!(read_until_closed(&mut elsewhere).await, None);
+
+        first.write_all(b"\r\n").await.unwrap();
+        let answered = read_until_closed(&mut first).await.unwrap();
+        assert!(answered.starts_with("HTTP/1.1 "), "{answered}");
+        // The permit is released just after the connection closes
+        for attempt in 1.. {
+            let mut third = connect_from(ip, server).await;
+            match read_until_closed(&mut third).await {
+                None => break,
+                Some(refusal) if attempt < 10 => assert!(refusal.starts_with("HTTP/1.1 429")),
+                Some(refusal) => panic!("still refused: {refusal}"),
+            }
+        }
+    }
 }
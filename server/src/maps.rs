@@ -0,0 +1,85 @@
+//! Game maps loaded from the configured map directory. The server only needs
+//! to know which maps exist; the layout itself is passed through to viewers.
+
+use crate::game_state::Message;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path, sync::Mutex};
+
+lazy_static::lazy_static! {
+    /// Maps loaded at startup, keyed by id.
+    static ref MAPS: Mutex<BTreeMap<String, GameMap>> = Mutex::new(BTreeMap::new());
+    /// Id of the map currently being played, once one has been chosen.
+    static ref CURRENT: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// A map file in the format of `src/maps/*.json`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameMap {
+    pub name: String,
+    pub width: f32,
+    pub height: f32,
+    /// Platforms, obstacles, spawn points and anything else in the file.
+    #[serde(flatten)]
+    pub layout: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Load every `*.json` map in `dir`, keyed by file stem (e.g. `map1`).
+pub fn load_dir(dir: &Path) -> Result<BTreeMap<String, GameMap>> {
+    let mut maps = BTreeMap::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let map: GameMap = serde_json::from_str(&text)
+            .with_context(|| format!("invalid map {}", path.display()))?;
+        maps.insert(id.to_string(), map);
+    }
+    Ok(maps)
+}
+
+/// Make `maps` the maps that can be switched to.
+pub fn set_loaded(maps: BTreeMap<String, GameMap>) {
+    *MAPS.lock().unwrap() = maps;
+}
+
+pub fn ids() -> Vec<String> {
+    MAPS.lock().unwrap().keys().cloned().collect()
+}
+
+/// Switch to the map `id`. Without a map directory any id is accepted, since
+/// viewers bundle their own maps.
+pub fn select(id: &str) -> Result<Message, String> {
+    let maps = MAPS.lock().unwrap();
+    if !maps.is_empty() && !maps.contains_key(id) {
+        return Err(format!("unknown map `{id}`"));
+    }
+    *CURRENT.lock().unwrap() = Some(id.to_string());
+    Ok(map_message(id, maps.get(id)))
+}
+
+/// Id of the map being played, if one has been chosen.
+pub fn current() -> Option<String> {
+    CURRENT.lock().unwrap().clone()
+}
+
+/// The `map` message announcing the current map, if one has been chosen.
+pub fn current_message() -> Option<Message> {
+    let id = current()?;
+    Some(map_message(&id, MAPS.lock().unwrap().get(&id)))
+}
+
+fn map_message(id: &str, map: Option<&GameMap>) -> Message {
+    Message {
+        type_: "map".to_string(),
+        data: serde_json::json!({ "id": id, "map": map }),
+    }
+}
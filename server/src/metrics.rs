@@ -0,0 +1,169 @@
+//! Prometheus metrics for the game server, exposed over HTTP at `/metrics`.
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+lazy_static::lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    /// Connected clients by role (`player`, `viewer` or `unregistered`).
+    pub static ref CLIENTS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("duckgame_clients", "Connected clients by role"),
+        &["role"],
+    ));
+    /// Players waiting for a free slot.
+    pub static ref QUEUED_PLAYERS: IntGauge = register(IntGauge::new(
+        "duckgame_queued_players",
+        "Players waiting for a free slot",
+    ));
+    /// Registered players per room.
+    pub static ref ROOM_PLAYERS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("duckgame_room_players", "Registered players per room"),
+        &["room"],
+    ));
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_messages_received_total", "Messages received by type"),
+        &["type"],
+    ));
+    /// Messages sent by type, counted once per recipient.
+    pub static ref MESSAGES_SENT: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_messages_sent_total", "Messages sent by type"),
+        &["type"],
+    ));
+    pub static ref BYTES_SENT: IntCounter = register(IntCounter::new(
+        "duckgame_bytes_sent_total",
+        "Websocket payload bytes sent",
+    ));
+    /// Websocket message bytes through permessage-deflate, by direction
+    /// (`sent` or `received`) and form (`raw` or `compressed`). Compressed
+    /// over raw is the compression ratio.
+    pub static ref DEFLATE_BYTES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_deflate_bytes_total", "Websocket message bytes through permessage-deflate"),
+        &["direction", "form"],
+    ));
+    /// Time spent compressing or inflating one message, by direction. The
+    /// sum is the CPU time compression costs.
+    pub static ref DEFLATE_SECONDS: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("duckgame_deflate_seconds", "Time to compress or inflate one message")
+            .buckets(vec![0.00001, 0.00002, 0.00005, 0.0001, 0.0002, 0.0005, 0.001, 0.002, 0.005]),
+        &["direction"],
+    ));
+    /// Messages sent uncompressed to clients that negotiated compression,
+    /// by reason (`small` or `role`).
+    pub static ref DEFLATE_SKIPPED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_deflate_skipped_total", "Messages sent uncompressed to compressing clients"),
+        &["reason"],
+    ));
+    /// Time to hand one message to every recipient of a broadcast.
+    pub static ref BROADCAST_SECONDS: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("duckgame_broadcast_seconds", "Broadcast latency")
+            .buckets(vec![0.0001, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1]),
+    ));
+    pub static ref TICK_SECONDS: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new("duckgame_tick_duration_seconds", "Simulation tick duration")
+            .buckets(vec![0.0001, 0.0005, 0.001, 0.002, 0.004, 0.008, 0.016, 0.032, 0.064]),
+    ));
+    /// Ticks that took longer than the configured tick rate.
+    pub static ref TICK_OVERRUNS: IntCounter = register(IntCounter::new(
+        "duckgame_tick_overruns_total",
+        "Ticks that ran longer than the tick rate",
+    ));
+    /// Sends that failed, after which the client was dropped.
+    pub static ref SEND_FAILURES: IntCounter = register(IntCounter::new(
+        "duckgame_send_failures_total",
+        "Failed sends to clients",
+    ));
+    /// Connections refused before or during the websocket handshake.
+    pub static ref CONNECTIONS_REJECTED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_connections_rejected_total", "Refused connections by reason"),
+        &["reason"],
+    ));
+    /// QUIC clients that carried on from a new address.
+    pub static ref QUIC_MIGRATIONS: IntCounter = register(IntCounter::new(
+        "duckgame_quic_migrations_total",
+        "QUIC connections that moved to a new client address",
+    ));
+    pub static ref MESSAGES_REJECTED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("duckgame_messages_rejected_total", "Rejected client messages by reason"),
+        &["reason"],
+    ));
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<M>) -> M {
+    let metric = metric.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Count a client message the server refused to act on.
+pub fn reject(reason: &str) {
+    MESSAGES_REJECTED.with_label_values(&[reason]).inc();
+}
+
+/// Count a connection the server refused.
+pub fn reject_connection(reason: &str) {
+    CONNECTIONS_REJECTED.with_label_values(&[reason]).inc();
+}
+
+/// Render every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("failed to encode metrics");
+    String::from_utf8(buffer).expect("metrics are not UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of the sample line starting with `prefix`.
+    fn sample(text: &str, prefix: &str) -> f64 {
+        let line = text
+            .lines()
+            .find(|line| line.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no `{prefix}` in\n{text}"));
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn renders_counters_gauges_and_histograms() {
+        let before = render();
+        let rejected = r#"duckgame_connections_rejected_total{reason="render_test"}"#;
+        let broadcasts = "duckgame_broadcast_seconds_count";
+        let fast = r#"duckgame_broadcast_seconds_bucket{le="0.0001"}"#;
+        let count = sample_or_zero(&before, broadcasts);
+        let fast_count = sample_or_zero(&before, fast);
+
+        reject_connection("render_test");
+        reject_connection("render_test");
+        ROOM_PLAYERS.with_label_values(&["render_test"]).set(3);
+        BROADCAST_SECONDS.observe(0.00005);
+        BROADCAST_SECONDS.observe(0.2);
+
+        let text = render();
+        assert!(text.contains("# TYPE duckgame_connections_rejected_total counter"));
+        assert!(text.contains("# TYPE duckgame_broadcast_seconds histogram"));
+        assert_eq!(sample(&text, rejected), 2.0);
+        assert_eq!(
+            sample(&text, r#"duckgame_room_players{room="render_test"}"#),
+            3.0
+        );
+        // At least, as other tests broadcast too
+        assert!(sample(&text, broadcasts) >= count + 2.0);
+        assert!(sample(&text, fast) > fast_count);
+        assert!(sample(&text, r#"duckgame_broadcast_seconds_bucket{le="+Inf"}"#) >= count + 2.0);
+    }
+
+    fn sample_or_zero(text: &str, prefix: &str) -> f64 {
+        match text.lines().any(|line| line.starts_with(prefix)) {
+            true => sample(text, prefix),
+            false => 0.0,
+        }
+    }
+}
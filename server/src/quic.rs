@@ -0,0 +1,570 @@
+//! QUIC endpoint for controllers. Joystick and button input arrives as
+//! unreliable datagrams, so a lost packet never holds up the inputs sent
+//! after it the way a lost TCP segment stalls a websocket.
+//!
+//! Connections negotiating [`ALPN_GAME`] are game clients. Each request opens
+//! a bidirectional stream carrying one JSON message, as sent over a
+//! websocket, and the server answers on the same stream with
+//! newline-separated JSON messages before finishing it. Messages that aren't
+//! replies, like feedback and state broadcasts, arrive the same way on a
+//! unidirectional stream the server opens. Inputs are datagrams holding the
+//! data of an `action` message plus a `seq` number that increases with every
+//! datagram; anything older than the last applied input is dropped. QUIC
+//! clients join the same sessions as websocket ones, so viewers see their
+//! players like any other. Their players are named after the connection
+//! rather than the client's address, so a client that migrates to a new
+//! address keeps playing as the same players.
+//!
+//! Connections negotiating [`webtransport::ALPN`] open a WebTransport session
+//! that carries the same requests and inputs inside its own streams and
+//! datagrams.
+//!
+//! Connections negotiating [`ALPN_FILES`] fetch files from the static
+//! directory, one request per stream. Requests are HTTP/1.1 heads, answered
+//! with the same status, headers and directory listings as on the game port;
+//! bare HTTP/0.9 `GET` lines still get just the file.
+
+use crate::config::{Config, ConfigWatch};
+use crate::game_state::{Message, SharedPlayers};
+use crate::http::{self, Response};
+use crate::transport::{BoxFuture, Connection, Inbound, Incoming, Outbound, Peer, OUTGOING_QUEUE};
+use crate::{bans, limits, metrics, session, static_files, tls, webtransport};
+use anyhow::{anyhow, Context, Result};
+use quinn::crypto::rustls::QuicServerConfig;
+use serde_json::Value;
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// ALPN protocol of controller connections.
+pub const ALPN_GAME: &[u8] = b"duckgame";
+
+/// ALPN protocol of static file connections.
+pub const ALPN_FILES: &[u8] = b"hq-29";
+
+/// Bind a QUIC endpoint on `addr` with the configured TLS certificate.
+pub fn endpoint(config: &Config, addr: SocketAddr) -> Result<quinn::Endpoint> {
+    let (certs, key) = tls::certificate(config)?;
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .context("invalid TLS certificate or key")?;
+    crypto.alpn_protocols = vec![
+        ALPN_GAME.to_vec(),
+        webtransport::ALPN.to_vec(),
+        ALPN_FILES.to_vec(),
+    ];
+
+    let mut server_config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
+    // Clients only open unidirectional streams for HTTP/3, which is allowed
+    // them once it has been negotiated
+    transport_config.max_concurrent_uni_streams(0_u8.into());
+
+    quinn::Endpoint::server(server_config, addr)
+        .with_context(|| format!("failed to bind QUIC endpoint on {addr}"))
+}
+
+/// Accept QUIC connections until the endpoint is closed.
+pub async fn accept_connections(
+    endpoint: quinn::Endpoint,
+    players_state: SharedPlayers,
+    config_rx: ConfigWatch,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let addr = incoming.remote_address();
+        let config = config_rx.borrow().clone();
+        if bans::is_banned(addr.ip()) {
+            debug!(%addr, "refusing banned address");
+            metrics::reject_connection("banned");
+            incoming.refuse();
+            continue;
+        }
+        let Some(permit) = limits::acquire(addr.ip(), config.max_connections_per_ip) else {
+            debug!(%addr, "too many connections from this address");
+            metrics::reject_connection("per_ip_limit");
+            incoming.refuse();
+            continue;
+        };
+        let players_state = players_state.clone();
+        let config_rx = config_rx.clone();
+        tokio::spawn(async move {
+            // Held until the connection closes
+            let _permit = permit;
+            let connecting = match incoming.accept() {
+                Ok(connecting) => connecting,
+                Err(e) => {
+                    debug!(%addr, error = %e, "QUIC connection failed");
+                    return;
+                }
+            };
+            let connection = match time::timeout(config.handshake_timeout, connecting).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(e)) => {
+                    debug!(%addr, error = %e, "QUIC handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    debug!(%addr, "QUIC handshake timed out");
+                    metrics::reject_connection("handshake_timeout");
+                    return;
+                }
+            };
+            let span = info_span!("quic", %addr, role = tracing::field::Empty);
+            match protocol(&connection).as_deref() {
+                Some(ALPN_GAME) => {
+                    handle_controller(connection, players_state, config_rx, Framing::Quic)
+                        .instrument(span)
+                        .await
+                }
+                Some(webtransport::ALPN) => {
+                    connection.set_max_concurrent_uni_streams(webtransport::UNI_STREAMS.into());
+                    webtransport::handle_connection(connection, players_state, config_rx)
+                        .instrument(span)
+                        .await
+                }
+                Some(ALPN_FILES) => serve_files(connection, &config).await,
+                _ => connection.close(0u32.into(), b"unknown protocol"),
+            }
+        });
+    }
+}
+
+/// The ALPN protocol the client negotiated.
+fn protocol(connection: &quinn::Connection) -> Option<Vec<u8>> {
+    connection
+        .handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .protocol
+}
+
+/// How a controller's requests and inputs are wrapped on its connection.
+#[derive(Clone, Copy)]
+pub enum Framing {
+    /// Bare QUIC streams and datagrams.
+    Quic,
+    /// Streams and datagrams of the WebTransport session with this id.
+    WebTransport(u64),
+}
+
+/// How long a closing connection waits for the client to receive its last
+/// messages.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Serve a client's requests and inputs until its connection closes.
+pub async fn handle_controller(
+    connection: quinn::Connection,
+    players_state: SharedPlayers,
+    config: ConfigWatch,
+    framing: Framing,
+) {
+    info!("new QUIC client");
+    let addr = connection.remote_address();
+    let (outgoing, queue) = mpsc::channel(OUTGOING_QUEUE);
+    tokio::spawn(write_messages(connection.clone(), framing, queue).in_current_span());
+    // The connection outlives its address when the client migrates, so its
+    // players are named after the connection instead
+    let mut peer = Peer::new(addr, String::new());
+    peer.player_id = format!("quic/{}", peer.id);
+    peer.secure = true;
+    let client = Connection {
+        peer,
+        inbound: Box::new(QuicInbound {
+            connection: connection.clone(),
+            framing,
+            config: config.clone(),
+            outgoing: outgoing.clone(),
+            replying: false,
+            last_seq: None,
+            addr,
+        }),
+        outbound: Box::new(QuicOutbound {
+            connection,
+            outgoing,
+        }),
+    };
+    session::serve(client, players_state, config).await;
+}
+
+/// Requests and inputs arriving on a QUIC connection.
+struct QuicInbound {
+    connection: quinn::Connection,
+    framing: Framing,
+    config: ConfigWatch,
+    outgoing: mpsc::Sender<Outgoing>,
+    /// Whether messages are being sent on the last request's stream.
+    replying: bool,
+    /// Sequence number of the last input passed on.
+    last_seq: Option<u64>,
+    /// The client's address as of the last thing it sent.
+    addr: SocketAddr,
+}
+
+impl Inbound for QuicInbound {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            // The last request has been handled, so its replies are complete
+            if std::mem::take(&mut self.replying) {
+                self.outgoing.send(Outgoing::EndReply).await.ok()?;
+            }
+            loop {
+                tokio::select! {
+                    stream = self.connection.accept_bi() => {
+                        let (send, recv) = match stream {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                debug!(error = %e, "QUIC connection closed");
+                                return None;
+                            }
+                        };
+                        if !self.follow_migration() {
+                            return None;
+                        }
+                        let request = match self.read_request(recv).await {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!(error = %format!("{e:#}"), "QUIC request failed");
+                                continue;
+                            }
+                        };
+                        self.outgoing.send(Outgoing::Reply(send)).await.ok()?;
+                        self.replying = true;
+                        return Some(match serde_json::from_slice::<Message>(&request) {
+                            Ok(message) => Incoming::Message(message),
+                            Err(_) => Incoming::Invalid("malformed"),
+                        });
+                    }
+                    datagram = self.connection.read_datagram() => match datagram {
+                        Ok(datagram) => {
+                            if !self.follow_migration() {
+                                return None;
+                            }
+                            return Some(self.input(&datagram));
+                        }
+                        Err(e) => {
+                            debug!(error = %e, "QUIC connection closed");
+                            return None;
+                        }
+                    },
+                }
+            }
+        })
+    }
+}
+
+impl QuicInbound {
+    /// Notice the client moving to another address, like a phone switching
+    /// networks or a NAT rebinding its port. QUIC carries the connection
+    /// over, and with it the client's players. Returns false if the new
+    /// address is banned.
+    fn follow_migration(&mut self) -> bool {
+        let addr = self.connection.remote_address();
+        if addr == self.addr {
+            return true;
+        }
+        info!(from = %self.addr, to = %addr, "QUIC client migrated");
+        metrics::QUIC_MIGRATIONS.inc();
+        self.addr = addr;
+        if bans::is_banned(addr.ip()) {
+            debug!(%addr, "refusing banned address");
+            metrics::reject_connection("banned");
+            return false;
+        }
+        true
+    }
+
+    /// Read the one message a request stream carries.
+    async fn read_request(&self, mut recv: quinn::RecvStream) -> Result<Vec<u8>> {
+        let config = self.config.borrow().clone();
+        let read = async {
+            if let Framing::WebTransport(session) = self.framing {
+                webtransport::read_stream_header(&mut recv, session).await?;
+            }
+            recv.read_to_end(config.max_message_size)
+                .await
+                .context("failed reading request")
+        };
+        time::timeout(config.handshake_timeout, read)
+            .await
+            .map_err(|_| anyhow!("timed out reading request"))?
+    }
+
+    /// Turn an input datagram into an `action` message.
+    fn input(&mut self, datagram: &[u8]) -> Incoming {
+        let payload = match self.framing {
+            Framing::Quic => Some(datagram),
+            Framing::WebTransport(session) => webtransport::datagram_payload(datagram, session),
+        };
+        let Some(Ok(data)) = payload.map(serde_json::from_slice::<Value>) else {
+            return Incoming::Invalid("malformed");
+        };
+        let Some(seq) = data.get("seq").and_then(|s| s.as_u64()) else {
+            return Incoming::Invalid("malformed");
+        };
+        // Datagrams may be reordered; an input older than the last one applied
+        // would undo it
+        if self.last_seq.is_some_and(|last| seq <= last) {
+            return Incoming::Invalid("stale");
+        }
+        self.last_seq = Some(seq);
+        Incoming::Message(Message {
+            type_: "action".to_string(),
+            data,
+        })
+    }
+}
+
+/// Messages for a QUIC client, queued for its writer task so a slow client
+/// never holds up sends to everyone else.
+struct QuicOutbound {
+    connection: quinn::Connection,
+    outgoing: mpsc::Sender<Outgoing>,
+}
+
+impl Outbound for QuicOutbound {
+    fn send(&mut self, text: &str) -> Result<()> {
+        self.outgoing
+            .try_send(Outgoing::Message(text.to_string()))
+            .map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => anyhow!("client isn't keeping up"),
+                mpsc::error::TrySendError::Closed(_) => anyhow!("connection closed"),
+            })
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        // QUIC times round trips itself
+        Ok(())
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        Some(self.connection.rtt())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.connection.remote_address())
+    }
+
+    fn close(&mut self) {
+        // Let the writer deliver what's queued first, like a parting message
+        if self.outgoing.try_send(Outgoing::Close).is_err() {
+            self.connection.close(0u32.into(), b"closed");
+        }
+    }
+}
+
+enum Outgoing {
+    /// Send messages on this request stream until the request is handled.
+    Reply(quinn::SendStream),
+    /// The request being replied to has been handled.
+    EndReply,
+    Message(String),
+    /// Close the connection once the client has everything sent before.
+    Close,
+}
+
+/// Write a client's messages as newline-separated JSON: replies on the
+/// stream of the request being handled, and everything else on a
+/// unidirectional stream opened for it.
+async fn write_messages(
+    connection: quinn::Connection,
+    framing: Framing,
+    mut queue: mpsc::Receiver<Outgoing>,
+) {
+    let mut reply: Option<quinn::SendStream> = None;
+    let mut pushed: Option<quinn::SendStream> = None;
+    while let Some(outgoing) = queue.recv().await {
+        let text = match outgoing {
+            Outgoing::Reply(stream) => {
+                reply = Some(stream);
+                continue;
+            }
+            Outgoing::EndReply => {
+                if let Some(mut stream) = reply.take() {
+                    let _ = stream.finish();
+                }
+                continue;
+            }
+            Outgoing::Message(mut text) => {
+                text.push('\n');
+                text
+            }
+            Outgoing::Close => {
+                for mut stream in [reply.take(), pushed.take()].into_iter().flatten() {
+                    if stream.finish().is_ok() {
+                        let _ = time::timeout(CLOSE_GRACE, stream.stopped()).await;
+                    }
+                }
+                connection.close(0u32.into(), b"closed");
+                break;
+            }
+        };
+        if let Some(stream) = &mut reply {
+            // The client may have given up on its request; that's no reason
+            // to drop the connection
+            if let Err(e) = stream.write_all(text.as_bytes()).await {
+                debug!(error = %e, "failed to send reply");
+                reply = None;
+            }
+            continue;
+        }
+        if let Err(e) = push(&connection, framing, &mut pushed, &text).await {
+            debug!(error = %format!("{e:#}"), "failed to send to QUIC client");
+            connection.close(0u32.into(), b"send failed");
+            break;
+        }
+    }
+}
+
+/// Write to the client's unidirectional message stream, opening it first if
+/// this is the first message.
+async fn push(
+    connection: &quinn::Connection,
+    framing: Framing,
+    stream: &mut Option<quinn::SendStream>,
+    text: &str,
+) -> Result<()> {
+    if stream.is_none() {
+        let mut opened = connection.open_uni().await?;
+        if let Framing::WebTransport(session) = framing {
+            opened
+                .write_all(&webtransport::uni_stream_header(session))
+                .await?;
+        }
+        *stream = Some(opened);
+    }
+    stream.as_mut().unwrap().write_all(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Answer requests for static files, one per stream.
+async fn serve_files(connection: quinn::Connection, config: &Config) {
+    let Some(root) = &config.static_dir else {
+        connection.close(0u32.into(), b"no static directory");
+        return;
+    };
+    let root = Arc::<Path>::from(root.as_path());
+    let listed = Arc::<[String]>::from(config.listed_dirs.as_slice());
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        let root = root.clone();
+        let listed = listed.clone();
+        tokio::spawn(async move {
+            let response = match recv.read_to_end(http::MAX_HEAD_SIZE).await {
+                Ok(request) => process_get(&root, &listed, &request).await,
+                Err(e) => {
+                    debug!(error = %e, "failed reading file request");
+                    return;
+                }
+            };
+            if send.write_all(&response).await.is_ok() {
+                let _ = send.finish();
+            }
+        });
+    }
+}
+
+/// Answer one file request. Requests naming an HTTP/1 version get a status
+/// line and headers like on the game port, including `304`s for conditional
+/// requests; HTTP/0.9 requests like `GET /index.html` get the body alone.
+async fn process_get(root: &Path, listed: &[String], request: &[u8]) -> Vec<u8> {
+    let head = std::str::from_utf8(request)
+        .ok()
+        .map(|head| head.trim_end_matches("\r\n"));
+    let Some(request) = head.and_then(|head| http::parse_head(head).ok()) else {
+        debug!("malformed file request");
+        return encode(Response::text(400, "bad request\n"));
+    };
+    let mut response = match request.method.as_str() {
+        "GET" | "HEAD" => static_files::serve(root, listed, &request).await,
+        _ => Response::text(405, "method not allowed\n"),
+    };
+    response.head_only = request.method == "HEAD";
+    let version = head
+        .and_then(|head| head.lines().next())
+        .and_then(|line| line.split(' ').nth(2));
+    match version {
+        Some(_) => encode(response),
+        None => response.body,
+    }
+}
+
+fn encode(response: Response) -> Vec<u8> {
+    let mut bytes = response.head().into_bytes();
+    if !response.head_only {
+        bytes.extend_from_slice(&response.body);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("index.html"), "app").unwrap();
+        root
+    }
+
+    async fn get(root: &tempfile::TempDir, request: &str) -> String {
+        let response = process_get(root.path(), &[], request.as_bytes()).await;
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_http1_requests_with_a_head() {
+        let root = site();
+        let response = get(&root, "GET /index.html HTTP/1.1\r\nHost: duck\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(head.contains("Content-Length: 3\r\n"));
+        assert!(head.contains("Cache-Control: no-cache"));
+        assert_eq!(body, "app");
+
+        let etag = head
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap();
+        let conditional = format!("GET /index.html HTTP/1.1\r\nIf-None-Match: {etag}\r\n\r\n");
+        let response = get(&root, &conditional).await;
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+        let response = get(&root, "HEAD /index.html HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Length: 3\r\n"));
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn answers_bare_gets_with_the_body_alone() {
+        let root = site();
+        assert_eq!(get(&root, "GET /index.html\r\n").await, "app");
+        assert_eq!(get(&root, "GET /missing.js").await, "not found\n");
+    }
+
+    #[tokio::test]
+    async fn refuses_other_methods_and_garbage() {
+        let root = site();
+        let response = get(&root, "POST /index.html HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+        let response = process_get(root.path(), &[], &[0xff, 0xfe]).await;
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        let response = get(&root, "").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[test]
+    fn encode_leaves_out_bodies_of_head_responses() {
+        let full = String::from_utf8(encode(Response::text(200, "hello"))).unwrap();
+        assert!(full.ends_with("Content-Length: 5\r\n\r\nhello"), "{full}");
+        let mut response = Response::text(200, "hello");
+        response.head_only = true;
+        let head = String::from_utf8(encode(response)).unwrap();
+        assert!(head.ends_with("Content-Length: 5\r\n\r\n"), "{head}");
+    }
+}
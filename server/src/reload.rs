@@ -0,0 +1,108 @@
+//! Live reloading of the config file and the ban file. Both are polled for
+//! changes; a changed file is read and validated in full before anything is
+//! applied, so a bad edit leaves the running settings in place.
+
+use crate::bans;
+use crate::config::Config;
+use crate::session;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::watch, time};
+use tracing::{debug, error, info, warn};
+
+/// How often the watched files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replaces the log filter of the running subscriber.
+pub type LogReloader = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Watch the config file and ban file named in the configuration, applying
+/// their changes through `config` as they happen.
+pub async fn watch(config: watch::Sender<Arc<Config>>, set_log_level: LogReloader) {
+    // Both files were already loaded at startup
+    let current = config.borrow().clone();
+    let mut config_contents = read(&current.config_file).await;
+    let mut ban_file = (current.ban_file.clone(), read(&current.ban_file).await);
+
+    let mut interval = time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = config.borrow().clone();
+        let contents = read(&current.config_file).await;
+        if contents != config_contents {
+            config_contents = contents;
+            reload_config(&config, &set_log_level).await;
+        }
+
+        // The reload may have pointed at a different ban file
+        let path = config.borrow().ban_file.clone();
+        let contents = read(&path).await;
+        if (&path, &contents) != (&ban_file.0, &ban_file.1) {
+            reload_bans(&path).await;
+            ban_file = (path, contents);
+        }
+    }
+}
+
+async fn read(path: &Option<PathBuf>) -> Option<Vec<u8>> {
+    tokio::fs::read(path.as_ref()?).await.ok()
+}
+
+async fn reload_config(config: &watch::Sender<Arc<Config>>, set_log_level: &LogReloader) {
+    let current = config.borrow().clone();
+    let new = match Config::reload() {
+        Ok(new) => new,
+        Err(e) => {
+            error!(error = %format!("{e:#}"), "rejected config reload, keeping the running configuration");
+            return;
+        }
+    };
+    let reload = current.reloaded(new);
+    if reload.applied.is_empty() && reload.needs_restart.is_empty() {
+        debug!("config file changed without changing any settings");
+        return;
+    }
+    for change in &reload.applied {
+        info!(%change, "config reloaded");
+    }
+    for change in &reload.needs_restart {
+        warn!(%change, "config change takes effect after a restart");
+    }
+
+    if reload.config.log_level != current.log_level {
+        if let Err(e) = set_log_level(&reload.config.log_level) {
+            warn!(error = %e, "failed to apply log_level");
+        }
+    }
+    let new_motd = reload
+        .config
+        .motd
+        .clone()
+        .filter(|m| Some(m) != current.motd.as_ref());
+    config.send_replace(Arc::new(reload.config));
+    if let Some(motd) = new_motd {
+        session::broadcast_message(&session::motd_message(&motd)).await;
+    }
+}
+
+/// Load the ban file at `path`, or clear the file bans if there is none, and
+/// disconnect clients from newly banned addresses.
+async fn reload_bans(path: &Option<PathBuf>) {
+    let banned = match path {
+        Some(path) => match bans::read_file(path) {
+            Ok(banned) => banned,
+            Err(e) => {
+                error!(error = %format!("{e:#}"), "rejected ban file reload, keeping the previous bans");
+                return;
+            }
+        },
+        None => Default::default(),
+    };
+    let (added, removed) = bans::set_file_bans(banned);
+    if added.is_empty() && removed.is_empty() {
+        return;
+    }
+    info!(?added, ?removed, "ban file reloaded");
+    for ip in added {
+        session::kick_ip(ip, "banned").await;
+    }
+}
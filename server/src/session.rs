@@ -0,0 +1,1665 @@
+//! Client sessions, whichever transport they connect over: registration,
+//! the player queue, message handling, and delivery to connected clients.
+
+use crate::config::{Config, ConfigWatch};
+use crate::game_state::{
+    AfkAction, Feedback, FeedbackRequest, GameState, Message, RejectReason, Rejection, Role,
+};
+use crate::input::{Capability, ExtendedInput, InputSettings};
+use crate::join::JoinLink;
+use crate::metrics;
+use crate::transport::{ClientId, Connection, Incoming, Outbound};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tracing::{debug, info, warn};
+
+/// A connected client: the sending half of its connection, the role it
+/// registered as and the player ids it drives, indexed by local slot.
+struct Client {
+    outbound: Box<dyn Outbound>,
+    /// Where the connection was opened from; see [`Client::addr`].
+    addr: SocketAddr,
+    role: Role,
+    /// Whether the client has sent a `register` message that was accepted.
+    registered: bool,
+    /// The connection's own id, which signaling peers address viewers by.
+    id: String,
+    /// Signaling only reaches peers in the same room.
+    room: String,
+    players: Vec<String>,
+    /// When the outstanding ping was sent, if its pong hasn't arrived yet.
+    ping_sent: Option<Instant>,
+    /// Round-trip time measured from the last answered ping.
+    rtt: Option<Duration>,
+    /// Tells the session one of its players was retired for being AFK.
+    retired: Arc<Notify>,
+    /// Dropped along with the client, which ends the connection's receive
+    /// loop. Removing a client from `CLIENTS` therefore disconnects it.
+    _connection: oneshot::Sender<()>,
+}
+
+impl Client {
+    /// Where the client is connected from now, which for a migrated QUIC
+    /// connection isn't where it started.
+    fn addr(&self) -> SocketAddr {
+        self.outbound.remote_addr().unwrap_or(self.addr)
+    }
+}
+
+/// What the admin API reports about a connected client.
+#[derive(Serialize)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    pub role: Role,
+    pub registered: bool,
+    pub players: Vec<String>,
+    pub rtt_ms: Option<f64>,
+}
+
+/// A player registration waiting for a free slot.
+struct Queued {
+    client: ClientId,
+    /// Local players the registration asked for.
+    slots: usize,
+    /// Woken when enough slots are free for this registration to retry.
+    wake: Arc<Notify>,
+}
+
+// Global state for connected clients
+lazy_static::lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<ClientId, Client>> = Mutex::new(HashMap::new());
+    /// Players waiting for a slot, first in line at the front.
+    static ref QUEUE: Mutex<VecDeque<Queued>> = Mutex::new(VecDeque::new());
+}
+
+/// Most local players a single connection may register.
+const MAX_LOCAL_PLAYERS: usize = 4;
+
+/// Per-connection state kept while a client is connected.
+struct Session {
+    id: ClientId,
+    /// Player id of slot 0; further slots append `#<slot>`.
+    base_id: String,
+    is_player: bool,
+    /// Player ids owned by this connection, indexed by local slot.
+    slots: Vec<String>,
+    config: ConfigWatch,
+    /// How the client reached the server, for the join link viewers get.
+    host: Option<String>,
+    secure: bool,
+    /// The registration to retry once a slot frees up, while queued.
+    queued: Option<Value>,
+    wake: Arc<Notify>,
+    retired: Arc<Notify>,
+}
+
+impl Session {
+    /// The configuration as it stands now, reloads included.
+    fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+
+    fn slot_id(&self, slot: usize) -> String {
+        if slot == 0 {
+            self.base_id.clone()
+        } else {
+            format!("{}#{}", self.base_id, slot)
+        }
+    }
+
+    /// The player id a message addresses through its optional `slot` field.
+    fn player_for(&self, data: &Value) -> Option<(usize, &String)> {
+        let slot = data.get("slot").and_then(|s| s.as_u64()).unwrap_or(0) as usize;
+        self.slots.get(slot).map(|id| (slot, id))
+    }
+}
+
+/// Serve a client until its connection closes or it's removed from the
+/// client list.
+pub async fn serve(
+    connection: Connection,
+    players_state: Arc<Mutex<HashMap<String, GameState>>>,
+    config: ConfigWatch,
+) {
+    let Connection {
+        peer,
+        mut inbound,
+        outbound,
+    } = connection;
+    let mut session = Session {
+        id: peer.id,
+        base_id: peer.player_id.clone(),
+        is_player: false,
+        slots: Vec::new(),
+        config,
+        host: peer.host,
+        secure: peer.secure,
+        queued: None,
+        wake: Arc::new(Notify::new()),
+        retired: Arc::new(Notify::new()),
+    };
+
+    let (connection, mut removed) = oneshot::channel();
+    CLIENTS.lock().await.insert(
+        peer.id,
+        Client {
+            outbound,
+            addr: peer.addr,
+            role: Role::default(),
+            registered: false,
+            id: peer.player_id,
+            room: crate::DEFAULT_ROOM.to_string(),
+            players: Vec::new(),
+            ping_sent: None,
+            rtt: None,
+            retired: session.retired.clone(),
+            _connection: connection,
+        },
+    );
+
+    // Everyone starts out as a viewer, so gets the current state right away
+    let state = state_message(&*players_state.lock().await);
+    send_to(session.id, &state).await;
+
+    // Handle incoming messages until the client leaves or is removed
+    let wake = session.wake.clone();
+    let retired = session.retired.clone();
+    loop {
+        let incoming = tokio::select! {
+            incoming = inbound.recv() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = &mut removed => break,
+            _ = wake.notified(), if session.queued.is_some() => {
+                // A slot freed up; retry the queued registration
+                if let Some(data) = session.queued.take() {
+                    register(&mut session, &players_state, &data).await;
+                }
+                continue;
+            }
+            _ = retired.notified() => {
+                spectate_if_retired(&mut session, &players_state).await;
+                continue;
+            }
+        };
+        match incoming {
+            Incoming::Message(message) => {
+                handle_message(&mut session, &players_state, message).await
+            }
+            Incoming::Invalid(reason) => metrics::reject(reason),
+            Incoming::Pong => record_pong(session.id).await,
+        }
+    }
+
+    // Clean up when client disconnects
+    CLIENTS.lock().await.remove(&session.id);
+    leave_queue(session.id).await;
+    info!("client disconnected");
+
+    // Remove every local player this connection registered
+    if !session.slots.is_empty() {
+        let mut players = players_state.lock().await;
+        for id in &session.slots {
+            players.remove(id);
+        }
+    }
+}
+
+async fn handle_message(
+    session: &mut Session,
+    players_state: &Arc<Mutex<HashMap<String, GameState>>>,
+    message: Message,
+) {
+    debug!(message_type = %message.type_, data = %message.data, "message");
+    let known = matches!(
+        message.type_.as_str(),
+        "register"
+            | "action"
+            | "settings"
+            | "feedback"
+            | "readstate"
+            | "offer"
+            | "answer"
+            | "candidate"
+    );
+    metrics::MESSAGES_RECEIVED
+        .with_label_values(&[if known { &message.type_ } else { "unknown" }])
+        .inc();
+    match message.type_.as_str() {
+        "register" => register(session, players_state, &message.data).await,
+        "action" => {
+            // Only process actions from players
+            let Some((slot, id)) = session.player_for(&message.data) else {
+                metrics::reject("not_player");
+                return;
+            };
+            if crate::admin::is_paused() {
+                metrics::reject("paused");
+                return;
+            }
+            let Ok(action) = serde_json::from_value::<GameState>(message.data) else {
+                metrics::reject("malformed");
+                return;
+            };
+            let mut players = players_state.lock().await;
+            let Some(state) = players.get_mut(id) else {
+                return;
+            };
+            state.apply_action(action);
+            let status = status_of(state);
+            let state_msg = state_message(&players);
+            drop(players);
+            // Viewers draw the new input; the controller only hears about its
+            // own player
+            send_to_role(Role::Viewer, &state_msg).await;
+            send_feedback(session.id, slot, &status).await;
+        }
+        "settings" => {
+            let Some((slot, id)) = session.player_for(&message.data) else {
+                metrics::reject("not_player");
+                return;
+            };
+            match parse_settings(message.data.clone()) {
+                Ok(settings) => {
+                    if let Some(state) = players_state.lock().await.get_mut(id) {
+                        state.settings = settings;
+                    }
+                    send_feedback(session.id, slot, &Feedback::Settings(settings)).await;
+                }
+                Err(e) => {
+                    warn!(error = %e, "rejected settings");
+                    metrics::reject("invalid_settings");
+                }
+            }
+        }
+        "feedback" => {
+            // Only viewers run the game and can report on players
+            if session.is_player {
+                metrics::reject("not_viewer");
+            } else if let Ok(request) = serde_json::from_value::<FeedbackRequest>(message.data) {
+                relay_feedback(session, players_state, request).await;
+            }
+        }
+        "offer" | "answer" | "candidate" => relay_signal(session, message).await,
+        "readstate" => {
+            // Only the client asking gets an answer: viewers the whole game,
+            // controllers the status of each of their players
+            let players = players_state.lock().await;
+            if session.is_player {
+                let statuses: Vec<_> = (session.slots.iter())
+                    .map(|id| players.get(id).map(status_of))
+                    .collect();
+                drop(players);
+                for (slot, status) in statuses.iter().enumerate() {
+                    if let Some(status) = status {
+                        send_feedback(session.id, slot, status).await;
+                    }
+                }
+            } else {
+                let state_msg = state_message(&players);
+                drop(players);
+                send_to(session.id, &state_msg).await;
+            }
+        }
+        _ => {
+            warn!(message_type = %message.type_, "unknown message type");
+            metrics::reject("unknown_type");
+        }
+    }
+}
+
+/// Register the connection's role and, for players, create a game state for
+/// each requested local slot.
+async fn register(
+    session: &mut Session,
+    players_state: &Arc<Mutex<HashMap<String, GameState>>>,
+    data: &Value,
+) {
+    let id = session.id;
+    let Some(role_name) = data.get("role").and_then(|r| r.as_str()) else {
+        return;
+    };
+    let is_player = role_name == "player";
+    let slot_count = if is_player { requested_slots(data) } else { 0 };
+
+    // Turn away registrations that would take the server over capacity, or
+    // queue them if there's room in the queue. Queued players keep their
+    // place, so nobody can register past them.
+    let config = session.config();
+    let mut players = players_state.lock().await;
+    if is_player {
+        let others = other_players(&players, &session.slots);
+        let has_room = others + slot_count <= config.max_players;
+        if !has_room || !first_in_queue(id).await {
+            drop(players);
+            if config.player_queue == 0 {
+                reject(session, players_state, RejectReason::PlayersFull).await;
+            } else if enqueue(id, slot_count, &session.wake, config.player_queue).await {
+                info!(players = others, "server full, queued player");
+                session.queued = Some(data.clone());
+            } else {
+                reject(session, players_state, RejectReason::QueueFull).await;
+            }
+            return;
+        }
+    } else if !session.is_player || session.slots.is_empty() {
+        let viewers = count_viewers(id).await;
+        if viewers >= config.max_viewers {
+            drop(players);
+            reject(session, players_state, RejectReason::ViewersFull).await;
+            return;
+        }
+    }
+    // Registered one way or another, so no longer waiting for a slot
+    session.queued = None;
+    leave_queue(id).await;
+    session.is_player = is_player;
+    let role = if is_player {
+        Role::Player
+    } else {
+        Role::Viewer
+    };
+    tracing::Span::current().record("role", tracing::field::display(role));
+    info!(requested_role = %role_name, slots = slot_count, "registered");
+
+    // Re-registering replaces any slots from an earlier registration
+    for id in session.slots.drain(..) {
+        players.remove(&id);
+    }
+
+    // Only create game state for players
+    if session.is_player {
+        let ids: Vec<_> = (0..slot_count).map(|slot| session.slot_id(slot)).collect();
+        let feedback = add_players(&mut players, &ids, data);
+        session.slots = ids;
+        drop(players);
+
+        set_registration(id, Role::Player, session.slots.clone()).await;
+        for (slot, feedback) in &feedback {
+            send_feedback(id, *slot, feedback).await;
+        }
+    } else {
+        drop(players);
+        set_registration(id, Role::Viewer, Vec::new()).await;
+        // Late viewers need to know which map is being played
+        if let Some(map) = crate::maps::current_message() {
+            send_to(id, &map).await;
+        }
+        // So the screen can show a code for phones to scan
+        let host = session.host.as_deref();
+        let link = JoinLink::new(&config, crate::DEFAULT_ROOM, host, session.secure);
+        match link.message() {
+            Ok(join) => {
+                send_to(id, &join).await;
+            }
+            Err(e) => warn!(url = %link.url, error = %e, "failed to encode join code"),
+        }
+    }
+    if let Some(motd) = &config.motd {
+        send_to(id, &motd_message(motd)).await;
+    }
+}
+
+/// The `state` message carrying every player's state, which viewers draw.
+pub fn state_message(players: &HashMap<String, GameState>) -> Message {
+    Message {
+        type_: "state".to_string(),
+        data: serde_json::to_value(players).unwrap(),
+    }
+}
+
+/// A player's score and health, as feedback for its controller.
+fn status_of(state: &GameState) -> Feedback {
+    Feedback::Status {
+        score: state.score,
+        health: state.health,
+    }
+}
+
+/// Create a game state for each of `ids`, the local slots of one player
+/// registration, with the settings and capabilities it asked for. Returns the
+/// feedback to send each slot's controller.
+fn add_players(
+    players: &mut HashMap<String, GameState>,
+    ids: &[String],
+    data: &Value,
+) -> Vec<(usize, Feedback)> {
+    // Controllers may restore settings saved from an earlier session
+    let settings = match data.get("settings").map(|s| parse_settings(s.clone())) {
+        Some(Ok(settings)) => settings,
+        Some(Err(e)) => {
+            warn!(error = %e, "ignoring registration settings");
+            InputSettings::default()
+        }
+        None => InputSettings::default(),
+    };
+    // Negotiate which optional inputs this controller may send
+    let requested: Vec<String> = data
+        .get("capabilities")
+        .and_then(|c| serde_json::from_value(c.clone()).ok())
+        .unwrap_or_default();
+    let capabilities = Capability::negotiate(&requested);
+
+    let mut feedback = Vec::new();
+    for (slot, id) in ids.iter().enumerate() {
+        let mut state = GameState::new_with_color(players);
+        state.settings = settings;
+        state.capabilities = capabilities.clone();
+        state.extended = ExtendedInput::for_capabilities(&capabilities);
+        feedback.push((
+            slot,
+            Feedback::Assigned {
+                player_id: id.clone(),
+                color: state.color.clone(),
+            },
+        ));
+        feedback.push((
+            slot,
+            Feedback::Capabilities {
+                accepted: capabilities.clone(),
+            },
+        ));
+        players.insert(id.clone(), state);
+    }
+    feedback
+}
+
+/// Players in `players` that aren't among `own`, the slots of one
+/// connection. Slots retired for being AFK or kicked are gone from `players`
+/// while the connection still holds them.
+fn other_players(players: &HashMap<String, GameState>, own: &[String]) -> usize {
+    players.len() - own.iter().filter(|id| players.contains_key(*id)).count()
+}
+
+/// Local player slots a registration asks for, within the per-connection
+/// limit.
+fn requested_slots(data: &Value) -> usize {
+    data.get("slots")
+        .and_then(|s| s.as_u64())
+        .unwrap_or(1)
+        .clamp(1, MAX_LOCAL_PLAYERS as u64) as usize
+}
+
+/// The message of the day, sent to clients as they register.
+pub fn motd_message(motd: &str) -> Message {
+    Message {
+        type_: "motd".to_string(),
+        data: serde_json::json!({ "message": motd }),
+    }
+}
+
+/// Tell a client its registration was turned away, with the counts that
+/// decided it.
+async fn reject(
+    session: &Session,
+    players_state: &Arc<Mutex<HashMap<String, GameState>>>,
+    reason: RejectReason,
+) {
+    let players = players_state.lock().await.len();
+    let msg = rejection_message(reason, players, session.id, &session.config()).await;
+    send_to(session.id, &msg).await;
+}
+
+/// The `rejected` message for a registration from client `id` turned away
+/// for `reason` while `players` players were registered.
+async fn rejection_message(
+    reason: RejectReason,
+    players: usize,
+    id: ClientId,
+    config: &Config,
+) -> Message {
+    let viewers = count_viewers(id).await;
+    warn!(
+        players,
+        viewers,
+        reason = reason.label(),
+        "rejecting registration"
+    );
+    metrics::reject(reason.label());
+    let rejection = Rejection {
+        reason,
+        players,
+        max_players: config.max_players,
+        viewers,
+        max_viewers: config.max_viewers,
+    };
+    Message {
+        type_: "rejected".to_string(),
+        data: serde_json::to_value(rejection).unwrap(),
+    }
+}
+
+/// Whether client `id` may take a free slot: nobody is queued ahead of it.
+async fn first_in_queue(id: ClientId) -> bool {
+    QUEUE.lock().await.front().is_none_or(|q| q.client == id)
+}
+
+/// Put client `id` at the back of the player queue, or leave it where it is
+/// if it's already queued, and tell it its place. Returns false if the queue
+/// is full.
+async fn enqueue(id: ClientId, slots: usize, wake: &Arc<Notify>, limit: usize) -> bool {
+    let mut queue = QUEUE.lock().await;
+    let position = match queue.iter().position(|q| q.client == id) {
+        Some(position) => position,
+        None if queue.len() >= limit => return false,
+        None => {
+            queue.push_back(Queued {
+                client: id,
+                slots,
+                wake: wake.clone(),
+            });
+            metrics::QUEUED_PLAYERS.set(queue.len() as i64);
+            queue.len() - 1
+        }
+    };
+    let length = queue.len();
+    drop(queue);
+    send_to(id, &queue_message(position, length)).await;
+    true
+}
+
+/// Take client `id` out of the player queue and tell everyone behind it their
+/// new place.
+async fn leave_queue(id: ClientId) {
+    let mut queue = QUEUE.lock().await;
+    let Some(position) = queue.iter().position(|q| q.client == id) else {
+        return;
+    };
+    queue.remove(position);
+    metrics::QUEUED_PLAYERS.set(queue.len() as i64);
+    let behind: Vec<_> = queue.iter().skip(position).map(|q| q.client).collect();
+    let length = queue.len();
+    drop(queue);
+    for (offset, id) in behind.into_iter().enumerate() {
+        send_to(id, &queue_message(position + offset, length)).await;
+    }
+}
+
+fn queue_message(position: usize, length: usize) -> Message {
+    Message {
+        type_: "queued".to_string(),
+        // Positions are 1-based for display
+        data: serde_json::json!({ "position": position + 1, "length": length }),
+    }
+}
+
+/// Wake the first queued player if `free` slots are enough for it.
+pub async fn promote_queued(free: usize) {
+    if let Some(first) = QUEUE.lock().await.front() {
+        if first.slots <= free {
+            first.wake.notify_one();
+        }
+    }
+}
+
+/// Parse and validate input settings sent by a controller.
+fn parse_settings(data: Value) -> Result<InputSettings, String> {
+    let settings: InputSettings = serde_json::from_value(data).map_err(|e| e.to_string())?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// Make a player connection a viewer once none of its players are left in
+/// the game, after they were retired for being AFK.
+async fn spectate_if_retired(
+    session: &mut Session,
+    players_state: &Arc<Mutex<HashMap<String, GameState>>>,
+) {
+    let players = players_state.lock().await;
+    if !session.is_player || session.slots.iter().any(|id| players.contains_key(id)) {
+        return;
+    }
+    drop(players);
+    session.is_player = false;
+    session.slots.clear();
+    set_registration(session.id, Role::Viewer, Vec::new()).await;
+    tracing::Span::current().record("role", tracing::field::display(Role::Viewer));
+    info!("moved idle client to spectators");
+}
+
+/// Record the role a client registered as and the players it drives, so
+/// role-targeted sends and per-player feedback reach it.
+async fn set_registration(id: ClientId, role: Role, players: Vec<String>) {
+    if let Some(client) = CLIENTS.lock().await.get_mut(&id) {
+        client.role = role;
+        client.registered = true;
+        client.outbound.set_role(role);
+        client.players = players;
+    }
+}
+
+/// Number of registered viewers other than `except`.
+async fn count_viewers(except: ClientId) -> usize {
+    CLIENTS
+        .lock()
+        .await
+        .iter()
+        .filter(|(&id, c)| id != except && c.registered && c.role == Role::Viewer)
+        .count()
+}
+
+/// Apply a viewer's feedback request to the player's state and forward it to
+/// that player's controller. Viewers may only report what happens in the
+/// game; the rest of the feedback kinds come from the server alone.
+async fn relay_feedback(
+    session: &Session,
+    players_state: &Arc<Mutex<HashMap<String, GameState>>>,
+    request: FeedbackRequest,
+) {
+    if !matches!(
+        request.feedback,
+        Feedback::Hit { .. } | Feedback::Vibrate { .. } | Feedback::Status { .. }
+    ) {
+        metrics::reject("feedback_kind");
+        return;
+    }
+    let registered = CLIENTS
+        .lock()
+        .await
+        .get(&session.id)
+        .is_some_and(|client| client.registered);
+    if !registered {
+        metrics::reject("not_registered");
+        return;
+    }
+    if let Feedback::Status { score, health } = request.feedback {
+        match players_state.lock().await.get_mut(&request.player) {
+            Some(state) => {
+                state.score = score;
+                state.health = health;
+            }
+            None => return,
+        }
+    }
+    match find_player(&request.player).await {
+        Some((target, slot)) => send_feedback(target, slot, &request.feedback).await,
+        None => debug!(player = %request.player, "feedback for unknown player"),
+    }
+}
+
+/// Relay a WebRTC offer, answer or ICE candidate to the peer named in its
+/// `to` field, a player id or a viewer's connection id. The peer receives the
+/// same message with `from` naming the sender, and `slot` when it's addressed
+/// as one of a controller's local players.
+async fn relay_signal(session: &Session, message: Message) {
+    let Value::Object(mut data) = message.data else {
+        metrics::reject("malformed");
+        return;
+    };
+    let payload = if message.type_ == "candidate" {
+        "candidate"
+    } else {
+        "sdp"
+    };
+    let Some(Value::String(to)) = data.remove("to") else {
+        metrics::reject("malformed");
+        return;
+    };
+    if !data.contains_key(payload) {
+        metrics::reject("malformed");
+        return;
+    }
+
+    // Controllers speak as the player in `slot`, viewers as themselves
+    let from = if session.is_player {
+        match session.player_for(&Value::Object(data.clone())) {
+            Some((_, id)) => id.clone(),
+            None => {
+                metrics::reject("not_player");
+                return;
+            }
+        }
+    } else {
+        session.base_id.clone()
+    };
+
+    let target = {
+        let clients = CLIENTS.lock().await;
+        let Some(sender) = clients.get(&session.id).filter(|c| c.registered) else {
+            metrics::reject("not_registered");
+            return;
+        };
+        clients.iter().find_map(|(&id, client)| {
+            if id == session.id || !client.registered || client.room != sender.room {
+                return None;
+            }
+            if client.role == Role::Viewer && client.id == to {
+                return Some((id, None));
+            }
+            let slot = client.players.iter().position(|player| *player == to)?;
+            Some((id, Some(slot)))
+        })
+    };
+    let Some((id, slot)) = target else {
+        debug!(peer = %to, signal = %message.type_, "signal for unknown peer");
+        metrics::reject("unknown_peer");
+        return;
+    };
+
+    data.remove("slot");
+    data.insert("from".to_string(), from.into());
+    if let Some(slot) = slot {
+        data.insert("slot".to_string(), slot.into());
+    }
+    let relayed = Message {
+        type_: message.type_,
+        data: Value::Object(data),
+    };
+    send_to(id, &relayed).await;
+}
+
+/// The client driving `player_id` and the local slot it occupies there.
+async fn find_player(player_id: &str) -> Option<(ClientId, usize)> {
+    CLIENTS.lock().await.iter().find_map(|(&id, client)| {
+        client
+            .players
+            .iter()
+            .position(|player| player == player_id)
+            .map(|slot| (id, slot))
+    })
+}
+
+/// The address of the client driving `player_id`.
+pub async fn player_addr(player_id: &str) -> Option<SocketAddr> {
+    let (id, _) = find_player(player_id).await?;
+    CLIENTS.lock().await.get(&id).map(|client| client.addr())
+}
+
+/// Tell a player's controller about `feedback`, wherever it is connected.
+pub async fn notify_player(player_id: &str, feedback: &Feedback) {
+    if let Some((id, slot)) = find_player(player_id).await {
+        send_feedback(id, slot, feedback).await;
+    }
+}
+
+/// Players the ticker found inactive on one tick.
+#[derive(Debug, Default, PartialEq)]
+pub struct Inactive {
+    /// Players that just went idle.
+    pub idle: Vec<String>,
+    /// Players idle past the AFK timeout, already removed from the game.
+    pub afk: Vec<String>,
+}
+
+/// Mark players without meaningful input for `idle_after` as idle, and take
+/// those without any for `afk_timeout` out of the game. Their controllers
+/// are told with [`notify_inactive`] once the players lock is released.
+pub fn mark_inactive(players: &mut HashMap<String, GameState>, config: &Config) -> Inactive {
+    let mut inactive = Inactive::default();
+    for (id, state) in players.iter_mut() {
+        let inactive_for = state.last_input.0.elapsed();
+        if inactive_for >= config.afk_timeout {
+            inactive.afk.push(id.clone());
+        } else if inactive_for >= config.idle_after && !state.idle {
+            state.idle = true;
+            inactive.idle.push(id.clone());
+        }
+    }
+    for id in &inactive.afk {
+        players.remove(id);
+        info!(player_id = %id, action = ?config.afk_action, "player AFK");
+    }
+    inactive
+}
+
+/// Tell the controllers of players [`mark_inactive`] found idle or AFK, and
+/// retire the AFK ones with `action`.
+pub async fn notify_inactive(inactive: &Inactive, action: AfkAction) {
+    for id in &inactive.idle {
+        notify_player(id, &Feedback::Idle).await;
+    }
+    for id in &inactive.afk {
+        retire_afk_player(id, action).await;
+    }
+}
+
+/// Handle a player that was dropped from the game for being AFK: notify its
+/// controller, then have its session demote the connection to a viewer once
+/// it has no players left, or close it outright.
+pub async fn retire_afk_player(player_id: &str, action: AfkAction) {
+    let Some((id, slot)) = find_player(player_id).await else {
+        return;
+    };
+    send_feedback(id, slot, &Feedback::Afk { action }).await;
+
+    let mut clients = CLIENTS.lock().await;
+    let Some(client) = clients.get_mut(&id) else {
+        return;
+    };
+    let addr = client.addr();
+    match action {
+        AfkAction::Spectate => client.retired.notify_one(),
+        AfkAction::Remove => {
+            if let Some(mut client) = clients.remove(&id) {
+                client.outbound.close();
+                info!(%addr, "disconnected idle client");
+            }
+        }
+    }
+}
+
+/// Disconnect every client matching `filter`, telling it why first. Returns
+/// the addresses that were disconnected.
+async fn kick_where(reason: &str, filter: impl Fn(SocketAddr) -> bool) -> Vec<SocketAddr> {
+    let text = serde_json::to_string(&Message {
+        type_: "kicked".to_string(),
+        data: serde_json::json!({ "reason": reason }),
+    })
+    .unwrap();
+    let mut clients = CLIENTS.lock().await;
+    let matching: Vec<_> = clients
+        .iter()
+        .filter(|(_, client)| filter(client.addr()))
+        .map(|(&id, _)| id)
+        .collect();
+    let mut kicked = Vec::new();
+    for id in matching {
+        if let Some(mut client) = clients.remove(&id) {
+            let _ = client.outbound.send(&text);
+            client.outbound.close();
+            info!(addr = %client.addr(), reason, "kicked client");
+            kicked.push(client.addr());
+        }
+    }
+    kicked
+}
+
+/// Disconnect the clients at `addr`. Returns false if none are connected.
+pub async fn kick(addr: SocketAddr, reason: &str) -> bool {
+    !kick_where(reason, |a| a == addr).await.is_empty()
+}
+
+/// Disconnect every client connecting from `ip`.
+pub async fn kick_ip(ip: IpAddr, reason: &str) -> Vec<SocketAddr> {
+    kick_where(reason, |a| a.ip() == ip).await
+}
+
+/// Ping every client whose transport doesn't time round trips itself, so its
+/// round-trip time can be measured from the pong.
+pub async fn ping_clients() {
+    for client in CLIENTS.lock().await.values_mut() {
+        if client.outbound.rtt().is_none() && client.outbound.ping().is_ok() {
+            client.ping_sent = Some(Instant::now());
+        }
+    }
+}
+
+async fn record_pong(id: ClientId) {
+    if let Some(client) = CLIENTS.lock().await.get_mut(&id) {
+        if let Some(sent) = client.ping_sent.take() {
+            client.rtt = Some(sent.elapsed());
+        }
+    }
+}
+
+/// Every connected client, sorted by address.
+pub async fn client_info() -> Vec<ClientInfo> {
+    let mut info: Vec<_> = CLIENTS
+        .lock()
+        .await
+        .values()
+        .map(|client| ClientInfo {
+            addr: client.addr(),
+            role: client.role,
+            registered: client.registered,
+            players: client.players.clone(),
+            rtt_ms: client
+                .outbound
+                .rtt()
+                .or(client.rtt)
+                .map(|rtt| rtt.as_secs_f64() * 1000.0),
+        })
+        .collect();
+    info.sort_by_key(|client| client.addr);
+    info
+}
+
+/// Send a feedback message to the controller of one local player slot.
+pub async fn send_feedback(id: ClientId, slot: usize, feedback: &Feedback) {
+    send_to(id, &feedback_message(slot, feedback)).await;
+}
+
+/// The `feedback` message for one local player slot.
+fn feedback_message(slot: usize, feedback: &Feedback) -> Message {
+    let mut data = serde_json::to_value(feedback).unwrap();
+    data["slot"] = slot.into();
+    Message {
+        type_: "feedback".to_string(),
+        data,
+    }
+}
+
+/// Send a message to a single client. Returns false if the client is not
+/// connected or the send failed, in which case the client is removed.
+pub async fn send_to(id: ClientId, message: &Message) -> bool {
+    let text = serde_json::to_string(message).unwrap();
+    let mut clients = CLIENTS.lock().await;
+    let Some(client) = clients.get_mut(&id) else {
+        return false;
+    };
+    if let Err(e) = client.outbound.send(&text) {
+        warn!(addr = %client.addr(), error = %e, "failed to send, removing client");
+        metrics::SEND_FAILURES.inc();
+        clients.remove(&id);
+        return false;
+    }
+    record_sent(message, &text, 1);
+    true
+}
+
+/// Send a message to every client registered with `role`.
+pub async fn send_to_role(role: Role, message: &Message) {
+    send_where(message, |client| client.role == role).await;
+}
+
+// Helper function to broadcast a message to all clients
+pub async fn broadcast_message(message: &Message) {
+    send_where(message, |_| true).await;
+}
+
+async fn send_where(message: &Message, filter: impl Fn(&Client) -> bool) {
+    let _timer = metrics::BROADCAST_SECONDS.start_timer();
+    let text = serde_json::to_string(message).unwrap();
+    let mut clients = CLIENTS.lock().await;
+    let mut failed_clients = Vec::new();
+    let mut delivered = 0;
+
+    for (&id, client) in clients.iter_mut().filter(|(_, c)| filter(c)) {
+        if let Err(e) = client.outbound.send(&text) {
+            warn!(addr = %client.addr(), error = %e, "failed to send, removing client");
+            failed_clients.push(id);
+        } else {
+            delivered += 1;
+        }
+    }
+    metrics::SEND_FAILURES.inc_by(failed_clients.len() as u64);
+    for id in failed_clients {
+        clients.remove(&id);
+    }
+    record_sent(message, &text, delivered);
+}
+
+fn record_sent(message: &Message, text: &str, recipients: u64) {
+    metrics::MESSAGES_SENT
+        .with_label_values(&[&message.type_])
+        .inc_by(recipients);
+    metrics::BYTES_SENT.inc_by(text.len() as u64 * recipients);
+}
+
+/// Refresh the connected-clients gauge from the current client list.
+pub async fn update_client_gauges() {
+    let (mut players, mut viewers, mut unregistered) = (0, 0, 0);
+    for client in CLIENTS.lock().await.values() {
+        match (client.registered, client.role) {
+            (false, _) => unregistered += 1,
+            (true, Role::Player) => players += 1,
+            (true, Role::Viewer) => viewers += 1,
+        }
+    }
+    metrics::CLIENTS.with_label_values(&["player"]).set(players);
+    metrics::CLIENTS.with_label_values(&["viewer"]).set(viewers);
+    metrics::CLIENTS
+        .with_label_values(&["unregistered"])
+        .set(unregistered);
+}
+
+/// A server of sessions over in-memory connections, for driving sessions
+/// from tests.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::transport::{self, test::TestClient};
+    use serde_json::json;
+    use tokio::{
+        sync::{watch, MutexGuard},
+        task::JoinHandle,
+    };
+
+    /// Sessions share the global client list and queue, so tests take turns.
+    static SERIAL: Mutex<()> = Mutex::const_new(());
+
+    /// Sessions served against one shared player map and configuration.
+    pub struct Server {
+        pub players: Arc<Mutex<HashMap<String, GameState>>>,
+        pub config: ConfigWatch,
+        _reload: watch::Sender<Arc<Config>>,
+        pub sessions: HashMap<SocketAddr, JoinHandle<()>>,
+        next_port: u16,
+        _serial: MutexGuard<'static, ()>,
+    }
+
+    impl Server {
+        pub async fn start(config: Config) -> Server {
+            let serial = SERIAL.lock().await;
+            // Whatever an earlier test left behind
+            CLIENTS.lock().await.clear();
+            QUEUE.lock().await.clear();
+            let (reload, config) = watch::channel(Arc::new(config));
+            Server {
+                players: Arc::new(Mutex::new(HashMap::new())),
+                config,
+                _reload: reload,
+                sessions: HashMap::new(),
+                next_port: 40000,
+                _serial: serial,
+            }
+        }
+
+        /// A connected client that hasn't registered yet.
+        pub fn connect(&mut self) -> TestClient {
+            self.connect_from(IpAddr::from([127, 0, 0, 1]))
+        }
+
+        /// A connected client at `ip` that hasn't registered yet.
+        pub fn connect_from(&mut self, ip: IpAddr) -> TestClient {
+            self.next_port += 1;
+            let addr = SocketAddr::new(ip, self.next_port);
+            let (connection, client) = transport::test::connection(addr);
+            let session =
+                tokio::spawn(serve(connection, self.players.clone(), self.config.clone()));
+            self.sessions.insert(client.addr, session);
+            client
+        }
+
+        /// A connected controller registered with `slots` local players, and
+        /// the player id of its first.
+        pub async fn player(&mut self, slots: usize) -> (TestClient, String) {
+            let mut client = self.connect();
+            client.send("register", json!({ "role": "player", "slots": slots }));
+            let id = assigned(&mut client).await;
+            for _ in 1..slots {
+                assigned(&mut client).await;
+            }
+            (client, id)
+        }
+
+        /// A connected, registered viewer.
+        pub async fn viewer(&mut self) -> TestClient {
+            let mut client = self.connect();
+            client.send("register", json!({ "role": "viewer" }));
+            client.expect("join").await;
+            client
+        }
+
+        /// Disconnect `client` and wait for its session to clean up.
+        pub async fn disconnect(&mut self, mut client: TestClient) {
+            client.disconnect();
+            if let Some(session) = self.sessions.remove(&client.addr) {
+                session.await.unwrap();
+            }
+        }
+    }
+
+    /// The next feedback of `kind` sent to `client`.
+    pub async fn feedback(client: &mut TestClient, kind: &str) -> Value {
+        loop {
+            let feedback = client.expect("feedback").await;
+            if feedback.data["kind"] == kind {
+                return feedback.data;
+            }
+        }
+    }
+
+    /// The player id in the next `assigned` feedback to `client`.
+    pub async fn assigned(client: &mut TestClient) -> String {
+        let assigned = feedback(client, "assigned").await;
+        assigned["player_id"].as_str().unwrap().to_string()
+    }
+
+    /// The types of the messages `client` was sent and hasn't taken yet.
+    pub fn types(client: &mut TestClient) -> Vec<String> {
+        client.drain().into_iter().map(|m| m.type_).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test::*;
+    use super::*;
+    use crate::transport::test::TestClient;
+    use serde_json::json;
+
+    /// Wait for `client`'s session to demote it to a viewer.
+    async fn spectating(client: &TestClient) {
+        let demoted = async {
+            loop {
+                let clients = client_info().await;
+                let client = clients.iter().find(|c| c.addr == client.addr).unwrap();
+                if client.role == Role::Viewer && client.players.is_empty() {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), demoted)
+            .await
+            .expect("client never became a spectator");
+    }
+
+    #[tokio::test]
+    async fn registers_players_and_viewers() {
+        let mut server = Server::start(Config::default()).await;
+        let (_player, id) = server.player(1).await;
+        let mut viewer = server.connect();
+        viewer.send("register", json!({ "role": "viewer" }));
+        viewer.expect("join").await;
+
+        assert!(server.players.lock().await.contains_key(&id));
+        let mut roles: Vec<_> = client_info()
+            .await
+            .into_iter()
+            .map(|client| (client.role, client.registered, client.players))
+            .collect();
+        roles.sort_by_key(|(role, ..)| *role == Role::Viewer);
+        assert_eq!(
+            roles,
+            [(Role::Player, true, vec![id]), (Role::Viewer, true, vec![])]
+        );
+    }
+
+    #[tokio::test]
+    async fn join_links_lead_back_the_way_viewers_came() {
+        let mut server = Server::start(Config::default()).await;
+        let addr = SocketAddr::from(([127, 0, 0, 1], 39999));
+        let (mut connection, mut viewer) = crate::transport::test::connection(addr);
+        connection.peer.host = Some("duck.lan:3443".to_string());
+        connection.peer.secure = true;
+        let session = serve(connection, server.players.clone(), server.config.clone());
+        server.sessions.insert(addr, tokio::spawn(session));
+        viewer.send("register", json!({ "role": "viewer" }));
+
+        let join = viewer.expect("join").await;
+        assert_eq!(join.data["websocketUrl"], "wss://duck.lan:3443");
+        assert_eq!(
+            join.data["url"],
+            "https://duck.lan:3443/controls?server=wss%3A%2F%2Fduck.lan%3A3443&room=default"
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnecting_removes_every_slot() {
+        let mut server = Server::start(Config::default()).await;
+        let (player, id) = server.player(3).await;
+        let mut ids: Vec<_> = server.players.lock().await.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, [id.clone(), format!("{id}#1"), format!("{id}#2")]);
+
+        server.disconnect(player).await;
+        assert!(server.players.lock().await.is_empty());
+        assert!(client_info().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_players_when_full() {
+        let config = Config {
+            max_players: 1,
+            ..Config::default()
+        };
+        let mut server = Server::start(config).await;
+        let (_first, _) = server.player(1).await;
+        let mut second = server.connect();
+        second.send("register", json!({ "role": "player" }));
+
+        let rejected = second.expect("rejected").await;
+        assert_eq!(rejected.data["reason"], "players_full");
+        assert_eq!(rejected.data["players"], 1);
+        assert_eq!(rejected.data["max_players"], 1);
+        assert_eq!(server.players.lock().await.len(), 1);
+    }
+
+    #[test]
+    fn counts_only_slots_still_registered() {
+        let players: HashMap<_, _> = ["a", "b", "c"]
+            .into_iter()
+            .map(|id| (id.to_string(), GameState::default()))
+            .collect();
+        let own = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        assert_eq!(other_players(&players, &[]), 3);
+        assert_eq!(other_players(&players, &own(&["a", "b"])), 1);
+        // Slots removed behind the session's back don't count
+        assert_eq!(
+            other_players(&players, &own(&["a", "gone", "also-gone"])),
+            2
+        );
+        assert_eq!(other_players(&HashMap::new(), &own(&["gone"])), 0);
+    }
+
+    #[tokio::test]
+    async fn registers_again_after_its_player_was_removed() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        server.players.lock().await.remove(&id);
+
+        player.send("register", json!({ "role": "player" }));
+        assert_eq!(assigned(&mut player).await, id);
+        assert_eq!(server.players.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn afk_spectator_can_register_again() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(1).await;
+
+        // As the ticker retires an AFK player
+        server.players.lock().await.remove(&id);
+        retire_afk_player(&id, AfkAction::Spectate).await;
+        let afk = feedback(&mut player, "afk").await;
+        assert_eq!(afk["action"], "spectate");
+        spectating(&player).await;
+
+        // Spectators report on players like any viewer
+        let refused = || {
+            metrics::MESSAGES_REJECTED
+                .with_label_values(&["not_viewer"])
+                .get()
+        };
+        let before = refused();
+        player.send("feedback", json!({ "player": "nobody", "kind": "hit" }));
+        player.send("register", json!({ "role": "player" }));
+        assert_eq!(assigned(&mut player).await, id);
+        assert_eq!(refused(), before);
+        assert!(server.players.lock().await.contains_key(&id));
+        assert_eq!(client_info().await[0].role, Role::Player);
+    }
+
+    #[tokio::test]
+    async fn queued_player_is_promoted_when_a_slot_frees() {
+        let config = Config {
+            max_players: 1,
+            player_queue: 2,
+            ..Config::default()
+        };
+        let mut server = Server::start(config).await;
+        let (first, _) = server.player(1).await;
+        let mut second = server.connect();
+        second.send("register", json!({ "role": "player" }));
+        let queued = second.expect("queued").await;
+        assert_eq!(queued.data, json!({ "position": 1, "length": 1 }));
+
+        server.disconnect(first).await;
+        promote_queued(1).await;
+        let id = assigned(&mut second).await;
+        assert_eq!(id, second.addr.to_string());
+        assert!(QUEUE.lock().await.is_empty());
+    }
+
+    fn rejected(reason: &str) -> u64 {
+        metrics::MESSAGES_REJECTED
+            .with_label_values(&[reason])
+            .get()
+    }
+
+    #[tokio::test]
+    async fn actions_reach_viewers_and_the_controllers_own_status() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(1).await;
+        let mut viewer = server.viewer().await;
+        player.drain();
+
+        let buttons = json!({ "a": true, "b": false, "x": false, "y": false });
+        let action = json!({ "joystick": { "x": 0.5, "y": 0.0 }, "buttons": buttons });
+        player.send("action", action);
+        let state = viewer.expect("state").await;
+        assert_eq!(state.data[&id]["joystick"]["x"], 0.5);
+        assert_eq!(state.data[&id]["buttons"]["a"], true);
+        let status = feedback(&mut player, "status").await;
+        assert_eq!(
+            status,
+            json!({ "kind": "status", "score": 0, "health": 100, "slot": 0 })
+        );
+        assert!(!types(&mut player).contains(&"state".to_string()));
+    }
+
+    #[tokio::test]
+    async fn readstate_answers_only_the_client_asking() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        let mut viewer = server.viewer().await;
+        let mut other = server.viewer().await;
+        server.players.lock().await.get_mut(&id).unwrap().score = 7;
+        player.drain();
+        other.drain();
+
+        viewer.send("readstate", Value::Null);
+        let state = viewer.expect("state").await;
+        assert_eq!(state.data[&id]["score"], 7);
+
+        player.send("readstate", Value::Null);
+        let first = feedback(&mut player, "status").await;
+        let second = feedback(&mut player, "status").await;
+        assert_eq!(
+            (first["slot"].clone(), first["score"].clone()),
+            (json!(0), json!(7))
+        );
+        assert_eq!(
+            (second["slot"].clone(), second["score"].clone()),
+            (json!(1), json!(0))
+        );
+        assert!(!types(&mut player).contains(&"state".to_string()));
+        assert!(types(&mut other).is_empty());
+    }
+
+    #[tokio::test]
+    async fn targeted_sends_reach_only_their_clients() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        let mut viewer = server.viewer().await;
+        let mut other = server.viewer().await;
+        player.drain();
+        other.drain();
+        let message = |type_: &str| Message {
+            type_: type_.to_string(),
+            data: Value::Null,
+        };
+
+        assert!(send_to(viewer.id, &message("direct")).await);
+        send_to_role(Role::Player, &message("players")).await;
+        send_to_role(Role::Viewer, &message("viewers")).await;
+        notify_player(&format!("{id}#1"), &Feedback::Idle).await;
+        notify_player("nobody", &Feedback::Idle).await;
+
+        assert_eq!(types(&mut viewer), ["direct", "viewers"]);
+        assert_eq!(types(&mut other), ["viewers"]);
+        let sent = player.drain();
+        let sent: Vec<_> = sent.iter().map(|m| (&*m.type_, &m.data)).collect();
+        assert_eq!(
+            sent,
+            [
+                ("players", &Value::Null),
+                ("feedback", &json!({ "kind": "idle", "slot": 1 })),
+            ]
+        );
+        assert!(!send_to(ClientId::MAX, &message("direct")).await);
+    }
+
+    #[tokio::test]
+    async fn relays_game_feedback_from_viewers() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        let viewer = server.viewer().await;
+        let second = format!("{id}#1");
+
+        viewer.send(
+            "feedback",
+            json!({ "player": second, "kind": "hit", "by": id }),
+        );
+        let hit = feedback(&mut player, "hit").await;
+        assert_eq!(hit, json!({ "kind": "hit", "by": id, "slot": 1 }));
+
+        viewer.send(
+            "feedback",
+            json!({ "player": id, "kind": "vibrate", "pattern": [50, 20, 50] }),
+        );
+        let vibrate = feedback(&mut player, "vibrate").await;
+        assert_eq!(vibrate["pattern"], json!([50, 20, 50]));
+        assert_eq!(vibrate["slot"], 0);
+
+        viewer.send(
+            "feedback",
+            json!({ "player": second, "kind": "status", "score": 3, "health": 40 }),
+        );
+        let status = feedback(&mut player, "status").await;
+        assert_eq!(
+            status,
+            json!({ "kind": "status", "score": 3, "health": 40, "slot": 1 })
+        );
+        let players = server.players.lock().await;
+        assert_eq!((players[&second].score, players[&second].health), (3, 40));
+    }
+
+    #[tokio::test]
+    async fn refuses_feedback_only_the_server_sends() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(1).await;
+        let viewer = server.viewer().await;
+        let unregistered = server.connect();
+        player.drain();
+
+        let before = rejected("feedback_kind");
+        let spoofed = [
+            json!({ "player": id, "kind": "assigned", "player_id": "x", "color": "#000000" }),
+            json!({ "player": id, "kind": "afk", "action": "remove" }),
+            json!({ "player": id, "kind": "idle" }),
+            json!({ "player": id, "kind": "settings", "deadzone": 0.5 }),
+            json!({ "player": id, "kind": "capabilities", "accepted": ["tilt"] }),
+        ];
+        for feedback in &spoofed {
+            viewer.send("feedback", feedback.clone());
+        }
+        let before_unregistered = rejected("not_registered");
+        unregistered.send(
+            "feedback",
+            json!({ "player": id, "kind": "status", "score": 99, "health": 1 }),
+        );
+
+        // Anything relayed would arrive ahead of this
+        viewer.send("feedback", json!({ "player": id, "kind": "hit" }));
+        feedback(&mut player, "hit").await;
+        assert_eq!(rejected("feedback_kind"), before + spoofed.len() as u64);
+        assert_eq!(rejected("not_registered"), before_unregistered + 1);
+        assert!(types(&mut player).is_empty());
+        assert_eq!(server.players.lock().await[&id].score, 0);
+    }
+
+    /// A configuration that marks players idle after 1s and AFK after 2s.
+    fn impatient(afk_action: AfkAction) -> Config {
+        Config {
+            idle_after: Duration::from_secs(1),
+            afk_timeout: Duration::from_secs(2),
+            afk_action,
+            ..Config::default()
+        }
+    }
+
+    /// Make `id` look like it last sent input `ago`.
+    async fn last_input(server: &Server, id: &str, ago: Duration) {
+        let mut players = server.players.lock().await;
+        players.get_mut(id).unwrap().last_input =
+            crate::game_state::Activity(tokio::time::Instant::now() - ago);
+    }
+
+    /// Run the ticker's inactivity check once, as the ticker does.
+    async fn check_activity(server: &Server) -> Inactive {
+        let config = server.config.borrow().clone();
+        let inactive = mark_inactive(&mut *server.players.lock().await, &config);
+        notify_inactive(&inactive, config.afk_action).await;
+        inactive
+    }
+
+    #[tokio::test]
+    async fn idle_players_are_told_once_and_afk_players_spectate() {
+        let mut server = Server::start(impatient(AfkAction::Spectate)).await;
+        let (mut active, active_id) = server.player(1).await;
+        let (mut idle, idle_id) = server.player(1).await;
+        let (mut afk, afk_id) = server.player(1).await;
+        last_input(&server, &idle_id, Duration::from_millis(1500)).await;
+        last_input(&server, &afk_id, Duration::from_secs(3)).await;
+
+        let inactive = check_activity(&server).await;
+        assert_eq!(
+            inactive,
+            Inactive {
+                idle: vec![idle_id.clone()],
+                afk: vec![afk_id.clone()],
+            }
+        );
+        assert_eq!(feedback(&mut idle, "idle").await["slot"], 0);
+        assert_eq!(feedback(&mut afk, "afk").await["action"], "spectate");
+        {
+            let players = server.players.lock().await;
+            assert!(players[&idle_id].idle);
+            assert!(!players[&active_id].idle);
+            assert!(!players.contains_key(&afk_id));
+        }
+
+        // Already idle, so not told again
+        assert_eq!(check_activity(&server).await, Inactive::default());
+        active.drain();
+        idle.drain();
+        assert!(types(&mut active).is_empty() && types(&mut idle).is_empty());
+
+        // The AFK controller stays connected, as a viewer
+        spectating(&afk).await;
+        afk.send("readstate", Value::Null);
+        let state = afk.expect("state").await;
+        assert!(state.data.get(&afk_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn afk_players_are_disconnected_when_removal_is_configured() {
+        let mut server = Server::start(impatient(AfkAction::Remove)).await;
+        let (_active, _) = server.player(1).await;
+        let (mut afk, afk_id) = server.player(1).await;
+        last_input(&server, &afk_id, Duration::from_secs(3)).await;
+
+        let inactive = check_activity(&server).await;
+        assert_eq!(inactive.afk, vec![afk_id.clone()]);
+        assert_eq!(feedback(&mut afk, "afk").await["action"], "remove");
+        let session = server.sessions.remove(&afk.addr).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), session)
+            .await
+            .expect("AFK session kept running")
+            .unwrap();
+        assert_eq!(client_info().await.len(), 1);
+        assert!(!server.players.lock().await.contains_key(&afk_id));
+    }
+
+    /// Everything `client` is sent until the server answers a `readstate`,
+    /// by which time every message it sent before has been handled.
+    async fn handled(client: &mut TestClient) -> Vec<Message> {
+        client.send("readstate", Value::Null);
+        let answered =
+            |message: &Message| message.type_ == "state" || message.data["kind"] == "status";
+        let mut sent = Vec::new();
+        while !sent.iter().any(answered) {
+            sent.extend(client.drain());
+            tokio::task::yield_now().await;
+        }
+        sent
+    }
+
+    /// The signals among `messages`.
+    fn signals(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.type_.as_str())
+            .filter(|type_| ["offer", "answer", "candidate"].contains(type_))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn signals_reach_players_by_id_and_viewers_by_connection() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        let mut viewer = server.viewer().await;
+        let mut other = server.viewer().await;
+        let viewer_id = viewer.addr.to_string();
+
+        let sdp = json!({ "type": "offer", "sdp": "v=0" });
+        viewer.send("offer", json!({ "to": format!("{id}#1"), "sdp": sdp }));
+        let offer = player.expect("offer").await;
+        assert_eq!(
+            offer.data,
+            json!({ "from": viewer_id, "slot": 1, "sdp": sdp })
+        );
+
+        let sdp = json!({ "type": "answer", "sdp": "v=0" });
+        player.send("answer", json!({ "to": viewer_id, "slot": 1, "sdp": sdp }));
+        let answer = viewer.expect("answer").await;
+        assert_eq!(
+            answer.data,
+            json!({ "from": format!("{id}#1"), "sdp": sdp })
+        );
+
+        // Without a slot, controllers speak for their first player
+        let candidate = json!({ "candidate": "candidate:1 1 udp 1 10.0.0.2 5000 typ host" });
+        player.send(
+            "candidate",
+            json!({ "to": viewer_id, "candidate": candidate }),
+        );
+        let relayed = viewer.expect("candidate").await;
+        assert_eq!(relayed.data, json!({ "from": id, "candidate": candidate }));
+
+        // Viewers reach each other, and nobody else hears it
+        viewer.send(
+            "offer",
+            json!({ "to": other.addr.to_string(), "sdp": "v=0" }),
+        );
+        let offer = other.expect("offer").await;
+        assert_eq!(offer.data, json!({ "from": viewer_id, "sdp": "v=0" }));
+        assert!(signals(&handled(&mut player).await).is_empty());
+        assert!(signals(&handled(&mut viewer).await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn signals_cannot_claim_another_sender_or_slot() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(2).await;
+        let mut viewer = server.viewer().await;
+        let viewer_id = viewer.addr.to_string();
+
+        let forged = json!({ "to": id, "from": format!("{id}#1"), "slot": 1, "sdp": "v=0" });
+        viewer.send("offer", forged);
+        let offer = player.expect("offer").await;
+        assert_eq!(
+            offer.data,
+            json!({ "from": viewer_id, "slot": 0, "sdp": "v=0" })
+        );
+
+        // A slot the controller doesn't have
+        let before = rejected("not_player");
+        player.send(
+            "answer",
+            json!({ "to": viewer_id, "slot": 2, "sdp": "v=0" }),
+        );
+        handled(&mut player).await;
+        assert_eq!(rejected("not_player"), before + 1);
+        assert!(signals(&handled(&mut viewer).await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn signals_for_unknown_peers_go_nowhere() {
+        let mut server = Server::start(Config::default()).await;
+        let (mut player, id) = server.player(1).await;
+        let mut viewer = server.viewer().await;
+        let mut unregistered = server.connect();
+        // Sent as it connects, so not an answer to what follows
+        unregistered.expect("state").await;
+
+        let before = rejected("unknown_peer");
+        for to in [
+            "nobody".to_string(),
+            format!("{id}#1"),
+            unregistered.addr.to_string(),
+            viewer.addr.to_string(),
+        ] {
+            viewer.send("offer", json!({ "to": to, "sdp": "v=0" }));
+        }
+        // Not even the sender hears its own signal back
+        assert!(signals(&handled(&mut viewer).await).is_empty());
+        assert_eq!(rejected("unknown_peer"), before + 4);
+
+        let before = rejected("malformed");
+        viewer.send("offer", json!({ "to": id }));
+        viewer.send("candidate", json!({ "to": id, "sdp": "v=0" }));
+        viewer.send("offer", json!({ "sdp": "v=0" }));
+        viewer.send("offer", json!("v=0"));
+        handled(&mut viewer).await;
+        assert_eq!(rejected("malformed"), before + 4);
+
+        let before = rejected("not_registered");
+        unregistered.send("offer", json!({ "to": id, "sdp": "v=0" }));
+        handled(&mut unregistered).await;
+        assert_eq!(rejected("not_registered"), before + 1);
+
+        assert!(signals(&handled(&mut player).await).is_empty());
+    }
+}
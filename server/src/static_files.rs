@@ -0,0 +1,413 @@
+//! Static files for the built web frontend (the `dist/` directory from `npm
+//! run build`), with client-side routes falling back to `index.html`.
+//! Directories without an `index.html` under the configured listed dirs, like
+//! those holding maps, are listed instead.
+
+use crate::http::{Request, Response};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Vite emits content-hashed bundles under this path, so they never change.
+const IMMUTABLE_PREFIX: &str = "/assets/";
+
+/// Characters escaped in the links of a directory listing.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Serve the file under `root` that `request` asks for. Directories are only
+/// listed at or below one of the `listed` routes.
+pub async fn serve(root: &Path, listed: &[String], request: &Request) -> Response {
+    let route = request.route();
+    let Some(relative) = resolve(route) else {
+        return Response::text(400, "bad path\n");
+    };
+    let may_list = listed
+        .iter()
+        .filter_map(|dir| resolve(dir))
+        .any(|dir| relative.starts_with(dir));
+    let mut path = root.join(relative);
+    if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+        let index = path.join("index.html");
+        if may_list && !tokio::fs::try_exists(&index).await.unwrap_or(false) {
+            return match listing_response(&path, request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "failed to list directory");
+                    Response::text(500, "internal server error\n")
+                }
+            };
+        }
+        path = index;
+    }
+    match file_response(&path, request).await {
+        Ok(response) => response,
+        // Paths without an extension are routes of the single page app
+        Err(e) if e.kind() == io::ErrorKind::NotFound && is_app_route(route) => {
+            match file_response(&root.join("index.html"), request).await {
+                Ok(response) => response,
+                Err(_) => Response::not_found(),
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Response::not_found(),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "failed to read static file");
+            Response::text(500, "internal server error\n")
+        }
+    }
+}
+
+/// Turn a request path into a path relative to the static root, refusing
+/// anything that could escape it.
+pub fn resolve(route: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(route).decode_utf8().ok()?;
+    let mut components = Path::new(decoded.as_ref()).components();
+    if components.next() != Some(Component::RootDir) {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+fn is_app_route(route: &str) -> bool {
+    !route.rsplit('/').next().unwrap_or_default().contains('.')
+}
+
+/// Read `path` into a response with its content type and caching headers,
+/// or a `304` if the client's copy is still current.
+async fn file_response(path: &Path, request: &Request) -> io::Result<Response> {
+    let metadata = tokio::fs::metadata(path).await?;
+    if !metadata.is_file() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    let modified = metadata.modified().ok();
+    let secs = modified
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    let etag = format!("\"{:x}-{:x}\"", metadata.len(), secs);
+    let cache_control = if request.route().starts_with(IMMUTABLE_PREFIX) {
+        "public, max-age=31536000, immutable"
+    } else {
+        // Revalidate with the ETag so a rebuilt index.html is picked up
+        "no-cache"
+    };
+
+    let mut response = if is_fresh(request, &etag, modified) {
+        Response::new(304, "text/plain", Vec::new())
+    } else {
+        Response::new(200, content_type(path), tokio::fs::read(path).await?)
+    };
+    if let Some(modified) = modified {
+        response = response.with_header("Last-Modified", httpdate::fmt_http_date(modified));
+    }
+    Ok(response
+        .with_header("ETag", etag)
+        .with_header("Cache-Control", cache_control))
+}
+
+/// List the entries of `dir`, as JSON if the client accepts it and as an HTML
+/// page of links otherwise. Hidden files are left out.
+async fn listing_response(dir: &Path, request: &Request) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        entries.push((name, metadata.is_dir(), metadata.len()));
+    }
+    entries.sort();
+
+    let json = request
+        .header("accept")
+        .is_some_and(|accept| accept.contains("application/json"));
+    let mut response = if json {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(name, is_dir, size)| match is_dir {
+                true => serde_json::json!({ "name": name, "type": "directory" }),
+                false => serde_json::json!({ "name": name, "type": "file", "size": size }),
+            })
+            .collect();
+        Response::json(200, &entries)
+    } else {
+        Response::new(
+            200,
+            "text/html; charset=utf-8",
+            listing_page(request.route(), &entries),
+        )
+    };
+
+    // Listings are small, so their validator is a hash of the whole body
+    let mut hasher = DefaultHasher::new();
+    response.body.hash(&mut hasher);
+    let etag = format!("W/\"{:x}\"", hasher.finish());
+    if is_fresh(request, &etag, None) {
+        response = Response::new(304, "text/plain", Vec::new());
+    }
+    Ok(response
+        .with_header("ETag", etag)
+        .with_header("Cache-Control", "no-cache")
+        .with_header("Vary", "Accept"))
+}
+
+fn listing_page(route: &str, entries: &[(String, bool, u64)]) -> String {
+    let decoded = percent_decode_str(route).decode_utf8_lossy();
+    // Links are rebuilt from the decoded segments, so nothing from the request
+    // line reaches the page unescaped
+    let base: String = decoded
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{}", utf8_percent_encode(segment, PATH_SEGMENT)))
+        .collect();
+    let title = html_escape(&decoded);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+    if !base.is_empty() {
+        let parent = &base[..base.rfind('/').unwrap_or(0)];
+        page.push_str(&format!("<li><a href=\"{parent}/\">../</a></li>\n"));
+    }
+    for (name, is_dir, _) in entries {
+        let slash = if *is_dir { "/" } else { "" };
+        page.push_str(&format!(
+            "<li><a href=\"{base}/{}{slash}\">{}{slash}</a></li>\n",
+            utf8_percent_encode(name, PATH_SEGMENT),
+            html_escape(name),
+        ));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    page
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Whether the client's cached copy, described by its conditional request
+/// headers, still matches. `If-None-Match` wins over `If-Modified-Since`
+/// when both are sent.
+fn is_fresh(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(tags) = request.header("if-none-match") {
+        // Weak comparison, as for GET and HEAD
+        let opaque = etag.trim_start_matches("W/");
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == opaque);
+    }
+    let since = request
+        .header("if-modified-since")
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    match (since, modified) {
+        // HTTP dates have whole seconds
+        (Some(since), Some(modified)) => modified
+            .duration_since(since)
+            .map_or(true, |newer| newer.as_secs() == 0),
+        _ => false,
+    }
+}
+
+fn content_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let essence = mime.essence_str();
+    let textual = mime.type_() == "text"
+        || ["javascript", "json", "xml"]
+            .iter()
+            .any(|t| essence.contains(t));
+    if textual && mime.get_param("charset").is_none() {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parse_head;
+
+    fn get(path: &str) -> Request {
+        parse_head(&format!("GET {path} HTTP/1.1\r\nHost: localhost")).unwrap()
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body.clone()).unwrap()
+    }
+
+    /// A built frontend with maps and an asset directory, neither of which
+    /// has an index.html.
+    fn site() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("index.html"), "app").unwrap();
+        std::fs::create_dir_all(root.path().join("maps/extra")).unwrap();
+        std::fs::write(root.path().join("maps/forest.json"), "{}").unwrap();
+        std::fs::create_dir(root.path().join("assets")).unwrap();
+        std::fs::write(root.path().join("assets/app-1234.js"), "").unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn lists_only_configured_directories() {
+        let root = site();
+        let listed = vec!["/maps".to_string()];
+
+        for route in ["/maps/", "/maps", "/maps/extra/"] {
+            let response = serve(root.path(), &listed, &get(route)).await;
+            assert_eq!(response.status, 200, "{route}");
+            assert!(body(&response).contains("Index of"), "{route}");
+        }
+        let maps = body(&serve(root.path(), &listed, &get("/maps/")).await);
+        assert!(maps.contains("<a href=\"/maps/forest.json\">forest.json</a>"));
+        assert!(maps.contains("<a href=\"/maps/extra/\">extra/</a>"));
+
+        // Other directories fall back to the app like any client-side route
+        for route in ["/assets/", "/"] {
+            let response = serve(root.path(), &listed, &get(route)).await;
+            assert_eq!(body(&response), "app", "{route}");
+        }
+        let response = serve(root.path(), &[], &get("/maps/")).await;
+        assert_eq!(body(&response), "app");
+    }
+
+    /// `request` with `headers` added.
+    fn with(mut request: Request, headers: &[(&str, &str)]) -> Request {
+        for (name, value) in headers {
+            request.headers.push((name.to_string(), value.to_string()));
+        }
+        request
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_etags() {
+        let root = site();
+        let fetch = |headers: &[(&str, &str)]| {
+            let request = with(get("/index.html"), headers);
+            let root = root.path().to_path_buf();
+            async move { serve(&root, &[], &request).await }
+        };
+        let first = fetch(&[]).await;
+        assert_eq!((first.status, body(&first)), (200, "app".to_string()));
+        let etag = header(&first, "etag").unwrap().to_string();
+
+        let weak = format!("W/{etag}");
+        let listed = format!("\"other\", {etag}");
+        for tag in [etag.as_str(), &weak, &listed, "*"] {
+            let response = fetch(&[("If-None-Match", tag)]).await;
+            assert_eq!(response.status, 304, "{tag}");
+            assert!(response.body.is_empty());
+            assert_eq!(header(&response, "etag"), Some(etag.as_str()));
+        }
+        let response = fetch(&[("If-None-Match", "\"other\"")]).await;
+        assert_eq!((response.status, body(&response)), (200, "app".to_string()));
+    }
+
+    #[tokio::test]
+    async fn revalidates_with_dates_unless_etags_are_sent() {
+        let root = site();
+        let fetch = |headers: &[(&str, &str)]| {
+            let request = with(get("/index.html"), headers);
+            let root = root.path().to_path_buf();
+            async move { serve(&root, &[], &request).await.status }
+        };
+        let first = serve(root.path(), &[], &get("/index.html")).await;
+        let modified = header(&first, "last-modified").unwrap().to_string();
+        let etag = header(&first, "etag").unwrap().to_string();
+        let long_ago = "Thu, 01 Jan 1970 00:00:00 GMT";
+
+        assert_eq!(fetch(&[("If-Modified-Since", &modified)]).await, 304);
+        assert_eq!(fetch(&[("If-Modified-Since", long_ago)]).await, 200);
+        assert_eq!(fetch(&[("If-Modified-Since", "yesterday")]).await, 200);
+        // If-None-Match decides whenever it's sent
+        let stale_tag = [
+            ("If-None-Match", "\"other\""),
+            ("If-Modified-Since", &modified),
+        ];
+        assert_eq!(fetch(&stale_tag).await, 200);
+        let old_date = [
+            ("If-None-Match", etag.as_str()),
+            ("If-Modified-Since", long_ago),
+        ];
+        assert_eq!(fetch(&old_date).await, 304);
+    }
+
+    #[tokio::test]
+    async fn hashed_assets_are_cached_for_good() {
+        let root = site();
+        let asset = serve(root.path(), &[], &get("/assets/app-1234.js")).await;
+        assert_eq!(
+            header(&asset, "cache-control"),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(asset.content_type, "text/javascript; charset=utf-8");
+        let page = serve(root.path(), &[], &get("/index.html")).await;
+        assert_eq!(header(&page, "cache-control"), Some("no-cache"));
+        assert_eq!(page.content_type, "text/html; charset=utf-8");
+        // App routes are the index page, revalidated like it
+        let route = serve(root.path(), &[], &get("/play")).await;
+        assert_eq!(body(&route), "app");
+        assert_eq!(header(&route, "cache-control"), Some("no-cache"));
+    }
+
+    #[test]
+    fn listing_escapes_the_requested_path() {
+        let entries = [("a&b.json".to_string(), false, 2)];
+        let page = listing_page("/maps/%22%3E%3Cscript%3E&x/", &entries);
+        assert!(!page.contains("<script>"), "{page}");
+        assert!(page.contains("<title>Index of /maps/&quot;&gt;&lt;script&gt;&amp;x/</title>"));
+        assert!(page.contains("<a href=\"/maps/\">../</a>"), "{page}");
+        assert!(
+            page.contains("<a href=\"/maps/%22%3E%3Cscript%3E%26x/a%26b.json\">a&amp;b.json</a>")
+        );
+    }
+
+    #[test]
+    fn resolve_refuses_escaping_paths() {
+        assert_eq!(
+            resolve("/maps/forest.json"),
+            Some("maps/forest.json".into())
+        );
+        assert_eq!(resolve("/"), Some(PathBuf::new()));
+        assert_eq!(resolve("/maps/../../etc/passwd"), None);
+        assert_eq!(resolve("/%2e%2e/secret"), None);
+        assert_eq!(resolve("maps"), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! TLS for the `wss://` listeners and the QUIC endpoint. The certificate
+//! comes from PEM or DER files, or is generated self-signed on first use and
+//! cached in the local data directory.
+
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::{fs, io, path::Path, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+use tracing::info;
+
+/// Build the acceptor for the TLS listeners from the configured certificate.
+pub fn acceptor(config: &Config) -> Result<TlsAcceptor> {
+    let (certs, key) = certificate(config)?;
+    let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .context("invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// The configured certificate chain and key, or the self-signed one.
+pub fn certificate(
+    config: &Config,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => load_certificate(cert_path, key_path),
+        _ => self_signed_certificate(),
+    }
+}
+
+/// Read a certificate chain and private key, as DER if the file extension is
+/// `der` and as PEM otherwise.
+fn load_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let key = fs::read(key_path)
+        .with_context(|| format!("failed to read private key {}", key_path.display()))?;
+    let key = if key_path.extension().is_some_and(|x| x == "der") {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key))
+    } else {
+        rustls_pemfile::private_key(&mut &*key)
+            .context("malformed private key")?
+            .ok_or_else(|| anyhow::Error::msg("no private keys found"))?
+    };
+    let cert_chain = fs::read(cert_path)
+        .with_context(|| format!("failed to read certificate chain {}", cert_path.display()))?;
+    let cert_chain = if cert_path.extension().is_some_and(|x| x == "der") {
+        vec![CertificateDer::from(cert_chain)]
+    } else {
+        rustls_pemfile::certs(&mut &*cert_chain)
+            .collect::<Result<_, _>>()
+            .context("invalid PEM-encoded certificate")?
+    };
+    Ok((cert_chain, key))
+}
+
+/// A self-signed certificate for `localhost`, generated on first use and
+/// kept in the user's local data directory for later runs.
+fn self_signed_certificate() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let dirs = directories_next::ProjectDirs::from("org", "duckgame", "game-server")
+        .context("no home directory to keep a self-signed certificate in")?;
+    cached_self_signed(dirs.data_local_dir())
+}
+
+/// The self-signed certificate kept in `path`, generated and written there
+/// if there isn't one yet.
+fn cached_self_signed(
+    path: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let cert_path = path.join("cert.der");
+    let key_path = path.join("key.der");
+    let (cert, key) = match fs::read(&cert_path).and_then(|x| Ok((x, fs::read(&key_path)?))) {
+        Ok((cert, key)) => (
+            CertificateDer::from(cert),
+            PrivateKeyDer::try_from(key).map_err(anyhow::Error::msg)?,
+        ),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            info!(path = %path.display(), "generating self-signed certificate");
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+            let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+            let cert = cert.cert.into();
+            fs::create_dir_all(path).context("failed to create certificate directory")?;
+            fs::write(&cert_path, &cert).context("failed to write certificate")?;
+            fs::write(&key_path, key.secret_pkcs8_der()).context("failed to write private key")?;
+            (cert, key.into())
+        }
+        Err(e) => bail!("failed to read certificate: {}", e),
+    };
+    Ok((vec![cert], key))
+}
+
+/// Certificates for tests, so none is generated in the user's data
+/// directory.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// A configuration using a freshly generated certificate for
+    /// `localhost`, written as PEM files into `dir`.
+    pub fn config(dir: &Path) -> Config {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = Config {
+            tls_cert: Some(dir.join("cert.pem")),
+            tls_key: Some(dir.join("key.pem")),
+            ..Config::default()
+        };
+        fs::write(config.tls_cert.as_ref().unwrap(), generated.cert.pem()).unwrap();
+        let key = generated.key_pair.serialize_pem();
+        fs::write(config.tls_key.as_ref().unwrap(), key).unwrap();
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A certificate and key for `localhost` written into `dir` as `name.*`
+    /// in both PEM and DER.
+    fn write_certificate(dir: &Path, name: &str) -> rcgen::CertifiedKey {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let path = |extension| dir.join(format!("{name}.{extension}"));
+        fs::write(path("crt.pem"), generated.cert.pem()).unwrap();
+        fs::write(path("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+        fs::write(path("crt.der"), generated.cert.der()).unwrap();
+        fs::write(path("key.der"), generated.key_pair.serialize_der()).unwrap();
+        generated
+    }
+
+    fn config(dir: &Path, cert: &str, key: &str) -> Config {
+        Config {
+            tls_cert: Some(dir.join(cert)),
+            tls_key: Some(dir.join(key)),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn loads_pem_and_der_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let generated = write_certificate(dir.path(), "server");
+        let der = generated.key_pair.serialize_der();
+
+        for (cert, key) in [
+            ("server.crt.pem", "server.key.pem"),
+            ("server.crt.der", "server.key.der"),
+            ("server.crt.pem", "server.key.der"),
+        ] {
+            let config = config(dir.path(), cert, key);
+            let (certs, loaded) = certificate(&config).unwrap();
+            assert_eq!(certs, [generated.cert.der().clone()], "{cert}");
+            assert_eq!(loaded.secret_der(), der, "{key}");
+            assert!(acceptor(&config).is_ok(), "{cert} with {key}");
+        }
+    }
+
+    #[test]
+    fn loads_every_certificate_of_a_pem_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let leaf = write_certificate(dir.path(), "leaf");
+        let other = write_certificate(dir.path(), "other");
+        let chain = leaf.cert.pem() + &other.cert.pem();
+        fs::write(dir.path().join("chain.pem"), chain).unwrap();
+
+        let (certs, _) = certificate(&config(dir.path(), "chain.pem", "leaf.key.pem")).unwrap();
+        assert_eq!(certs, [leaf.cert.der().clone(), other.cert.der().clone()]);
+    }
+
+    #[test]
+    fn refuses_a_key_that_doesnt_match_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        write_certificate(dir.path(), "server");
+        write_certificate(dir.path(), "other");
+
+        let config = config(dir.path(), "server.crt.pem", "other.key.pem");
+        let Err(error) = acceptor(&config) else {
+            panic!("accepted another certificate's key");
+        };
+        assert_eq!(error.to_string(), "invalid TLS certificate or key");
+    }
+
+    #[test]
+    fn reports_missing_and_malformed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_certificate(dir.path(), "server");
+        fs::write(dir.path().join("empty.pem"), "").unwrap();
+        let error = |cert, key| {
+            let config = config(dir.path(), cert, key);
+            format!("{:#}", certificate(&config).unwrap_err())
+        };
+
+        assert!(error("server.crt.pem", "missing.pem").starts_with("failed to read private key"));
+        assert!(
+            error("missing.pem", "server.key.pem").starts_with("failed to read certificate chain")
+        );
+        assert_eq!(
+            error("server.crt.pem", "empty.pem"),
+            "no private keys found"
+        );
+        // A certificate isn't a key
+        assert_eq!(
+            error("server.crt.pem", "server.crt.pem"),
+            "no private keys found"
+        );
+    }
+
+    #[test]
+    fn generates_a_self_signed_certificate_once_and_reuses_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("data");
+
+        let (certs, key) = cached_self_signed(&cache).unwrap();
+        assert_eq!(fs::read(cache.join("cert.der")).unwrap(), *certs[0]);
+        assert_eq!(fs::read(cache.join("key.der")).unwrap(), key.secret_der());
+        let (again, key_again) = cached_self_signed(&cache).unwrap();
+        assert_eq!(again, certs);
+        assert_eq!(key_again.secret_der(), key.secret_der());
+
+        // A certificate without its key isn't a usable cache
+        fs::remove_file(cache.join("key.der")).unwrap();
+        let (regenerated, _) = cached_self_signed(&cache).unwrap();
+        assert_ne!(regenerated, certs);
+    }
+}
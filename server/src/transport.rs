@@ -0,0 +1,210 @@
+//! The boundary between the game and the connections clients reach it over.
+//! A transport turns whatever arrives on its connection into [`Incoming`]
+//! protocol messages and delivers the server's messages back, so the session
+//! logic in [`crate::session`] never sees websocket frames or QUIC streams.
+
+use crate::game_state::{Message, Role};
+use anyhow::Result;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Identifies one connected client. Unlike its address, it stays unique when
+/// clients on different transports share an IP address and port number.
+pub type ClientId = u64;
+
+/// Messages waiting to be written to a client, at most. A client that falls
+/// further behind is disconnected.
+pub const OUTGOING_QUEUE: usize = 512;
+
+/// Who is on the other end of a connection.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub id: ClientId,
+    pub addr: SocketAddr,
+    /// Player id of the connection's first local player. Viewers are
+    /// addressed by it too.
+    pub player_id: String,
+    /// The `Host` the client connected to, if it said, so links sent to it
+    /// lead back the same way.
+    pub host: Option<String>,
+    /// Whether the connection is encrypted.
+    pub secure: bool,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, player_id: String) -> Peer {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Peer {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            player_id,
+            host: None,
+            secure: false,
+        }
+    }
+}
+
+/// Something a client sent.
+pub enum Incoming {
+    /// A protocol message.
+    Message(Message),
+    /// Something that isn't a protocol message, counted as rejected with
+    /// this reason.
+    Invalid(&'static str),
+    /// The answer to a ping.
+    Pong,
+}
+
+/// The receiving half of a connection.
+pub trait Inbound: Send {
+    /// Wait for the next thing the client sends. Returns `None` once the
+    /// connection is closed.
+    fn recv(&mut self) -> BoxFuture<'_, Option<Incoming>>;
+}
+
+/// The sending half of a connection, kept in the client list so any part of
+/// the server can reach the client. Nothing here waits on the client: callers
+/// hold the client list while sending, so transports queue what they're given
+/// for a writer of their own.
+pub trait Outbound: Send {
+    /// Queue a serialized protocol message. Fails if the connection is gone
+    /// or the client has fallen too far behind.
+    fn send(&mut self, text: &str) -> Result<()>;
+
+    /// Ask the client for an [`Incoming::Pong`] to time the round trip.
+    fn ping(&mut self) -> Result<()>;
+
+    /// The round-trip time, for transports that measure it themselves and
+    /// don't need pings.
+    fn rtt(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Where the client is now, for transports whose connections survive the
+    /// client changing address. Others stay at [`Peer::addr`].
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Told the role the client registered as, for transports that treat
+    /// roles differently.
+    fn set_role(&mut self, _role: Role) {}
+
+    /// Close the connection once what's queued has been sent, ending its
+    /// [`Inbound`].
+    fn close(&mut self);
+}
+
+/// A client connection over any transport.
+pub struct Connection {
+    pub peer: Peer,
+    pub inbound: Box<dyn Inbound>,
+    pub outbound: Box<dyn Outbound>,
+}
+
+/// A connection over in-memory channels, for driving sessions from tests.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{sync::mpsc, time};
+
+    /// The client's end of a test connection.
+    pub struct TestClient {
+        pub id: ClientId,
+        pub addr: SocketAddr,
+        incoming: Option<mpsc::UnboundedSender<Incoming>>,
+        sent: mpsc::UnboundedReceiver<String>,
+    }
+
+    impl TestClient {
+        /// Send a protocol message to the server.
+        pub fn send(&self, type_: &str, data: serde_json::Value) {
+            let message = Message {
+                type_: type_.to_string(),
+                data,
+            };
+            if let Some(incoming) = &self.incoming {
+                let _ = incoming.send(Incoming::Message(message));
+            }
+        }
+
+        /// The next message of type `type_` the server sends, skipping
+        /// others. Panics if none arrives within a second.
+        pub async fn expect(&mut self, type_: &str) -> Message {
+            let wait = async {
+                while let Some(text) = self.sent.recv().await {
+                    let message: Message = serde_json::from_str(&text).unwrap();
+                    if message.type_ == type_ {
+                        return message;
+                    }
+                }
+                panic!("connection closed waiting for `{type_}`");
+            };
+            time::timeout(Duration::from_secs(1), wait)
+                .await
+                .unwrap_or_else(|_| panic!("no `{type_}` message"))
+        }
+
+        /// Every message the server has sent so far and not yet taken.
+        pub fn drain(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            while let Ok(text) = self.sent.try_recv() {
+                messages.push(serde_json::from_str(&text).unwrap());
+            }
+            messages
+        }
+
+        /// Close the connection from the client's side.
+        pub fn disconnect(&mut self) {
+            self.incoming = None;
+        }
+    }
+
+    /// A connection from `addr` and the client's end of it.
+    pub fn connection(addr: SocketAddr) -> (Connection, TestClient) {
+        let (incoming, inbound) = mpsc::unbounded_channel();
+        let (outbound, sent) = mpsc::unbounded_channel();
+        let connection = Connection {
+            peer: Peer::new(addr, addr.to_string()),
+            inbound: Box::new(ChannelInbound(inbound)),
+            outbound: Box::new(ChannelOutbound(outbound)),
+        };
+        let client = TestClient {
+            id: connection.peer.id,
+            addr,
+            incoming: Some(incoming),
+            sent,
+        };
+        (connection, client)
+    }
+
+    struct ChannelInbound(mpsc::UnboundedReceiver<Incoming>);
+
+    impl Inbound for ChannelInbound {
+        fn recv(&mut self) -> BoxFuture<'_, Option<Incoming>> {
+            Box::pin(self.0.recv())
+        }
+    }
+
+    struct ChannelOutbound(mpsc::UnboundedSender<String>);
+
+    impl Outbound for ChannelOutbound {
+        fn send(&mut self, text: &str) -> Result<()> {
+            Ok(self.0.send(text.to_string())?)
+        }
+
+        fn ping(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) {}
+    }
+}
@@ -1,140 +1,287 @@
-use crate::game_state::{GameState, Message};
-use crate::print_received_message;
-use serde_json::Value;
-
-use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::{net::TcpStream, sync::Mutex};
-use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
-
-// Global state for connected clients (each client sender mapped by their SocketAddr)
-lazy_static::lazy_static! {
-    static ref CLIENTS: Mutex<HashMap<SocketAddr, futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, WsMessage>>> = Mutex::new(HashMap::new());
-}
+//! Websocket transport: the handshake, and text frames carrying protocol
+//! messages in both directions, compressed with [`deflate`] when both ends
+//! agree to it.
+
+use crate::config::ConfigWatch;
+use crate::deflate::{self, Deflate, InflateStream};
+use crate::game_state::{Message, Role, SharedPlayers};
+use crate::http::{BoxedStream, Rewind};
+use crate::transport::{BoxFuture, Connection, Inbound, Incoming, Outbound, Peer, OUTGOING_QUEUE};
+use crate::{metrics, session};
+use anyhow::{anyhow, Result};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::SEC_WEBSOCKET_EXTENSIONS, HeaderValue, StatusCode},
+        protocol::{
+            frame::coding::{Data, OpCode},
+            frame::Frame,
+            WebSocketConfig,
+        },
+        Message as WsMessage,
+    },
+    WebSocketStream,
+};
+use tracing::{debug, instrument, warn, Instrument};
+
+/// The TCP or TLS stream of a client, with the handshake request the router
+/// already read from it put back in front.
+pub type ClientStream = Rewind<BoxedStream>;
 
+type ClientWebSocket = WebSocketStream<InflateStream<ClientStream>>;
+
+#[instrument(
+    name = "connection",
+    skip_all,
+    fields(player_id = %peer.player_id, role = tracing::field::Empty)
+)]
 pub async fn handle_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
-    players_state: Arc<Mutex<HashMap<String, GameState>>>,
-    player_id: String,
+    stream: ClientStream,
+    peer: Peer,
+    players_state: SharedPlayers,
+    config: ConfigWatch,
 ) -> Result<()> {
-    let mut is_player = false;
-    let ws_stream = accept_async(stream)
-        .await
-        .expect("Failed to accept websocket");
-
-    let (ws_sender, mut ws_receiver) = ws_stream.split();
-
-    // Store the sender part of the websocket
-    CLIENTS.lock().await.insert(addr, ws_sender);
-
-    // Send the initial global state
-    let state = players_state.lock().await;
-    let msg = serde_json::to_string(&Message {
-        type_: "state".to_string(),
-        data: serde_json::to_value(&*state).unwrap(),
-    })
-    .unwrap();
-    broadcast_message(&msg).await;
-    drop(state);
-
-    // Handle incoming messages
-    while let Some(result) = ws_receiver.next().await {
-        match result {
-            Ok(msg) => {
-                if let WsMessage::Text(text) = msg {
-                    if let Ok(message) = serde_json::from_str::<Message>(&text) {
-                        // Log received messages in a compact format
-                        match message.type_.as_str() {
-                            "register" => {
-                                if let Ok(data) =
-                                    serde_json::from_value::<Value>(message.data.clone())
-                                {
-                                    if let Some(role) = data.get("role").and_then(|r| r.as_str()) {
-                                        is_player = role == "player";
-                                        println!(
-                                            "Register {}: {} ({})",
-                                            if is_player { "player" } else { "viewer" },
-                                            addr,
-                                            role
-                                        );
-
-                                        // Only create game state for players
-                                        if is_player {
-                                            let mut players = players_state.lock().await;
-                                            players.insert(
-                                                player_id.clone(),
-                                                GameState::new_default(),
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                            "action" => {
-                                // Only process actions from players
-                                if is_player {
-                                    let mut players = players_state.lock().await;
-                                    if let Some(state) = players.get_mut(&player_id) {
-                                        if let Ok(action) = serde_json::from_value(message.data) {
-                                            *state = action;
-                                            let state_msg = serde_json::to_string(&Message {
-                                                type_: "state".to_string(),
-                                                data: serde_json::to_value(&*players).unwrap(),
-                                            })
-                                            .unwrap();
-                                            broadcast_message(&state_msg).await;
-                                        }
-                                    }
-                                }
-                            }
-                            "readstate" => {
-                                let state = players_state.lock().await;
-                                let state_msg = serde_json::to_string(&Message {
-                                    type_: "state".to_string(),
-                                    data: serde_json::to_value(&*state).unwrap(),
-                                })
-                                .unwrap();
-                                broadcast_message(&state_msg).await;
-                            }
-                            _ => println!("Unknown message type from {}: {}", addr, message.type_),
-                        }
-                    }
+    let current = config.borrow().clone();
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(current.max_message_size),
+        max_frame_size: Some(current.max_message_size),
+        ..WebSocketConfig::default()
+    };
+    let allowed_origins = &current.allowed_origins;
+    let agreement = Arc::new(OnceLock::new());
+    #[allow(clippy::result_large_err)] // The error type is tungstenite's
+    let check_origin = |request: &Request, mut response: Response| {
+        let origin = request
+            .headers()
+            .get("origin")
+            .and_then(|o| o.to_str().ok());
+        if origin_allowed(origin, allowed_origins) {
+            if current.websocket_compression {
+                let offers = request
+                    .headers()
+                    .get_all(SEC_WEBSOCKET_EXTENSIONS)
+                    .iter()
+                    .filter_map(|offer| offer.to_str().ok());
+                if let Some(accepted) = deflate::negotiate(offers) {
+                    let header = HeaderValue::from_str(&accepted.header())
+                        .expect("extension header is valid");
+                    response
+                        .headers_mut()
+                        .insert(SEC_WEBSOCKET_EXTENSIONS, header);
+                    let _ = agreement.set(accepted);
                 }
             }
-            Err(e) => {
-                println!("Error receiving message for {}: {}", addr, e);
-                break;
+            Ok(response)
+        } else {
+            warn!(
+                origin = origin.unwrap_or_default(),
+                "refusing websocket from origin"
+            );
+            metrics::reject_connection("origin");
+            let mut response = ErrorResponse::new(Some("origin not allowed".to_string()));
+            *response.status_mut() = StatusCode::FORBIDDEN;
+            Err(response)
+        }
+    };
+    let stream = InflateStream::new(stream, agreement.clone(), current.max_message_size);
+    let handshake = accept_hdr_async_with_config(stream, check_origin, Some(ws_config));
+    let ws_stream = match tokio::time::timeout(current.handshake_timeout, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
+            debug!(error = %e, "websocket handshake failed");
+            return Ok(());
+        }
+        Err(_) => {
+            debug!("websocket handshake timed out");
+            metrics::reject_connection("handshake_timeout");
+            return Ok(());
+        }
+    };
+
+    let (sender, receiver) = ws_stream.split();
+    let (outgoing, queue) = mpsc::channel(OUTGOING_QUEUE);
+    tokio::spawn(write_messages(sender, queue).in_current_span());
+    let connection = Connection {
+        peer,
+        inbound: Box::new(WebSocketInbound(receiver)),
+        outbound: Box::new(WebSocketOutbound {
+            outgoing,
+            deflate: agreement.get().copied().map(Deflate::new),
+            role: Role::default(),
+            config: config.clone(),
+        }),
+    };
+    session::serve(connection, players_state, config).await;
+    Ok(())
+}
+
+/// Whether a websocket with this `Origin` header may connect. Clients that
+/// send no origin aren't browsers, so cross-site requests aren't a concern.
+fn origin_allowed(origin: Option<&str>, allowed: &[String]) -> bool {
+    match origin {
+        _ if allowed.is_empty() => true,
+        None => true,
+        Some(origin) => allowed
+            .iter()
+            .any(|a| a.trim_end_matches('/').eq_ignore_ascii_case(origin)),
+    }
+}
+
+struct WebSocketInbound(SplitStream<ClientWebSocket>);
+
+impl Inbound for WebSocketInbound {
+    fn recv(&mut self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async move {
+            loop {
+                let incoming = match self.0.next().await? {
+                    Ok(WsMessage::Text(text)) => match serde_json::from_str::<Message>(&text) {
+                        Ok(message) => Incoming::Message(message),
+                        Err(_) => Incoming::Invalid("malformed"),
+                    },
+                    Ok(WsMessage::Binary(_)) => Incoming::Invalid("binary"),
+                    Ok(WsMessage::Pong(_)) => Incoming::Pong,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!(error = %e, "error receiving message");
+                        return None;
+                    }
+                };
+                return Some(incoming);
             }
+        })
+    }
+}
+
+/// Messages for a websocket client, queued for its writer task so a slow
+/// client never holds up sends to everyone else.
+struct WebSocketOutbound {
+    outgoing: mpsc::Sender<Outgoing>,
+    /// Set if the client agreed to compression.
+    deflate: Option<Deflate>,
+    role: Role,
+    config: ConfigWatch,
+}
+
+impl WebSocketOutbound {
+    /// `text` as a compressed frame, if the client takes compression and the
+    /// configuration wants it compressed.
+    fn compressed(&mut self, text: &str) -> Result<Option<Frame>> {
+        let Some(deflate) = &mut self.deflate else {
+            return Ok(None);
+        };
+        let (threshold, for_role) = {
+            let config = self.config.borrow();
+            let for_role = config.compression_roles.contains(&self.role);
+            (config.compression_threshold, for_role)
+        };
+        if !for_role {
+            metrics::DEFLATE_SKIPPED.with_label_values(&["role"]).inc();
+            return Ok(None);
         }
+        if text.len() < threshold {
+            metrics::DEFLATE_SKIPPED.with_label_values(&["small"]).inc();
+            return Ok(None);
+        }
+        let mut frame = Frame::message(
+            deflate.compress(text.as_bytes())?,
+            OpCode::Data(Data::Text),
+            true,
+        );
+        frame.header_mut().rsv1 = true;
+        Ok(Some(frame))
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> Result<()> {
+        self.outgoing.try_send(outgoing).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => anyhow!("client isn't keeping up"),
+            mpsc::error::TrySendError::Closed(_) => anyhow!("connection closed"),
+        })
     }
+}
 
-    // Clean up when client disconnects
-    CLIENTS.lock().await.remove(&addr);
-    println!("Client {} disconnected", addr);
+impl Outbound for WebSocketOutbound {
+    fn send(&mut self, text: &str) -> Result<()> {
+        // Compressed here rather than by the writer, since the compressor's
+        // window must see messages in the order they're queued
+        let message = match self.compressed(text)? {
+            Some(frame) => WsMessage::Frame(frame),
+            None => WsMessage::Text(text.to_string()),
+        };
+        self.queue(Outgoing::Message(message))
+    }
 
-    // Remove the player's state from the shared players map only if they were a player
-    if is_player {
-        let mut players = players_state.lock().await;
-        players.remove(&player_id);
+    fn ping(&mut self) -> Result<()> {
+        self.queue(Outgoing::Message(WsMessage::Ping(Vec::new())))
     }
 
-    Ok(())
+    fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    fn close(&mut self) {
+        // If the queue is full or the writer is gone, dropping the sender
+        // along with this outbound ends the writer anyway
+        let _ = self.queue(Outgoing::Close);
+    }
 }
 
-// Helper function to broadcast a message to all clients
-pub async fn broadcast_message(message: &str) {
-    let mut clients = CLIENTS.lock().await;
-    let mut failed_clients = Vec::new();
+enum Outgoing {
+    Message(WsMessage),
+    /// Close the websocket once the client has everything sent before.
+    Close,
+}
 
-    for (&addr, sender) in clients.iter_mut() {
-        if let Err(e) = sender.send(WsMessage::Text(message.to_string())).await {
-            println!("Failed to send to client {}: {}", addr, e);
-            failed_clients.push(addr);
+/// Write a client's queued messages to its websocket until it closes.
+async fn write_messages(
+    mut sink: SplitSink<ClientWebSocket, WsMessage>,
+    mut queue: mpsc::Receiver<Outgoing>,
+) {
+    while let Some(outgoing) = queue.recv().await {
+        let message = match outgoing {
+            Outgoing::Message(message) => message,
+            Outgoing::Close => break,
+        };
+        if let Err(e) = sink.send(message).await {
+            debug!(error = %e, "failed to send to websocket client");
+            return;
         }
     }
-    for addr in failed_clients {
-        clients.remove(&addr);
-        println!("Removed disconnected client {}", addr);
+    let _ = sink.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_must_be_listed_when_any_are() {
+        let allowed = vec![
+            "https://duck.example".to_string(),
+            "http://localhost:8080/".to_string(),
+        ];
+        assert!(origin_allowed(Some("https://duck.example"), &allowed));
+        assert!(origin_allowed(Some("HTTPS://Duck.Example"), &allowed));
+        assert!(origin_allowed(Some("http://localhost:8080"), &allowed));
+
+        assert!(!origin_allowed(Some("http://duck.example"), &allowed));
+        assert!(!origin_allowed(Some("https://duck.example:8443"), &allowed));
+        assert!(!origin_allowed(Some("http://localhost:3000"), &allowed));
+        assert!(!origin_allowed(Some("https://evil.example"), &allowed));
+        assert!(!origin_allowed(Some("null"), &allowed));
+    }
+
+    #[test]
+    fn clients_without_an_origin_and_unrestricted_servers_are_allowed() {
+        let allowed = vec!["https://duck.example".to_string()];
+        assert!(origin_allowed(None, &allowed));
+        assert!(origin_allowed(Some("https://anywhere.example"), &[]));
+        assert!(origin_allowed(None, &[]));
     }
 }
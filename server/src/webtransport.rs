@@ -0,0 +1,332 @@
+//! WebTransport sessions for browser controllers, which can't open raw QUIC
+//! connections. HTTP/3 is only spoken to set up the session; after that its
+//! streams and datagrams carry the same requests and inputs as a controller
+//! connection in [`quic`], behind the headers WebTransport adds to them.
+
+use crate::config::ConfigWatch;
+use crate::game_state::SharedPlayers;
+use crate::metrics;
+use crate::quic::{self, Framing};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use h3::{ext::Protocol, server::RequestStream};
+use http::{Method, StatusCode};
+use tokio::time;
+use tracing::debug;
+
+/// ALPN protocol of HTTP/3, which WebTransport runs over.
+pub const ALPN: &[u8] = b"h3";
+
+/// Path browsers open sessions on, e.g. `https://host:4433/wt`.
+pub const PATH: &str = "/wt";
+
+/// Unidirectional streams a client may open: the HTTP/3 control stream and
+/// the QPACK encoder and decoder streams.
+pub const UNI_STREAMS: u8 = 3;
+
+/// Stream type that starts every bidirectional WebTransport stream.
+const BIDI_STREAM: u64 = 0x41;
+
+/// Stream type that starts every unidirectional WebTransport stream.
+const UNI_STREAM: u64 = 0x54;
+
+type H3Connection = h3::server::Connection<h3_quinn::Connection, Bytes>;
+type SessionStream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Set up a WebTransport session on an HTTP/3 connection and serve it as a
+/// controller. One session is allowed per connection.
+pub async fn handle_connection(
+    connection: quinn::Connection,
+    players_state: SharedPlayers,
+    config: ConfigWatch,
+) {
+    let timeout = config.borrow().handshake_timeout;
+    let accept = accept_session(connection.clone());
+    // The HTTP/3 connection is kept but never polled again. Polling it would
+    // take the session's bidirectional streams as HTTP requests before
+    // [`quic`] could read them as WebTransport streams, and dropping it
+    // would close its control stream, which ends the connection. Nothing
+    // after the handshake needs it: any other request the browser sends
+    // arrives as a stream [`quic`] refuses for lacking a WebTransport
+    // header, and the end of the session is seen on the CONNECT stream.
+    let (_h3, mut session) = match time::timeout(timeout, accept).await {
+        Ok(Ok(Some(session))) => session,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            debug!(error = %format!("{e:#}"), "WebTransport handshake failed");
+            return;
+        }
+        Err(_) => {
+            debug!("WebTransport handshake timed out");
+            metrics::reject_connection("handshake_timeout");
+            return;
+        }
+    };
+    // Sessions are identified by the id of the stream that opened them
+    let session_id = session.id().into_inner();
+
+    // The session ends when the browser finishes the CONNECT stream
+    let closing = connection.clone();
+    tokio::spawn(async move {
+        while let Ok(Some(_)) = session.recv_data().await {}
+        closing.close(0u32.into(), b"session closed");
+    });
+
+    // The HTTP/3 connection stays alive alongside the session
+    quic::handle_controller(
+        connection,
+        players_state,
+        config,
+        Framing::WebTransport(session_id),
+    )
+    .await;
+}
+
+/// Wait for the extended CONNECT request that opens a session and accept it,
+/// answering any other request with a 404.
+async fn accept_session(
+    connection: quinn::Connection,
+) -> Result<Option<(H3Connection, SessionStream)>> {
+    let mut h3 = h3::server::builder()
+        .enable_webtransport(true)
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .max_webtransport_sessions(1)
+        .build(h3_quinn::Connection::new(connection))
+        .await?;
+    while let Some(resolver) = h3.accept().await? {
+        let (request, mut stream) = resolver.resolve_request().await?;
+        let is_session = request.method() == Method::CONNECT
+            && request.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT);
+        if is_session && request.uri().path() == PATH {
+            let response = http::Response::builder()
+                .status(StatusCode::OK)
+                // Older browsers only accept sessions from draft-02 servers
+                .header("sec-webtransport-http3-draft", "draft02")
+                .body(())?;
+            stream.send_response(response).await?;
+            return Ok(Some((h3, stream)));
+        }
+        debug!(method = %request.method(), uri = %request.uri(), "not a WebTransport session");
+        let response = http::Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(())?;
+        stream.send_response(response).await?;
+        stream.finish().await?;
+    }
+    Ok(None)
+}
+
+/// Read the header of a bidirectional WebTransport stream, failing unless
+/// the stream belongs to `session`.
+pub async fn read_stream_header(recv: &mut quinn::RecvStream, session: u64) -> Result<()> {
+    let kind = read_varint(recv).await?;
+    let id = read_varint(recv).await?;
+    if kind != BIDI_STREAM || id != session {
+        bail!("not a stream of this WebTransport session");
+    }
+    Ok(())
+}
+
+/// The header that makes a unidirectional stream part of `session`.
+pub fn uni_stream_header(session: u64) -> Vec<u8> {
+    let mut header = Vec::new();
+    put_varint(&mut header, UNI_STREAM);
+    put_varint(&mut header, session);
+    header
+}
+
+/// The payload of a datagram sent on `session`. WebTransport datagrams start
+/// with the session's quarter stream id.
+pub fn datagram_payload(datagram: &[u8], session: u64) -> Option<&[u8]> {
+    let (&first, _) = datagram.split_first()?;
+    let len = varint_len(first);
+    let id = decode_varint(datagram.get(..len)?);
+    (id == session / 4).then(|| &datagram[len..])
+}
+
+async fn read_varint(recv: &mut quinn::RecvStream) -> Result<u64> {
+    let mut buf = [0; 8];
+    recv.read_exact(&mut buf[..1])
+        .await
+        .context("failed reading stream header")?;
+    let len = varint_len(buf[0]);
+    recv.read_exact(&mut buf[1..len])
+        .await
+        .context("failed reading stream header")?;
+    Ok(decode_varint(&buf[..len]))
+}
+
+fn put_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(0x4000 | value as u16).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(0x8000_0000 | value as u32).to_be_bytes()),
+        _ => buf.extend_from_slice(&(0xc000_0000_0000_0000 | value).to_be_bytes()),
+    }
+}
+
+/// Length of a QUIC variable-length integer from its first byte.
+fn varint_len(first: u8) -> usize {
+    1 << (first >> 6)
+}
+
+fn decode_varint(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |value, (i, &byte)| {
+        let byte = if i == 0 { byte & 0x3f } else { byte };
+        value << 8 | u64::from(byte)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::test::Server;
+    use crate::tls;
+    use serde_json::{json, Value};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn varints_use_the_shortest_encoding() {
+        let cases: [(u64, usize); 8] = [
+            (0, 1),
+            (0x3f, 1),
+            (0x40, 2),
+            (0x3fff, 2),
+            (0x4000, 4),
+            (0x3fff_ffff, 4),
+            (0x4000_0000, 8),
+            ((1 << 62) - 1, 8),
+        ];
+        for (value, len) in cases {
+            let mut encoded = Vec::new();
+            put_varint(&mut encoded, value);
+            assert_eq!(encoded.len(), len, "{value:#x}");
+            assert_eq!(varint_len(encoded[0]), len, "{value:#x}");
+            assert_eq!(decode_varint(&encoded), value, "{value:#x}");
+        }
+        // The examples of RFC 9000, appendix A.1
+        let decoded = decode_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]);
+        assert_eq!(decoded, 151_288_809_941_952_652);
+        assert_eq!(decode_varint(&[0x9d, 0x7f, 0x3e, 0x7d]), 494_878_333);
+        assert_eq!(decode_varint(&[0x7b, 0xbd]), 15_293);
+        assert_eq!(decode_varint(&[0x40, 0x25]), 37);
+    }
+
+    #[test]
+    fn uni_stream_headers_name_the_session() {
+        assert_eq!(uni_stream_header(0), [0x40, 0x54, 0x00]);
+        assert_eq!(uni_stream_header(100), [0x40, 0x54, 0x40, 0x64]);
+    }
+
+    #[test]
+    fn datagram_payloads_follow_the_quarter_session_id() {
+        assert_eq!(datagram_payload(b"\x01{}", 4), Some(&b"{}"[..]));
+        assert_eq!(datagram_payload(&[0x40, 0x19, b'x'], 100), Some(&b"x"[..]));
+        assert_eq!(datagram_payload(b"\x00", 0), Some(&b""[..]));
+        // Another session's, or too short to hold the id
+        assert_eq!(datagram_payload(b"\x02{}", 4), None);
+        assert_eq!(datagram_payload(&[0x80, 0x00, 0x00], 4), None);
+        assert_eq!(datagram_payload(b"", 4), None);
+    }
+
+    /// A client endpoint trusting the certificate of `config`.
+    fn client(config: &crate::config::Config) -> quinn::Endpoint {
+        let pem = std::fs::read(config.tls_cert.as_ref().unwrap()).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &*pem) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn sessions_carry_requests_and_inputs_past_http3() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = tls::test::config(dir.path());
+        let server = Server::start(config.clone()).await;
+        let endpoint = quic::endpoint(&config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let players = server.players.clone();
+        tokio::spawn(quic::accept_connections(
+            endpoint,
+            players,
+            server.config.clone(),
+        ));
+
+        let connection = client(&config)
+            .connect(addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        // HTTP/3 needs its unidirectional streams
+        let (mut driver, mut send_request) = h3::client::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build::<_, _, Bytes>(h3_quinn::Connection::new(connection.clone()))
+            .await
+            .unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+        let connect = http::Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!("https://localhost{PATH}"))
+            .extension(Protocol::WEB_TRANSPORT)
+            .body(())
+            .unwrap();
+        let mut session = send_request.send_request(connect).await.unwrap();
+        let response = session.recv_response().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let session_id = session.id().into_inner();
+
+        // A stream the unpolled HTTP/3 connection would otherwise have taken
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let mut request = Vec::new();
+        put_varint(&mut request, BIDI_STREAM);
+        put_varint(&mut request, session_id);
+        let register = json!({ "type": "register", "data": { "role": "player" } });
+        request.extend_from_slice(register.to_string().as_bytes());
+        send.write_all(&request).await.unwrap();
+        send.finish().unwrap();
+        let replies = recv.read_to_end(64 * 1024).await.unwrap();
+        let player_id = String::from_utf8(replies)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .find_map(|reply| reply["data"]["player_id"].as_str().map(str::to_string))
+            .expect("player wasn't assigned");
+
+        let mut datagram = Vec::new();
+        put_varint(&mut datagram, session_id / 4);
+        let input = json!({
+            "seq": 1,
+            "joystick": { "x": 0.5, "y": 0.0 },
+            "buttons": { "a": false, "b": false, "x": false, "y": false },
+        });
+        datagram.extend_from_slice(input.to_string().as_bytes());
+        connection.send_datagram(datagram.into()).unwrap();
+        let applied = async {
+            while server.players.lock().await[&player_id].joystick.x != 0.5 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(1), applied)
+            .await
+            .expect("input never arrived");
+
+        // Finishing the CONNECT stream ends the session and the connection
+        session.finish().await.unwrap();
+        time::timeout(Duration::from_secs(1), connection.closed())
+            .await
+            .expect("connection outlived its session");
+    }
+}
@@ -0,0 +1,300 @@
+//! A QUIC controller that moves to a new address mid-game, as when a NAT
+//! rebinds its port, keeps playing as the same player: viewers never see it
+//! leave and rejoin, and its input keeps arriving.
+
+use futures_util::{SinkExt, StreamExt};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeSet,
+    fs,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    process::{Child, Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+
+const ADMIN_TOKEN: &str = "migration-test";
+
+#[tokio::test]
+async fn rebinding_keeps_the_player() {
+    let server = Server::start().await;
+
+    let endpoint = client_endpoint();
+    let connection = endpoint
+        .connect(server.quic, "localhost")
+        .unwrap()
+        .await
+        .expect("QUIC handshake failed");
+    let replies = request(
+        &connection,
+        json!({ "type": "register", "data": { "role": "player" } }),
+    )
+    .await;
+    let player_id = replies
+        .iter()
+        .find(|reply| reply["data"]["kind"] == "assigned")
+        .and_then(|reply| reply["data"]["player_id"].as_str())
+        .expect("player wasn't assigned")
+        .to_string();
+
+    let (mut viewer, _) = connect_async(format!("ws://{}/", server.game))
+        .await
+        .unwrap();
+    let register = json!({ "type": "register", "data": { "role": "viewer" } });
+    viewer
+        .send(WsMessage::Text(register.to_string()))
+        .await
+        .unwrap();
+
+    let mut seq = 0;
+    let mut addrs = BTreeSet::new();
+    for (phase, x) in [0.25, -0.5, 0.75].into_iter().enumerate() {
+        if phase > 0 {
+            endpoint
+                .rebind(UdpSocket::bind("127.0.0.1:0").unwrap())
+                .unwrap();
+        }
+        addrs.insert(endpoint.local_addr().unwrap().port());
+        for _ in 0..20 {
+            seq += 1;
+            let input = json!({
+                "seq": seq,
+                "joystick": { "x": x, "y": 0.0 },
+                "buttons": { "a": false, "b": false, "x": false, "y": false },
+            });
+            connection
+                .send_datagram(input.to_string().into_bytes().into())
+                .unwrap();
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Every state on the way holds exactly the one player
+        let wait_for_input = async {
+            while let Some(Ok(message)) = viewer.next().await {
+                let WsMessage::Text(text) = message else {
+                    continue;
+                };
+                let message: Value = serde_json::from_str(&text).unwrap();
+                if message["type"] != "state" {
+                    continue;
+                }
+                let players: Vec<_> = message["data"].as_object().unwrap().keys().collect();
+                assert_eq!(players, [&player_id], "player churned in phase {phase}");
+                if message["data"][&player_id]["joystick"]["x"] == x {
+                    return;
+                }
+            }
+            panic!("viewer disconnected");
+        };
+        time::timeout(Duration::from_secs(5), wait_for_input)
+            .await
+            .unwrap_or_else(|_| panic!("input from phase {phase} never arrived"));
+
+        // The server reports the client where it is now
+        let clients = server.admin_clients().await;
+        let quic_clients: Vec<_> = clients
+            .iter()
+            .filter(|client| client["role"] == "player")
+            .collect();
+        assert_eq!(quic_clients.len(), 1);
+        let addr: SocketAddr = quic_clients[0]["addr"].as_str().unwrap().parse().unwrap();
+        assert_eq!(addr.port(), endpoint.local_addr().unwrap().port());
+        assert_eq!(quic_clients[0]["players"][0]["id"], player_id.as_str());
+    }
+    assert_eq!(addrs.len(), 3, "client didn't change address");
+
+    let metrics = server.metrics().await;
+    assert!(
+        metrics
+            .lines()
+            .any(|line| line == "duckgame_quic_migrations_total 2"),
+        "migrations not counted:\n{metrics}"
+    );
+    connection.close(0u32.into(), b"done");
+}
+
+/// The game server binary, killed when dropped.
+struct Server {
+    child: Child,
+    /// Holds the server's certificate, so none is generated in the user's
+    /// data directory.
+    _certificate: tempfile::TempDir,
+    game: SocketAddr,
+    quic: SocketAddr,
+    metrics: SocketAddr,
+}
+
+impl Server {
+    async fn start() -> Server {
+        let game = free_tcp_addr();
+        let metrics = free_tcp_addr();
+        let quic = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let certificate = tempfile::tempdir().unwrap();
+        let cert_path = certificate.path().join("cert.der");
+        let key_path = certificate.path().join("key.der");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        fs::write(&cert_path, cert.cert.der()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_der()).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_game-server"))
+            .args(["--listen", &game.to_string()])
+            .args(["--quic-listen", &quic.to_string()])
+            .arg("--tls-cert")
+            .arg(&cert_path)
+            .arg("--tls-key")
+            .arg(&key_path)
+            .args(["--metrics-listen", &metrics.to_string()])
+            .args(["--admin-token", ADMIN_TOKEN])
+            .args(["--log-level", "warn"])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        let server = Server {
+            child,
+            _certificate: certificate,
+            game,
+            quic,
+            metrics,
+        };
+        for _ in 0..100 {
+            if get(game, "/healthz", None).await.is_some() {
+                return server;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start");
+    }
+
+    async fn admin_clients(&self) -> Vec<Value> {
+        let body = get(self.game, "/admin/clients", Some(ADMIN_TOKEN))
+            .await
+            .expect("admin API unavailable");
+        let status: Value = serde_json::from_str(&body).unwrap();
+        status["clients"].as_array().unwrap().clone()
+    }
+
+    async fn metrics(&self) -> String {
+        get(self.metrics, "/metrics", None)
+            .await
+            .expect("metrics unavailable")
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_tcp_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// The body of a successful `GET`, or `None` if the request failed.
+async fn get(addr: SocketAddr, path: &str, token: Option<&str>) -> Option<String> {
+    let mut stream = TcpStream::connect(addr).await.ok()?;
+    let authorization = token
+        .map(|token| format!("Authorization: Bearer {token}\r\n"))
+        .unwrap_or_default();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n{authorization}\r\n");
+    stream.write_all(request.as_bytes()).await.ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    head.starts_with("HTTP/1.1 200").then(|| body.to_string())
+}
+
+/// Send one request on a new stream and collect the replies.
+async fn request(connection: &quinn::Connection, message: Value) -> Vec<Value> {
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    send.write_all(message.to_string().as_bytes())
+        .await
+        .unwrap();
+    send.finish().unwrap();
+    let replies = recv.read_to_end(64 * 1024).await.unwrap();
+    String::from_utf8(replies)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn client_endpoint() -> quinn::Endpoint {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"duckgame".to_vec()];
+    let config = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(config)));
+    endpoint
+}
+
+/// Trusts the server's self-signed certificate, whatever it is.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,43 @@
+// Used when the page isn't served by the game server (e.g. the Vite dev server).
+export const DEFAULT_SERVER_URL = "ws://192.168.0.82:3001";
+
+export interface ServerConfig {
+  // The server's address without a path, e.g. wss://duck.example
+  websocketUrl: string;
+  // Only set when the game server accepts WebTransport sessions
+  webtransportUrl?: string;
+}
+
+/**
+ * Ask the game server how to connect to it. Falls back to DEFAULT_SERVER_URL
+ * when /config.json isn't available.
+ */
+export async function loadServerConfig(): Promise<ServerConfig> {
+  try {
+    const response = await fetch("/config.json");
+    if (!response.ok) return { websocketUrl: DEFAULT_SERVER_URL };
+    const config = await response.json();
+    return {
+      websocketUrl: config.websocketUrl ?? DEFAULT_SERVER_URL,
+      webtransportUrl: config.webtransportUrl,
+    };
+  } catch {
+    return { websocketUrl: DEFAULT_SERVER_URL };
+  }
+}
+
+/**
+ * Ask the game server which websocket URL to use. Falls back to
+ * DEFAULT_SERVER_URL when /config.json isn't available.
+ */
+export async function loadServerUrl(): Promise<string> {
+  return (await loadServerConfig()).websocketUrl;
+}
+
+/**
+ * The websocket controllers connect to on a server, at `/ws` under its
+ * address. A trailing `/` or `/ws` on the address is tolerated.
+ */
+export function controllerSocketUrl(websocketUrl: string): string {
+  return `${websocketUrl.replace(/\/(ws\/?)?$/, "")}/ws`;
+}
@@ -0,0 +1,66 @@
+// Controller messages over a WebTransport session with the game server.
+// Requests go on their own bidirectional stream and the server answers with
+// newline-separated JSON; inputs go as datagrams. Other messages from the
+// server, like feedback, arrive on a unidirectional stream it opens.
+
+const encoder = new TextEncoder();
+
+export function supportsWebTransport(): boolean {
+  return "WebTransport" in window;
+}
+
+/** Send one request and wait for all of the server's replies to it. */
+export async function request(
+  transport: WebTransport,
+  message: unknown
+): Promise<any[]> {
+  const stream = await transport.createBidirectionalStream();
+  const writer = stream.writable.getWriter();
+  await writer.write(encoder.encode(JSON.stringify(message)));
+  await writer.close();
+  const replies = await new Response(stream.readable).text();
+  return replies
+    .split("\n")
+    .filter((line) => line.length > 0)
+    .map((line) => JSON.parse(line));
+}
+
+/** Call `onMessage` with each message the server sends outside of replies. */
+export async function receivePushed(
+  transport: WebTransport,
+  onMessage: (message: any) => void
+): Promise<void> {
+  const streams = transport.incomingUnidirectionalStreams.getReader();
+  for (;;) {
+    const { value: stream, done } = await streams.read();
+    if (done) return;
+    readLines(stream, onMessage).catch(() => {});
+  }
+}
+
+async function readLines(
+  stream: ReadableStream<Uint8Array>,
+  onMessage: (message: any) => void
+): Promise<void> {
+  const decoder = new TextDecoder();
+  const reader = stream.getReader();
+  let buffered = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) return;
+    buffered += decoder.decode(value, { stream: true });
+    const lines = buffered.split("\n");
+    buffered = lines.pop() ?? "";
+    lines
+      .filter((line) => line.length > 0)
+      .forEach((line) => onMessage(JSON.parse(line)));
+  }
+}
+
+/**
+ * Encode an action as an input datagram. `seq` must increase with every
+ * input so the server can drop ones that arrive late.
+ */
+export function inputDatagram(seq: number, action: object): Uint8Array {
+  return encoder.encode(JSON.stringify({ seq, ...action }));
+}
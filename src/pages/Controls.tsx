@@ -1,6 +1,13 @@
 //create a page that shows the controls of the game for a mobile phone, one wasd which is a movement joystick and a Shoot button.
 
 import React, { useRef, useState, useEffect } from "react";
+import { controllerSocketUrl, loadServerConfig } from "../lib/serverConfig";
+import {
+  inputDatagram,
+  receivePushed,
+  request,
+  supportsWebTransport,
+} from "../lib/webtransport";
 
 // This radius determines how far the joystick stick can move
 const JOYSTICK_RADIUS = 50;
@@ -22,7 +29,7 @@ interface GameState {
 
 const Controls = () => {
   // Store the joystick stick's position relative to the center,
-  // the shoot counter, websocket connection, and the WebRTC connection URL.
+  // the shoot counter, websocket connection, and the server URL.
   const [stickPosition, setStickPosition] = useState({ x: 0, y: 0 });
   const [buttons, setButtons] = useState({
     a: false,
@@ -44,11 +51,118 @@ const Controls = () => {
   const [signalingSocket, setSignalingSocket] = useState<WebSocket | null>(
     null
   );
-  const [serverURL, setServerURL] = useState("ws://192.168.0.82:3001/ws");
+  const [serverURL, setServerURL] = useState<string | null>(null);
+  // Preferred over the websocket for lower input latency, when available
+  const [webTransportURL, setWebTransportURL] = useState<string | null>(null);
+  const datagramWriter = useRef<WritableStreamDefaultWriter<Uint8Array> | null>(
+    null
+  );
+  const inputSeq = useRef(0);
   const [isPlayer, setIsPlayer] = useState(false);
+  // Duck color, score and health pushed by the server as feedback messages.
+  const [duckColor, setDuckColor] = useState<string | null>(null);
+  const [status, setStatus] = useState({ score: 0, health: 100 });
+  // Set while the server is full and this controller waits or was turned away.
+  const [notice, setNotice] = useState<string | null>(null);
+
+  // Use the URLs the game server advertises, when it serves this page. A
+  // scanned join link names the server to use instead.
+  useEffect(() => {
+    const joinServer = new URLSearchParams(window.location.search).get("server");
+    loadServerConfig().then((config) => {
+      const websocketUrl = joinServer ?? config.websocketUrl;
+      if (
+        config.webtransportUrl &&
+        websocketUrl === config.websocketUrl &&
+        supportsWebTransport()
+      ) {
+        setWebTransportURL(config.webtransportUrl);
+      }
+      setServerURL(controllerSocketUrl(websocketUrl));
+    });
+  }, []);
+
+  const handleMessage = (message: any) => {
+    if (message.type === "announcement") {
+      alert(message.data.message);
+      return;
+    }
+    if (message.type === "queued") {
+      setIsPlayer(false);
+      setNotice(`Server full · queued ${message.data.position} of ${message.data.length}`);
+      return;
+    }
+    if (message.type === "rejected") {
+      setIsPlayer(false);
+      setNotice(
+        `Server full (${message.data.players}/${message.data.max_players} players)`
+      );
+      return;
+    }
+    if (message.type === "kicked") {
+      console.log(`Kicked from server: ${message.data.reason}`);
+      setIsPlayer(false);
+      return;
+    }
+    if (message.type !== "feedback") return;
+    const feedback = message.data;
+    switch (feedback.kind) {
+      case "vibrate":
+        navigator.vibrate?.(feedback.pattern);
+        break;
+      case "hit":
+        navigator.vibrate?.(200);
+        break;
+      case "status":
+        setStatus({ score: feedback.score, health: feedback.health });
+        break;
+      case "assigned":
+        setDuckColor(feedback.color);
+        setIsPlayer(true);
+        setNotice(null);
+        break;
+      case "idle":
+        navigator.vibrate?.([100, 100, 100]);
+        break;
+      case "afk":
+        // Dropped for inactivity; reload the page to rejoin
+        setIsPlayer(false);
+        break;
+    }
+  };
+
+  useEffect(() => {
+    if (!webTransportURL) return;
+    const transport = new WebTransport(webTransportURL);
+    transport.ready
+      .then(async () => {
+        const replies = await request(transport, {
+          type: "register",
+          data: { role: "player" },
+        });
+        replies.forEach(handleMessage);
+        receivePushed(transport, handleMessage).catch(() => {});
+        datagramWriter.current = transport.datagrams.writable.getWriter();
+        console.log("Connected to server as player over WebTransport");
+      })
+      .catch((error) => {
+        // Fall back to the websocket
+        console.log("WebTransport unavailable:", error);
+        setWebTransportURL(null);
+      });
+    transport.closed
+      .catch(() => {})
+      .finally(() => {
+        datagramWriter.current = null;
+        setIsPlayer(false);
+      });
+    return () => {
+      transport.close();
+    };
+  }, [webTransportURL]);
 
   useEffect(() => {
-    if (!serverURL) return;
+    if (!serverURL || webTransportURL) return;
     const sock = new WebSocket(serverURL);
     sock.onopen = () => {
       // Send role when connecting
@@ -64,6 +178,10 @@ const Controls = () => {
       console.log("Connected to server as player");
     };
 
+    sock.onmessage = (event) => {
+      handleMessage(JSON.parse(event.data));
+    };
+
     sock.onclose = () => {
       console.log("Disconnected from server");
       // Try to reconnect in 5 seconds
@@ -76,7 +194,18 @@ const Controls = () => {
     return () => {
       sock.close();
     };
-  }, [serverURL]);
+  }, [serverURL, webTransportURL]);
+
+  const sendAction = (action: GameState) => {
+    if (datagramWriter.current) {
+      inputSeq.current += 1;
+      datagramWriter.current
+        .write(inputDatagram(inputSeq.current, action))
+        .catch(() => {});
+    } else if (signalingSocket?.readyState === WebSocket.OPEN) {
+      signalingSocket.send(JSON.stringify({ type: "action", data: action }));
+    }
+  };
 
   // Called when the user touches the joystick area
   const handleTouchStart = (e: React.TouchEvent) => {
@@ -118,53 +247,32 @@ const Controls = () => {
     const normalizedX = newX / JOYSTICK_RADIUS;
     const normalizedY = newY / JOYSTICK_RADIUS;
 
-    if (signalingSocket?.readyState === WebSocket.OPEN) {
-      signalingSocket.send(
-        JSON.stringify({
-          type: "action",
-          data: {
-            joystick: { x: normalizedX, y: normalizedY },
-            buttons: buttons,
-          },
-        })
-      );
-    }
+    sendAction({
+      joystick: { x: normalizedX, y: normalizedY },
+      buttons: buttons,
+    });
   };
 
   // Reset when touch ends
   const handleTouchEnd = () => {
     setStickPosition({ x: 0, y: 0 });
     setJoystickBasePosition(null);
-    if (signalingSocket?.readyState === WebSocket.OPEN) {
-      signalingSocket.send(
-        JSON.stringify({
-          type: "action",
-          data: {
-            joystick: { x: 0, y: 0 },
-            buttons: buttons,
-          },
-        })
-      );
-    }
+    sendAction({
+      joystick: { x: 0, y: 0 },
+      buttons: buttons,
+    });
   };
 
   const handleButtonPress = (button: keyof typeof buttons) => {
     setButtons((prev) => {
       const newButtons = { ...prev, [button]: true };
-      if (signalingSocket?.readyState === WebSocket.OPEN) {
-        signalingSocket.send(
-          JSON.stringify({
-            type: "action",
-            data: {
-              joystick: {
-                x: stickPosition.x / JOYSTICK_RADIUS,
-                y: stickPosition.y / JOYSTICK_RADIUS,
-              },
-              buttons: newButtons,
-            },
-          })
-        );
-      }
+      sendAction({
+        joystick: {
+          x: stickPosition.x / JOYSTICK_RADIUS,
+          y: stickPosition.y / JOYSTICK_RADIUS,
+        },
+        buttons: newButtons,
+      });
       return newButtons;
     });
   };
@@ -172,20 +280,13 @@ const Controls = () => {
   const handleButtonRelease = (button: keyof typeof buttons) => {
     setButtons((prev) => {
       const newButtons = { ...prev, [button]: false };
-      if (signalingSocket?.readyState === WebSocket.OPEN) {
-        signalingSocket.send(
-          JSON.stringify({
-            type: "action",
-            data: {
-              joystick: {
-                x: stickPosition.x / JOYSTICK_RADIUS,
-                y: stickPosition.y / JOYSTICK_RADIUS,
-              },
-              buttons: newButtons,
-            },
-          })
-        );
-      }
+      sendAction({
+        joystick: {
+          x: stickPosition.x / JOYSTICK_RADIUS,
+          y: stickPosition.y / JOYSTICK_RADIUS,
+        },
+        buttons: newButtons,
+      });
       return newButtons;
     });
   };
@@ -218,9 +319,11 @@ const Controls = () => {
           background: isPlayer ? "#4caf50" : "#f44336",
           color: "white",
           fontSize: 12,
+          borderLeft: duckColor ? `12px solid ${duckColor}` : undefined,
         }}
       >
-        {isPlayer ? "Player Controls" : "Viewer Only"}
+        {notice ?? (isPlayer ? "Player Controls" : "Viewer Only")}
+        {isPlayer && ` · ${status.score} pts · ${status.health} HP`}
       </div>
 
       {/* Left side - Joystick area */}
@@ -1,4 +1,5 @@
 import React, { useState, useEffect, useRef } from "react";
+import { DEFAULT_SERVER_URL, loadServerUrl } from "../lib/serverConfig";
 import { Button } from "../components/ui/button";
 import { Input } from "../components/ui/input";
 import { Label } from "../components/ui/label";
@@ -76,10 +77,15 @@ const PCControls = () => {
   const [signalingSocket, setSignalingSocket] = useState<WebSocket | null>(
     null
   );
-  const [serverURL, setServerURL] = useState("ws://192.168.0.82:3001");
+  const [serverURL, setServerURL] = useState(DEFAULT_SERVER_URL);
   const [isPlayer, setIsPlayer] = useState(false);
   const [showControls, setShowControls] = useState(true);
 
+  // Use the websocket URL the game server advertises, when it serves this page
+  useEffect(() => {
+    loadServerUrl().then(setServerURL);
+  }, []);
+
   // Connect to WebSocket server
   useEffect(() => {
     if (!serverURL) return;
@@ -92,6 +98,8 @@ const PCControls = () => {
           type: "register",
           data: {
             role: "player", // This component is always a player (controls)
+            // Arrow keys are sent as a d-pad alongside the WASD joystick
+            capabilities: ["dpad"],
           },
         })
       );
@@ -190,6 +198,13 @@ const PCControls = () => {
       y: activeKeys.has(keyConfig.buttonY),
     };
 
+    const dpad = {
+      up: activeKeys.has("arrowup"),
+      down: activeKeys.has("arrowdown"),
+      left: activeKeys.has("arrowleft"),
+      right: activeKeys.has("arrowright"),
+    };
+
     // Update state
     setJoystick({ x, y });
     setButtons(newButtons);
@@ -202,6 +217,7 @@ const PCControls = () => {
           data: {
             joystick: { x, y },
             buttons: newButtons,
+            dpad,
           },
         })
       );
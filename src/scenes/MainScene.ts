@@ -1,6 +1,7 @@
 import Phaser from "phaser";
 import { MapLoader } from "../maps/MapLoader";
 import { GameMap, Platform, Obstacle } from "../maps/types";
+import { DEFAULT_SERVER_URL, loadServerUrl } from "../lib/serverConfig";
 
 interface PlayerState {
   joystick: {
@@ -28,7 +29,7 @@ interface Player {
 export default class MainScene extends Phaser.Scene {
   private players: { [id: string]: Player } = {};
   private ws!: WebSocket;
-  private serverUrl = "ws://192.168.0.82:3001";
+  private serverUrl = DEFAULT_SERVER_URL;
   private playerId!: string;
   private speed: number = 650;
   private debugText!: Phaser.GameObjects.Text;
@@ -38,6 +39,7 @@ export default class MainScene extends Phaser.Scene {
   private currentMap!: GameMap;
   private mapLoader: MapLoader;
   private mapKeys!: Phaser.Input.Keyboard.Key[];
+  private joinCode?: Phaser.GameObjects.Container;
 
   constructor() {
     super({ key: "MainScene" });
@@ -46,7 +48,10 @@ export default class MainScene extends Phaser.Scene {
   }
 
   preload() {
-    this.createWebSocket();
+    loadServerUrl().then((url) => {
+      this.serverUrl = url;
+      this.createWebSocket();
+    });
   }
 
   create() {
@@ -266,6 +271,26 @@ export default class MainScene extends Phaser.Scene {
             this.removePlayer(id);
           }
         }
+      } else if (message.type === "map") {
+        // The server operator switched maps
+        if (message.data.map) {
+          this.mapLoader.registerMap(message.data.id, message.data.map);
+        }
+        this.loadMap(message.data.id);
+      } else if (message.type === "paused") {
+        if (message.data.paused) {
+          this.physics.pause();
+        } else {
+          this.physics.resume();
+        }
+      } else if (message.type === "rejected") {
+        console.warn(
+          `Server turned this viewer away: ${message.data.reason} (${message.data.viewers}/${message.data.max_viewers} viewers)`
+        );
+      } else if (message.type === "announcement" || message.type === "motd") {
+        this.showAnnouncement(message.data.message);
+      } else if (message.type === "join") {
+        this.showJoinCode(message.data.svg);
       }
     };
 
@@ -295,6 +320,47 @@ export default class MainScene extends Phaser.Scene {
     }
   }
 
+  // Show the server's QR code for phones to scan in the bottom right corner
+  showJoinCode(svg: string) {
+    const key = "join-code";
+    const show = () => {
+      const { width, height } = this.cameras.main;
+      this.joinCode = this.add.container(width - 16, height - 16, [
+        this.add.image(0, 0, key).setDisplaySize(160, 160).setOrigin(1, 1),
+        this.add
+          .text(-80, -168, "Scan to join", {
+            fontSize: "18px",
+            color: "#000",
+            backgroundColor: "#ffffffcc",
+            padding: { x: 8, y: 4 },
+          })
+          .setOrigin(0.5, 1),
+      ]);
+      this.joinCode.setScrollFactor(0).setDepth(1000);
+    };
+    // A reconnect brings a new code, maybe for a new address
+    this.joinCode?.destroy();
+    if (this.textures.exists(key)) {
+      this.textures.remove(key);
+    }
+    this.textures.once(Phaser.Textures.Events.ADD_KEY + key, show);
+    this.textures.addBase64(key, `data:image/svg+xml;base64,${btoa(svg)}`);
+  }
+
+  showAnnouncement(text: string) {
+    const announcement = this.add
+      .text(this.cameras.main.width / 2, 40, text, {
+        fontSize: "24px",
+        color: "#000",
+        backgroundColor: "#ffffffcc",
+        padding: { x: 12, y: 6 },
+      })
+      .setOrigin(0.5, 0)
+      .setScrollFactor(0)
+      .setDepth(1000);
+    this.time.delayedCall(5000, () => announcement.destroy());
+  }
+
   loadMap(mapId: string) {
     const map = this.mapLoader.getMap(mapId);
     if (!map) {